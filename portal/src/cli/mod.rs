@@ -3,6 +3,8 @@ use std::borrow::Cow;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
+use crate::proxy::Proxy;
+use crate::router::RouteRule;
 use crate::{get_first_run, Config};
 use clap::{Parser, Subcommand};
 use cli_table::format::Padding;
@@ -28,11 +30,20 @@ pub struct Cli {
     #[arg(short, long)]
     pub key: Option<String>,
 
+    /// Authenticate with a bearer JWT from your identity provider, instead of a key
+    #[arg(long, conflicts_with = "key")]
+    pub token: Option<String>,
+
+    /// Read the bearer JWT from this file, again on every reconnect so it can be rotated
+    #[arg(long = "token-file", value_name = "FILE", conflicts_with_all = ["key", "token"])]
+    pub token_file: Option<PathBuf>,
+
     /// Specify a sub-domain for this portal
     #[arg(short, long)]
     pub sub_domain: Option<String>,
 
-    /// Sets the HOST (i.e. localhost) to forward incoming portal traffic to
+    /// Sets the HOST (i.e. localhost) to forward incoming portal traffic to,
+    /// or a unix socket (i.e. unix:/path/to.sock)
     #[arg(long = "host", default_value = "localhost")]
     pub local_host: String,
 
@@ -40,6 +51,43 @@ pub struct Cli {
     #[arg(long = "use-tls", short = 't')]
     pub use_tls: bool,
 
+    /// Trust the certificates of this CA bundle (PEM) for local TLS services
+    #[arg(long = "local-ca", value_name = "FILE")]
+    pub local_ca: Option<PathBuf>,
+
+    /// Don't verify the certificates of local TLS services, for development only
+    #[arg(long = "local-insecure")]
+    pub local_insecure: bool,
+
+    /// The name to send and verify for local TLS services, instead of --host
+    #[arg(long = "local-server-name", value_name = "NAME")]
+    pub local_server_name: Option<String>,
+
+    /// Present this client certificate (PEM) to local TLS services
+    #[arg(long = "local-cert", value_name = "FILE", requires = "local_key")]
+    pub local_cert: Option<PathBuf>,
+
+    /// The private key (PEM) of --local-cert
+    #[arg(long = "local-key", value_name = "FILE", requires = "local_cert")]
+    pub local_key: Option<PathBuf>,
+
+    /// Trust the certificates of this CA bundle (PEM) for the control server
+    #[arg(long = "ctrl-ca", value_name = "FILE")]
+    pub ctrl_ca: Option<PathBuf>,
+
+    /// Only accept a control server whose certificate chain has this public key,
+    /// as `sha256/<base64>`. Can be used multiple times
+    #[arg(long = "ctrl-pin", value_name = "PIN")]
+    pub ctrl_pins: Vec<String>,
+
+    /// Present this client certificate (PEM) to the control server
+    #[arg(long = "ctrl-cert", value_name = "FILE", requires = "ctrl_key")]
+    pub ctrl_cert: Option<PathBuf>,
+
+    /// The private key (PEM) of --ctrl-cert
+    #[arg(long = "ctrl-key", value_name = "FILE", requires = "ctrl_cert")]
+    pub ctrl_key: Option<PathBuf>,
+
     /// Sets the port to forward incoming portal traffic to on the target host
     #[arg(short, long, default_value = "8000")]
     pub port: u16,
@@ -47,6 +95,37 @@ pub struct Cli {
     /// Sets the address of the local introspection dashboard
     #[arg(long = "dashboard-port")]
     pub dashboard_port: Option<u16>,
+
+    /// Connect through this proxy: http://[user:pass@]host:port or socks5://[user:pass@]host:port.
+    /// Defaults to HTTPS_PROXY or ALL_PROXY, hosts in NO_PROXY are reached directly
+    #[arg(long, value_name = "URL")]
+    pub proxy: Option<Proxy>,
+
+    /// Give up after this many failed reconnect attempts in a row
+    #[arg(long = "max-reconnect-attempts")]
+    pub max_reconnect_attempts: Option<u32>,
+
+    /// Give up reconnecting after being disconnected for this many seconds
+    #[arg(long = "reconnect-timeout", value_name = "SECONDS")]
+    pub reconnect_timeout: Option<u64>,
+
+    /// Share the sub-domain with other pool clients of this account, balancing requests between them
+    #[arg(long)]
+    pub pool: bool,
+
+    /// Serve this HTML page to visitors while the tunnel is down or the local service refuses them
+    #[arg(long = "maintenance-page", value_name = "FILE")]
+    pub maintenance_page: Option<PathBuf>,
+
+    /// Forward requests under a path prefix to another local service, i.e. `/api=8080`, `/api=127.0.0.1:8080` or `/api=unix:/path/to.sock`.
+    /// Can be used multiple times, other requests go to --host and --port
+    #[arg(long = "route", value_name = "PREFIX=[HOST:]PORT")]
+    pub routes: Vec<RouteRule>,
+
+    /// Remove this route prefix from the path of the requests it forwards.
+    /// Can be used multiple times, once for each --route to strip
+    #[arg(long = "strip-prefix", value_name = "PREFIX")]
+    pub strip_prefixes: Vec<String>,
 }
 
 #[derive(Subcommand)]
@@ -57,6 +136,30 @@ pub enum Commands {
         #[arg(short, long)]
         key: String,
     },
+    /// Share a directory or a file with the built-in file server
+    Serve {
+        /// The directory or file to serve
+        path: PathBuf,
+
+        /// Serve index.html for paths that don't exist, for single page apps
+        #[arg(long)]
+        spa: bool,
+    },
+    /// Manage the sub-domains reserved for your account
+    Domains {
+        #[command(subcommand)]
+        command: DomainsCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DomainsCommand {
+    /// List the sub-domains you reserved
+    List,
+    /// Reserve a sub-domain, so nobody else can open a tunnel on it
+    Reserve { sub_domain: String },
+    /// Release a sub-domain you reserved
+    Release { sub_domain: String },
 }
 
 pub struct CliInterface {
@@ -89,6 +192,17 @@ impl CliInterface {
         }
     }
 
+    /// Show that the tunnel is down and when we will try again
+    pub fn reconnecting(attempt: u32, delay: Duration, reason: &str) -> ProgressBar {
+        let msg = format!(
+            "\x1b[33mTunnel disconnected: {}. Reconnecting in {:.1}s (attempt {})...\x1b[0m",
+            reason,
+            delay.as_secs_f32(),
+            attempt
+        );
+        new_spinner(msg)
+    }
+
     pub async fn did_connect(&self, sub_domain: &str, full_hostname: &str) {
         self.spinner.finish_with_message(
             "\x1b[32mSuccess! Remote tunnel is now open.\x1b[0m\n".to_string(),
@@ -105,7 +219,7 @@ impl CliInterface {
         let forward_url = self.config.forward_url();
         let inspect = format!("\x1b[35mhttp://localhost:{}\x1b[0m", self.introspect.port());
 
-        let table = vec![
+        let mut table = vec![
             vec![
                 "\x1b[32mPublic tunnel URL\x1b[0m".cell(),
                 public_url
@@ -129,6 +243,17 @@ impl CliInterface {
             ],
         ];
 
+        for rule in &self.config.routes {
+            table.push(vec![
+                format!("  {}", rule.prefix).cell(),
+                rule.addr()
+                    .url(self.config.local_tls)
+                    .cell()
+                    .padding(Padding::builder().left(4).build())
+                    .justify(Justify::Left),
+            ]);
+        }
+
         let table = table.table();
         print_stderr(table).expect("failed to generate starting terminal user interface");
 
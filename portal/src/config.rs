@@ -1,33 +1,77 @@
 use serde::Deserialize;
 
+use crate::cli::Commands;
+use crate::local::LocalAddr;
+use crate::proxy::Proxy;
+use crate::router::RouteRule;
+
 use super::*;
-use std::{
-    error::Error,
-    net::{SocketAddr, ToSocketAddrs},
-};
+use std::{error::Error, net::ToSocketAddrs, path::PathBuf};
 
 const HOST_ENV: &str = "CTRL_HOST";
 const PORT_ENV: &str = "CTRL_PORT";
 const TLS_OFF_ENV: &str = "CTRL_TLS_OFF";
 
-const DEFAULT_HOST: &str = "localhost";
+pub const DEFAULT_HOST: &str = "localhost";
+/// Local hosts starting with this are unix sockets
+pub const UNIX_SOCKET_PREFIX: &str = "unix:";
 const DEFAULT_CONTROL_HOST: &str = "localhost";
 const DEFAULT_CONTROL_PORT: &str = "5000";
 
-const SETTINGS_DIR: &str = ".portal";
-const SECRET_KEY_FILE: &str = "key.token";
-
 #[derive(Deserialize, Debug)]
 struct InternalConfig {
     sub_domain: Option<String>,
+    token: Option<String>,
+    token_file: Option<PathBuf>,
+    pool: Option<bool>,
+    maintenance_page: Option<PathBuf>,
+    routes: Option<Vec<RouteRule>>,
     portal_host: Option<String>,
     portal_port: Option<u16>,
     portal_tls: Option<bool>,
+    portal_tls_ca: Option<PathBuf>,
+    portal_tls_pins: Option<Vec<String>>,
+    portal_tls_cert: Option<PathBuf>,
+    portal_tls_key: Option<PathBuf>,
     local_host: Option<String>,
     local_port: Option<u16>,
     local_tls: Option<bool>,
+    local_tls_ca: Option<PathBuf>,
+    local_tls_insecure: Option<bool>,
+    local_tls_server_name: Option<String>,
+    local_tls_cert: Option<PathBuf>,
+    local_tls_key: Option<PathBuf>,
     dashboard_port: Option<u16>,
     verbose: Option<bool>,
+    max_reconnect_attempts: Option<u32>,
+    reconnect_timeout: Option<u64>,
+    proxy: Option<Proxy>,
+}
+
+/// How we verify and authenticate to local TLS services
+#[derive(Debug, Clone, Default)]
+pub struct LocalTlsOptions {
+    /// CA bundle to trust on top of the web PKI roots
+    pub ca: Option<PathBuf>,
+    /// accept any certificate
+    pub insecure: bool,
+    /// the name to verify instead of the local host
+    pub server_name: Option<String>,
+    /// client certificate and key to present
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+/// How we verify and authenticate to the control server
+#[derive(Debug, Clone, Default)]
+pub struct ControlTlsOptions {
+    /// CA bundle to trust on top of the web PKI roots
+    pub ca: Option<PathBuf>,
+    /// `sha256/<base64>` digests of public keys, one of which the server's chain must have
+    pub pins: Vec<String>,
+    /// client certificate and key to present
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
 }
 
 /// Config
@@ -37,14 +81,30 @@ pub struct Config {
     pub portal_host: String,
     pub portal_port: u16,
     pub portal_tls: bool,
+    pub control_tls_options: ControlTlsOptions,
     pub local_tls: bool,
+    pub local_tls_options: LocalTlsOptions,
     pub local_host: String,
     pub local_port: u16,
-    pub local_addr: SocketAddr,
+    pub local_addr: LocalAddr,
+    /// local services by path prefix, the rest goes to `local_addr`
+    pub routes: Vec<RouteRule>,
     pub sub_domain: Option<String>,
     pub secret_key: Option<SecretKey>,
+    /// a bearer JWT to authenticate with, instead of the key
+    pub token: Option<String>,
+    /// where to read the token from on every connect
+    pub token_file: Option<PathBuf>,
+    /// share the sub-domain with other pool clients of this account
+    pub pool: bool,
+    /// where to read the page the server shows while we're down, on every connect
+    pub maintenance_page: Option<PathBuf>,
     pub dashboard_port: u16,
     pub verbose: bool,
+    pub max_reconnect_attempts: Option<u32>,
+    pub reconnect_timeout: Option<Duration>,
+    /// the proxy to connect through, instead of the one from the env
+    pub proxy: Option<Proxy>,
 }
 
 impl From<&mut InternalConfig> for Config {
@@ -54,23 +114,36 @@ impl From<&mut InternalConfig> for Config {
             .clone()
             .unwrap_or(DEFAULT_HOST.to_string());
         let local_port = config.local_port.unwrap_or(8000);
-        let local_addr = (local_host.as_str(), local_port)
-            .to_socket_addrs()
-            .unwrap()
-            .next()
-            .unwrap();
+        let local_addr = LocalAddr::new(&local_host, local_port);
         let local_tls = config.local_tls.unwrap_or(false);
+        let local_tls_options = LocalTlsOptions {
+            ca: config.local_tls_ca.take(),
+            insecure: config.local_tls_insecure.unwrap_or(false),
+            server_name: config.local_tls_server_name.take(),
+            cert: config.local_tls_cert.take(),
+            key: config.local_tls_key.take(),
+        };
 
         let portal_tls = config.portal_tls.unwrap_or(false);
-        let portal_schema = if portal_tls { "wss" } else { "ws" };
         let portal_host = config
             .portal_host
             .take()
             .unwrap_or(DEFAULT_CONTROL_HOST.to_string());
         let portal_port = config.portal_port.unwrap_or(5000);
+        let control_tls_options = ControlTlsOptions {
+            ca: config.portal_tls_ca.take(),
+            pins: config.portal_tls_pins.take().unwrap_or_default(),
+            cert: config.portal_tls_cert.take(),
+            key: config.portal_tls_key.take(),
+        };
         let secret_key = None.map(SecretKey);
+        let pool = config.pool.unwrap_or(false);
+        let routes = config.routes.take().unwrap_or_default();
         let dashboard_port = config.dashboard_port.unwrap_or(0);
         let verbose = config.verbose.unwrap_or(false);
+        let max_reconnect_attempts = config.max_reconnect_attempts;
+        let reconnect_timeout = config.reconnect_timeout.map(Duration::from_secs);
+        let proxy = config.proxy.take();
 
         Config {
             client_id: ClientId::generate(),
@@ -78,13 +151,23 @@ impl From<&mut InternalConfig> for Config {
             local_host,
             local_port,
             local_addr,
+            routes,
             local_tls,
+            local_tls_options,
             portal_host,
             portal_port,
             portal_tls,
+            control_tls_options,
             secret_key,
+            token: config.token.take(),
+            token_file: config.token_file.take(),
+            pool,
+            maintenance_page: config.maintenance_page.take(),
             dashboard_port,
             verbose,
+            max_reconnect_attempts,
+            reconnect_timeout,
+            proxy,
         }
     }
 }
@@ -97,10 +180,12 @@ impl Config {
             std::env::set_var("RUST_LOG", "portal=debug");
         }
         pretty_env_logger::init();
-        Ok(Config::from(&mut config))
+        let config = Config::from(&mut config);
+        config.build_tls()?;
+        Ok(config)
     }
 
-    pub fn load() -> Result<Config, ()> {
+    pub fn load() -> Result<Config, Box<dyn Error>> {
         let cli = get_cli();
         if cli.verbose {
             std::env::set_var("RUST_LOG", "portal=debug");
@@ -109,25 +194,39 @@ impl Config {
         pretty_env_logger::init();
 
         let secret_key: Option<String> = None;
+        if cli.pool && cli.token.is_none() && cli.token_file.is_none() {
+            warn!("--pool needs a --token, opening a single tunnel");
+        }
         let sub_domain = cli.sub_domain.clone();
 
-        let local_addr = (cli.local_host.as_str(), cli.port)
-            .to_socket_addrs()
-            .map_err(|_| {
-                error!(
-                    "Failed to resolve local address: {}:{}",
-                    cli.local_host.as_str(),
-                    cli.port
-                )
-            })?
-            .next()
-            .ok_or_else(|| {
-                error!(
-                    "No IP addresses found for: {}:{}",
-                    cli.local_host.as_str(),
-                    cli.port
-                )
-            })?;
+        let local_addr = match cli.command {
+            Some(Commands::Serve { ref path, spa }) => {
+                let path = path
+                    .canonicalize()
+                    .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+                LocalAddr::Dir { path, spa }
+            }
+            _ => LocalAddr::new(&cli.local_host, cli.port),
+        };
+        if let LocalAddr::Tcp { ref host, port } = local_addr {
+            (host.as_str(), port)
+                .to_socket_addrs()
+                .map_err(|_| format!("Failed to resolve local address: {}:{}", host, port))?
+                .next()
+                .ok_or_else(|| format!("No IP addresses found for: {}:{}", host, port))?;
+        }
+
+        if let Some(prefix) = cli
+            .strip_prefixes
+            .iter()
+            .find(|prefix| !cli.routes.iter().any(|rule| &rule.prefix == *prefix))
+        {
+            return Err(format!(
+                "--strip-prefix {} doesn't match the prefix of any --route",
+                prefix
+            )
+            .into());
+        }
 
         // get the host url
         let tls_off = env::var(TLS_OFF_ENV).is_ok();
@@ -136,20 +235,90 @@ impl Config {
 
         info!("Control Server URL: {}", &portal_host);
 
-        Ok(Config {
+        let config = Config {
             client_id: ClientId::generate(),
             portal_host,
             portal_port: portal_port.parse().unwrap(),
             local_host: cli.local_host.clone(),
             local_port: cli.port,
             local_tls: cli.use_tls,
+            local_tls_options: LocalTlsOptions {
+                ca: cli.local_ca.clone(),
+                insecure: cli.local_insecure,
+                server_name: cli.local_server_name.clone(),
+                cert: cli.local_cert.clone(),
+                key: cli.local_key.clone(),
+            },
             local_addr,
+            routes: cli
+                .routes
+                .iter()
+                .cloned()
+                .map(|rule| RouteRule {
+                    strip_prefix: cli.strip_prefixes.contains(&rule.prefix),
+                    ..rule
+                })
+                .collect(),
             sub_domain,
             dashboard_port: cli.dashboard_port.unwrap_or(0),
             verbose: cli.verbose,
             secret_key: secret_key.map(SecretKey),
+            token: cli.token.clone(),
+            token_file: cli.token_file.clone(),
+            pool: cli.pool,
+            maintenance_page: cli.maintenance_page.clone(),
             portal_tls: !tls_off,
-        })
+            control_tls_options: ControlTlsOptions {
+                ca: cli.ctrl_ca.clone(),
+                pins: cli.ctrl_pins.clone(),
+                cert: cli.ctrl_cert.clone(),
+                key: cli.ctrl_key.clone(),
+            },
+            max_reconnect_attempts: cli.max_reconnect_attempts,
+            reconnect_timeout: cli.reconnect_timeout.map(Duration::from_secs),
+            proxy: cli.proxy.clone(),
+        };
+        config
+            .build_tls()
+            .map_err(|e| format!("Invalid TLS options: {}", e))?;
+        Ok(config)
+    }
+
+    /// Build the TLS connectors we use, so bad certificates fail now rather than on the first request
+    fn build_tls(&self) -> Result<(), Box<dyn Error>> {
+        if self.local_tls {
+            let _ = LOCAL_TLS.set(local_tls::connector(&self.local_tls_options)?);
+        }
+        if self.portal_tls {
+            let _ = CONTROL_TLS.set(control_tls::connector(&self.control_tls_options)?);
+        }
+        Ok(())
+    }
+
+    /// The bearer token to authenticate with, read from its file if there is one
+    pub fn token(&self) -> std::io::Result<Option<String>> {
+        match self.token_file {
+            Some(ref path) => {
+                std::fs::read_to_string(path).map(|token| Some(token.trim().to_string()))
+            }
+            None => Ok(self.token.clone()),
+        }
+    }
+
+    /// The maintenance page to upload, read from its file if there is one
+    pub fn maintenance_page(&self) -> std::io::Result<Option<String>> {
+        let Some(ref path) = self.maintenance_page else {
+            return Ok(None);
+        };
+
+        let page = std::fs::read_to_string(path)?;
+        if page.len() > MAX_MAINTENANCE_PAGE_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("larger than {} KiB", MAX_MAINTENANCE_PAGE_SIZE / 1024),
+            ));
+        }
+        Ok(Some(page))
     }
 
     pub fn activation_url(&self, full_hostname: &str) -> String {
@@ -161,8 +330,7 @@ impl Config {
     }
 
     pub fn forward_url(&self) -> String {
-        let scheme = if self.local_tls { "https" } else { "http" };
-        format!("{}://{}:{}", &scheme, &self.local_host, &self.local_port)
+        self.local_addr.url(self.local_tls)
     }
 
     pub fn ws_forward_url(&self) -> String {
@@ -0,0 +1,264 @@
+use std::error::Error;
+
+use super::*;
+use base64::{engine::general_purpose, Engine as _};
+use rustls_webpki::{EndEntityCert, KeyUsage, VerifiedPath};
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, TrustAnchor, UnixTime};
+use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio_rustls::TlsConnector;
+
+use crate::local_tls::{load_certs, load_key};
+
+/// Pins are the base64 SHA-256 of a certificate's public key (SPKI)
+const PIN_PREFIX: &str = "sha256/";
+
+/// Build the connector for the control server
+pub fn connector(options: &ControlTlsOptions) -> Result<TlsConnector, Box<dyn Error>> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(ref ca) = options.ca {
+        for cert in load_certs(ca)? {
+            roots.add(cert)?;
+        }
+    }
+
+    let builder = if options.pins.is_empty() {
+        ClientConfig::builder().with_root_certificates(roots)
+    } else {
+        let pins = options
+            .pins
+            .iter()
+            .map(|pin| parse_pin(pin))
+            .collect::<Result<Vec<_>, _>>()?;
+        let roots = Arc::new(roots);
+        let verifier = PinnedVerifier {
+            inner: WebPkiServerVerifier::builder(roots.clone()).build()?,
+            roots,
+            pins,
+        };
+        ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+    };
+
+    let config = match (&options.cert, &options.key) {
+        (Some(cert), Some(key)) => {
+            builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err("a control client certificate needs both a cert and a key".into()),
+    };
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn parse_pin(pin: &str) -> Result<[u8; 32], Box<dyn Error>> {
+    let encoded = pin
+        .strip_prefix(PIN_PREFIX)
+        .ok_or_else(|| format!("invalid pin {}: expected {}<base64>", pin, PIN_PREFIX))?;
+    general_purpose::STANDARD
+        .decode(encoded)?
+        .try_into()
+        .map_err(|_| format!("invalid pin {}: not a sha256 digest", pin).into())
+}
+
+/// The SHA-256 of the public key of `cert`
+fn spki_digest(cert: &CertificateDer<'_>) -> Option<[u8; 32]> {
+    let cert = rustls_webpki::EndEntityCert::try_from(cert).ok()?;
+    Some(Sha256::digest(cert.subject_public_key_info().as_ref()).into())
+}
+
+/// The SHA-256 of the public key of a trust anchor,
+/// which only keeps the contents of the SPKI sequence
+fn anchor_digest(anchor: &TrustAnchor<'_>) -> [u8; 32] {
+    let spki = anchor.subject_public_key_info.as_ref();
+    let mut der = vec![0x30];
+    match spki.len() {
+        len @ 0..=0x7f => der.push(len as u8),
+        len @ 0x80..=0xff => der.extend([0x81, len as u8]),
+        len => der.extend([0x82, (len >> 8) as u8, len as u8]),
+    }
+    der.extend_from_slice(spki);
+    Sha256::digest(der).into()
+}
+
+/// Verifies the chain as usual, then requires a key on the path
+/// from the server certificate to a trusted root to be pinned
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    roots: Arc<RootCertStore>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl PinnedVerifier {
+    fn is_pinned(&self, path: &VerifiedPath<'_>) -> bool {
+        let digest = |spki: &[u8]| -> [u8; 32] { Sha256::digest(spki).into() };
+        std::iter::once(digest(path.end_entity().subject_public_key_info().as_ref()))
+            .chain(
+                path.intermediate_certificates()
+                    .map(|cert| digest(cert.subject_public_key_info().as_ref())),
+            )
+            .chain(std::iter::once(anchor_digest(path.anchor())))
+            .any(|digest| self.pins.contains(&digest))
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        // the server may send any certificate along, only those on a path
+        // to one of our roots count. Look for a path with a pinned key on it.
+        let pinned = EndEntityCert::try_from(end_entity).is_ok_and(|cert| {
+            cert.verify_for_usage(
+                rustls_webpki::ALL_VERIFICATION_ALGS,
+                &self.roots.roots,
+                intermediates,
+                now,
+                KeyUsage::server_auth(),
+                None,
+                Some(&|path: &VerifiedPath<'_>| {
+                    if self.is_pinned(path) {
+                        Ok(())
+                    } else {
+                        Err(rustls_webpki::Error::UnknownIssuer)
+                    }
+                }),
+            )
+            .is_ok()
+        });
+        if pinned {
+            return Ok(verified);
+        }
+
+        let actual = spki_digest(end_entity)
+            .map(|digest| general_purpose::STANDARD.encode(digest))
+            .unwrap_or_default();
+        Err(tokio_rustls::rustls::Error::General(format!(
+            "control server key {}{} is not pinned",
+            PIN_PREFIX, actual
+        )))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pin() {
+        let digest = [7u8; 32];
+        let pin = format!("sha256/{}", general_purpose::STANDARD.encode(digest));
+        assert_eq!(parse_pin(&pin).unwrap(), digest);
+
+        assert!(parse_pin(&general_purpose::STANDARD.encode(digest)).is_err());
+        assert!(parse_pin("sha256/not base64").is_err());
+        assert!(parse_pin("sha256/AAAA").is_err());
+    }
+
+    fn pin(path: &str) -> String {
+        let cert = &load_certs(path.as_ref()).unwrap()[0];
+        format!(
+            "{}{}",
+            PIN_PREFIX,
+            general_purpose::STANDARD.encode(spki_digest(cert).unwrap())
+        )
+    }
+
+    fn verify(pins: Vec<String>, intermediates: &[&str]) -> bool {
+        let options = ControlTlsOptions {
+            ca: Some("tests/control_tls/ca.pem".into()),
+            pins: pins.clone(),
+            ..Default::default()
+        };
+        assert!(connector(&options).is_ok());
+
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(load_certs("tests/control_tls/ca.pem".as_ref()).unwrap());
+        let roots = Arc::new(roots);
+        let verifier = PinnedVerifier {
+            inner: WebPkiServerVerifier::builder(roots.clone())
+                .build()
+                .unwrap(),
+            roots,
+            pins: pins.iter().map(|pin| parse_pin(pin).unwrap()).collect(),
+        };
+
+        let end_entity = &load_certs("tests/control_tls/cert.pem".as_ref()).unwrap()[0];
+        let intermediates: Vec<_> = intermediates
+            .iter()
+            .flat_map(|path| load_certs(path.as_ref()).unwrap())
+            .collect();
+        verifier
+            .verify_server_cert(
+                end_entity,
+                &intermediates,
+                &ServerName::try_from("portal.test").unwrap(),
+                &[],
+                UnixTime::now(),
+            )
+            .is_ok()
+    }
+
+    #[test]
+    fn test_pinned_verifier() {
+        // the server certificate or the root it chains to
+        assert!(verify(vec![pin("tests/control_tls/cert.pem")], &[]));
+        assert!(verify(vec![pin("tests/control_tls/ca.pem")], &[]));
+        assert!(verify(
+            vec![
+                pin("tests/control_tls/other.pem"),
+                pin("tests/control_tls/ca.pem")
+            ],
+            &[]
+        ));
+
+        // a pinned certificate the server sends along, but which isn't on its path
+        assert!(!verify(
+            vec![pin("tests/control_tls/other.pem")],
+            &["tests/control_tls/other.pem"]
+        ));
+        assert!(!verify(vec![pin("tests/control_tls/other.pem")], &[]));
+    }
+}
@@ -0,0 +1,111 @@
+use crate::cli::DomainsCommand;
+use crate::{connect_to_control, debug, Config, DomainReservation, Error};
+use bytes::Bytes;
+use cli_table::{print_stdout, Cell, Table};
+use hyper::header::{AUTHORIZATION, HOST};
+use hyper::{Body, Method, Request, StatusCode};
+
+/// Manage the sub-domains reserved for our account on the control server
+pub async fn run(config: &Config, command: &DomainsCommand) -> Result<(), Error> {
+    let (method, path) = match command {
+        DomainsCommand::List => (Method::GET, "/domains".to_string()),
+        DomainsCommand::Reserve { sub_domain } => (Method::PUT, format!("/domains/{}", sub_domain)),
+        DomainsCommand::Release { sub_domain } => {
+            (Method::DELETE, format!("/domains/{}", sub_domain))
+        }
+    };
+
+    let (status, body) = request(config, method, &path).await?;
+    if !status.is_success() {
+        let message = String::from_utf8_lossy(&body).trim().to_string();
+        return Err(Error::ServerError(if message.is_empty() {
+            status.to_string()
+        } else {
+            message
+        }));
+    }
+
+    match command {
+        DomainsCommand::List => {
+            let reservations: Vec<DomainReservation> =
+                serde_json::from_slice(&body).map_err(|_| Error::ServerReplyInvalid)?;
+            if reservations.is_empty() {
+                println!("You haven't reserved any sub-domains.");
+                return Ok(());
+            }
+
+            let table = reservations
+                .into_iter()
+                .map(|reservation| {
+                    vec![
+                        reservation.hostname.cell(),
+                        reservation
+                            .created
+                            .format("%Y-%m-%d %H:%M UTC")
+                            .to_string()
+                            .cell(),
+                        reservation
+                            .expires
+                            .map(|expires| expires.format("%Y-%m-%d %H:%M UTC").to_string())
+                            .unwrap_or_else(|| "never".to_string())
+                            .cell(),
+                    ]
+                })
+                .collect::<Vec<_>>()
+                .table()
+                .title(vec![
+                    "Sub-domain".cell(),
+                    "Reserved".cell(),
+                    "Expires".cell(),
+                ]);
+            print_stdout(table).expect("failed to print reserved sub-domains");
+        }
+        DomainsCommand::Reserve { .. } => {
+            let reservation: DomainReservation =
+                serde_json::from_slice(&body).map_err(|_| Error::ServerReplyInvalid)?;
+            bunt::println!("{$green}Reserved {}{/$}", reservation.hostname);
+        }
+        DomainsCommand::Release { sub_domain } => {
+            bunt::println!("{$green}Released {}{/$}", sub_domain);
+        }
+    }
+
+    Ok(())
+}
+
+/// Send a request to the control server, authenticated with our key or token,
+/// or only by our client certificate
+async fn request(
+    config: &Config,
+    method: Method,
+    path: &str,
+) -> Result<(StatusCode, Bytes), Error> {
+    let stream = connect_to_control(config).await?;
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("control connection closed: {:?}", e);
+        }
+    });
+
+    let mut request = Request::builder().method(method).uri(path).header(
+        HOST,
+        format!("{}:{}", config.portal_host, config.portal_port),
+    );
+    let credential = match config.secret_key {
+        Some(ref key) => Some(key.0.clone()),
+        None => config.token().map_err(Error::TokenFileError)?,
+    };
+    if let Some(credential) = credential {
+        request = request.header(AUTHORIZATION, format!("Bearer {}", credential));
+    }
+    // only the sub-domain in the path can be malformed
+    let request = request
+        .body(Body::empty())
+        .map_err(|_| Error::InvalidSubDomain)?;
+
+    let response = sender.send_request(request).await?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    Ok((status, body))
+}
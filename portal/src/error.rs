@@ -28,4 +28,16 @@ pub enum Error {
 
     #[error("The server timed out sending us something.")]
     Timeout,
+
+    #[error("Failed to read the token file: {0}.")]
+    TokenFileError(std::io::Error),
+
+    #[error("Failed to read the maintenance page: {0}.")]
+    MaintenancePageError(std::io::Error),
+
+    #[error("Failed to connect to control server: {0}.")]
+    ControlConnectionError(#[from] std::io::Error),
+
+    #[error("Request to control server failed: {0}.")]
+    ControlRequestError(#[from] hyper::Error),
 }
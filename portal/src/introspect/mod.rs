@@ -224,7 +224,7 @@ enum DataType {
 
 async fn inspector() -> Result<Page<Inspector>, warp::reject::Rejection> {
     let mut requests: Vec<Request> = get_requests().read().unwrap().values().cloned().collect();
-    requests.sort_by(|a, b| b.completed.cmp(&a.completed));
+    requests.sort_by_key(|r| std::cmp::Reverse(r.completed));
     let inspect = Inspector { requests };
     Ok(Page(inspect))
 }
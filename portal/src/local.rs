@@ -4,18 +4,264 @@ use super::*;
 use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use futures::{SinkExt, StreamExt};
 
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::io::{ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+use tokio::sync::watch;
 use tokio_rustls::rustls::pki_types::ServerName;
-use tokio_rustls::rustls::{ClientConfig, RootCertStore};
-use tokio_rustls::TlsConnector;
 
 use crate::introspect::{self, introspect_stream, IntrospectChannels};
 
 pub trait AnyTcpStream: AsyncRead + AsyncWrite + Unpin + Send {}
 impl<T: AsyncRead + AsyncWrite + Unpin + Send> AnyTcpStream for T {}
 
+/// Where a local service listens
+#[derive(Debug, Clone, PartialEq)]
+pub enum LocalAddr {
+    Tcp {
+        host: String,
+        port: u16,
+    },
+    Unix(PathBuf),
+    /// the built-in file server
+    Dir {
+        path: PathBuf,
+        spa: bool,
+    },
+}
+
+impl LocalAddr {
+    /// `unix:/path/to.sock` hosts are unix sockets, the port is ignored for them
+    pub fn new(host: &str, port: u16) -> Self {
+        match host.strip_prefix(UNIX_SOCKET_PREFIX) {
+            Some(path) => LocalAddr::Unix(PathBuf::from(path)),
+            None => LocalAddr::Tcp {
+                host: host.to_string(),
+                port,
+            },
+        }
+    }
+
+    /// the name to check the certificate of the local service against
+    fn server_name(&self) -> &str {
+        match self {
+            LocalAddr::Tcp { host, .. } => host,
+            LocalAddr::Unix(_) | LocalAddr::Dir { .. } => DEFAULT_HOST,
+        }
+    }
+
+    pub fn url(&self, tls: bool) -> String {
+        let scheme = if tls { "https" } else { "http" };
+        match self {
+            LocalAddr::Tcp { host, port } => format!("{}://{}:{}", scheme, host, port),
+            LocalAddr::Unix(path) => format!("{}+unix:{}", scheme, path.display()),
+            LocalAddr::Dir { path, .. } => format!("file://{}", path.display()),
+        }
+    }
+}
+
+impl std::fmt::Display for LocalAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LocalAddr::Tcp { host, port } => write!(f, "{}:{}", host, port),
+            LocalAddr::Unix(path) => write!(f, "{}{}", UNIX_SOCKET_PREFIX, path.display()),
+            LocalAddr::Dir { path, .. } => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// A local stream, and what we need to resume it after reconnecting
+#[derive(Debug, Clone)]
+pub struct ActiveStream {
+    pub tx: UnboundedSender<StreamMessage>,
+    state: Arc<std::sync::Mutex<StreamState>>,
+    /// set once the stream is reset, so we stop reading from the local service
+    reset: Arc<watch::Sender<bool>>,
+}
+
+#[derive(Debug)]
+struct StreamState {
+    /// bytes sent to the server
+    sent: ReplayBuffer,
+    /// how many bytes we received from the server
+    received: u64,
+    /// the tunnel we are sending on, if still connected
+    tunnel: Option<UnboundedSender<ControlPacket>>,
+    /// follows `tunnel`, for readers waiting on a resume
+    attachment: Attachment,
+    /// the local service is done sending
+    local_done: bool,
+    /// the visitor is done sending
+    remote_done: bool,
+}
+
+impl ActiveStream {
+    pub fn new(tx: UnboundedSender<StreamMessage>, tunnel: UnboundedSender<ControlPacket>) -> Self {
+        let state = StreamState {
+            sent: ReplayBuffer::default(),
+            received: 0,
+            tunnel: Some(tunnel),
+            attachment: Attachment::default(),
+            local_done: false,
+            remote_done: false,
+        };
+
+        ActiveStream {
+            tx,
+            state: Arc::new(std::sync::Mutex::new(state)),
+            reset: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// The local service is done sending. Returns true if the visitor is too.
+    pub fn finish_local(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.local_done = true;
+        state.remote_done
+    }
+
+    /// The visitor is done sending. Returns true if the local service is too.
+    pub fn finish_remote(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.remote_done = true;
+        state.local_done
+    }
+
+    /// Abort the stream: drop the local connection, nothing more is sent either way
+    pub fn reset(&self) {
+        self.reset.send_replace(true);
+        let _ = self.tx.unbounded_send(StreamMessage::Reset);
+    }
+
+    /// Resolves once the stream is reset
+    async fn was_reset(&self) {
+        let _ = self.reset.subscribe().wait_for(|reset| *reset).await;
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.state.lock().unwrap().tunnel.is_some()
+    }
+
+    /// Send a packet to the server. Returns false if the tunnel is down.
+    pub fn send(&self, packet: ControlPacket) -> bool {
+        let mut state = self.state.lock().unwrap();
+        send_attached(&mut state, packet)
+    }
+
+    /// Send data to the server, keeping a copy to replay on resume.
+    /// Returns false if the tunnel is down.
+    pub fn send_data(&self, stream_id: &StreamId, data: Vec<u8>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.sent.push(&data);
+        send_attached(&mut state, ControlPacket::Data(stream_id.clone(), data))
+    }
+
+    /// Count data the server sent us
+    pub fn receive(&self, len: usize) {
+        self.state.lock().unwrap().received += len as u64;
+    }
+
+    /// Attach to a new tunnel: tell the server what we received and replay
+    /// what it missed after `offset`. Returns false if we can't resume.
+    pub fn resume(
+        &self,
+        stream_id: &StreamId,
+        tunnel: UnboundedSender<ControlPacket>,
+        offset: u64,
+    ) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let replay = match state.sent.replay_from(offset) {
+            Some(replay) => replay,
+            None => return false,
+        };
+
+        if tunnel
+            .unbounded_send(ControlPacket::Resume(stream_id.clone(), state.received))
+            .is_err()
+        {
+            return false;
+        }
+
+        info!(
+            "stream[{:?}] -> resumed, replaying {} bytes",
+            stream_id.to_string(),
+            replay.len()
+        );
+        state.tunnel = Some(tunnel);
+        state.attachment.set(true);
+        replay.is_empty()
+            || send_attached(&mut state, ControlPacket::Data(stream_id.clone(), replay))
+    }
+}
+
+fn send_attached(state: &mut StreamState, packet: ControlPacket) -> bool {
+    let sent = match state.tunnel {
+        Some(ref tunnel) => tunnel.unbounded_send(packet).is_ok(),
+        None => false,
+    };
+
+    if !sent {
+        state.tunnel = None;
+        state.attachment.set(false);
+    }
+    sent
+}
+
+/// How long (in seconds) the server holds our streams while we reconnect
+static RESUME_GRACE: AtomicU64 = AtomicU64::new(STREAM_RESUME_GRACE);
+
+pub fn set_resume_grace(secs: u64) {
+    RESUME_GRACE.store(secs, Ordering::Relaxed);
+}
+
+/// Wait for the tunnel of a stream to come back.
+/// Returns false if it wasn't resumed within the grace period.
+async fn wait_for_resume(active_stream: &ActiveStream) -> bool {
+    let grace = Duration::from_secs(RESUME_GRACE.load(Ordering::Relaxed));
+    let attachment = active_stream.state.lock().unwrap().attachment.clone();
+    attachment.wait(grace).await
+}
+
+/// Connect to a local service, over TLS if configured
+pub async fn connect_local(
+    config: &Config,
+    addr: &LocalAddr,
+) -> std::io::Result<Box<dyn AnyTcpStream>> {
+    let local_tcp: Box<dyn AnyTcpStream> = match addr {
+        LocalAddr::Tcp { host, port } => {
+            Box::new(TcpStream::connect((host.as_str(), *port)).await?)
+        }
+        #[cfg(unix)]
+        LocalAddr::Unix(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+        #[cfg(not(unix))]
+        LocalAddr::Unix(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "unix sockets are not supported on this platform",
+            ))
+        }
+        // served in process, no need for tls
+        LocalAddr::Dir { path, spa } => return Ok(Box::new(serve::connect(path, *spa))),
+    };
+    if !config.local_tls {
+        return Ok(local_tcp);
+    }
+
+    let server_name = config
+        .local_tls_options
+        .server_name
+        .as_deref()
+        .unwrap_or(addr.server_name());
+    let dns_name = ServerName::try_from(server_name.to_string())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+    Ok(Box::new(
+        get_local_tls().connect(dns_name, local_tcp).await?,
+    ))
+}
+
 /// Establish a new local stream and start processing messages to it
 pub async fn setup_new_stream(
     config: Config,
@@ -23,45 +269,25 @@ pub async fn setup_new_stream(
     stream_id: StreamId,
 ) -> Option<UnboundedSender<StreamMessage>> {
     info!("setting up local stream: {}", &stream_id.to_string());
-    debug!("connecting to local service: {:?}", config.local_addr);
-    let local_tcp = match TcpStream::connect(config.local_addr).await {
+
+    // pick the service per request once we see it
+    if !config.routes.is_empty() {
+        return Some(router::spawn_routed_stream(config, tunnel_tx, stream_id));
+    }
+
+    debug!("connecting to local service: {}", config.local_addr);
+    let local_tcp = match connect_local(&config, &config.local_addr).await {
         Ok(s) => s,
         Err(e) => {
             error!("failed to connect to local service: {}", e);
             introspect::connect_failed();
-            let _ = tunnel_tx.send(ControlPacket::Refused(stream_id)).await;
+            let _ = tunnel_tx
+                .send(ControlPacket::Reset(stream_id, CloseReason::from(&e)))
+                .await;
             return None;
         }
     };
 
-    let local_tcp: Box<dyn AnyTcpStream> = if config.local_tls {
-        let dns_name = config.local_host;
-        let mut root_store = RootCertStore::empty();
-
-        root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-
-        let config = ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-
-        let config = TlsConnector::from(Arc::new(config));
-        let dns_name = ServerName::try_from(dns_name).ok()?;
-
-        let stream = match config.connect(dns_name, local_tcp).await {
-            Ok(s) => s,
-            Err(e) => {
-                error!("failed to connect to TLS service: {}", e);
-                introspect::connect_failed();
-                let _ = tunnel_tx.send(ControlPacket::Refused(stream_id)).await;
-                return None;
-            }
-        };
-
-        Box::new(stream)
-    } else {
-        Box::new(local_tcp)
-    };
-
     let IntrospectChannels {
         request: introspect_request,
         response: introspect_response,
@@ -69,45 +295,92 @@ pub async fn setup_new_stream(
 
     let (stream, sink) = split(local_tcp);
 
-    // Read local tcp bytes, send them tunnel
-    let stream_id_clone = stream_id.clone();
-    tokio::spawn(async move {
-        process_local_tcp(stream, tunnel_tx, stream_id_clone, introspect_response).await;
-    });
-
     // Forward remote packets to local tcp
     let (tx, rx) = unbounded();
+    let active_stream = ActiveStream::new(tx.clone(), tunnel_tx);
     get_active_streams()
         .write()
         .unwrap()
-        .insert(stream_id.clone(), tx.clone());
+        .insert(stream_id.clone(), active_stream.clone());
+
+    // Read local tcp bytes, send them tunnel
+    let (reader_stream, reader_id) = (active_stream.clone(), stream_id.clone());
+    tokio::spawn(async move {
+        process_local_tcp(
+            stream,
+            reader_stream,
+            reader_id,
+            introspect_response,
+            || true,
+        )
+        .await;
+    });
 
     tokio::spawn(async move {
-        forward_to_local_tcp(sink, rx, introspect_request).await;
+        forward_to_local_tcp(sink, rx, active_stream, stream_id, introspect_request).await;
     });
 
     Some(tx)
 }
 
-pub async fn process_local_tcp<T>(
+/// Forward what the local service sends to the tunnel.
+/// The stream ends with the local connection if `is_current`.
+pub async fn process_local_tcp<T, F>(
     mut stream: ReadHalf<T>,
-    mut tunnel: UnboundedSender<ControlPacket>,
+    active_stream: ActiveStream,
     stream_id: StreamId,
     mut introspect: UnboundedSender<Vec<u8>>,
+    is_current: F,
 ) where
     T: AnyTcpStream,
+    F: Fn() -> bool,
 {
     let mut buf = [0; 4 * 1024];
 
     loop {
-        let n = stream
-            .read(&mut buf)
-            .await
-            .expect("failed to read data from socket");
+        // the tunnel is down, give it a chance to come back
+        if !wait_for_resume(&active_stream).await {
+            warn!("tunnel not resumed, closing local stream");
+            get_active_streams().write().unwrap().remove(&stream_id);
+            active_stream.reset();
+            return;
+        }
+
+        let n = tokio::select! {
+            read = stream.read(&mut buf) => match read {
+                Ok(n) => n,
+                // i.e. a local TLS service rejecting our client certificate
+                Err(e) => {
+                    error!("failed to read from local service: {}", e);
+                    if is_current() {
+                        get_active_streams().write().unwrap().remove(&stream_id);
+                        active_stream.send(ControlPacket::Reset(stream_id, CloseReason::from(&e)));
+                        active_stream.reset();
+                    }
+                    return;
+                }
+            },
+            _ = active_stream.was_reset() => {
+                info!("stream reset, closing local stream");
+                return;
+            }
+        };
 
         if n == 0 {
             info!("done reading from client stream");
-            get_active_streams().write().unwrap().remove(&stream_id);
+            // we moved on to another local service
+            if !is_current() {
+                return;
+            }
+            // the visitor may still be sending, the stream is over once it's done too
+            if active_stream.finish_local() {
+                get_active_streams().write().unwrap().remove(&stream_id);
+            }
+            // so the server finishes the stream rather than waiting on the visitor,
+            // i.e. while draining. Older servers ignore it.
+            if wait_for_resume(&active_stream).await {
+                active_stream.send(ControlPacket::End(stream_id));
+            }
             return;
         }
 
@@ -117,11 +390,9 @@ pub async fn process_local_tcp<T>(
             std::str::from_utf8(&data).unwrap_or("<non utf8>")
         );
 
-        let packet = ControlPacket::Data(stream_id.clone(), data.clone());
-        tunnel
-            .send(packet)
-            .await
-            .expect("failed to tunnel packet from local tcp to tunnel");
+        if !active_stream.send_data(&stream_id, data.clone()) {
+            warn!("tunnel down, holding local data for resume");
+        }
 
         let _ = introspect.send(data).await;
     }
@@ -130,6 +401,8 @@ pub async fn process_local_tcp<T>(
 async fn forward_to_local_tcp<T>(
     mut sink: WriteHalf<T>,
     mut queue: UnboundedReceiver<StreamMessage>,
+    active_stream: ActiveStream,
+    stream_id: StreamId,
     mut introspect: UnboundedSender<Vec<u8>>,
 ) where
     T: AnyTcpStream,
@@ -138,19 +411,147 @@ async fn forward_to_local_tcp<T>(
         let data = match queue.next().await {
             Some(StreamMessage::Data(data)) => data,
             None | Some(StreamMessage::Close) => {
-                warn!("closing stream");
+                info!("visitor done sending, closing local stream for writing");
                 let _ = sink.shutdown().await.map_err(|e| {
                     error!("failed to shutdown: {:?}", e);
                 });
                 return;
             }
+            Some(StreamMessage::Reset) => {
+                warn!("stream reset, dropping local stream");
+                return;
+            }
         };
 
-        sink.write_all(&data)
-            .await
-            .expect("failed to write packet data to local tcp socket");
+        if let Err(e) = sink.write_all(&data).await {
+            error!("failed to write to local service: {}", e);
+            get_active_streams().write().unwrap().remove(&stream_id);
+            active_stream.send(ControlPacket::Reset(stream_id, CloseReason::from(&e)));
+            active_stream.reset();
+            return;
+        }
         debug!("wrote to local service: {:?}", data.len());
 
         let _ = introspect.send(data).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a stream to a local service over an in-memory connection, and the service's end of it
+    fn local_stream() -> (
+        ActiveStream,
+        StreamId,
+        UnboundedReceiver<ControlPacket>,
+        tokio::io::DuplexStream,
+    ) {
+        let (local, service) = tokio::io::duplex(1024);
+        let (stream, sink) = split(local);
+        let (tunnel_tx, tunnel_rx) = unbounded();
+        let (tx, rx) = unbounded();
+        let active_stream = ActiveStream::new(tx, tunnel_tx);
+        let stream_id = StreamId::generate();
+        get_active_streams()
+            .write()
+            .unwrap()
+            .insert(stream_id.clone(), active_stream.clone());
+
+        tokio::spawn(process_local_tcp(
+            stream,
+            active_stream.clone(),
+            stream_id.clone(),
+            unbounded().0,
+            || true,
+        ));
+        tokio::spawn(forward_to_local_tcp(
+            sink,
+            rx,
+            active_stream.clone(),
+            stream_id.clone(),
+            unbounded().0,
+        ));
+        (active_stream, stream_id, tunnel_rx, service)
+    }
+
+    #[tokio::test]
+    async fn test_half_close() {
+        let (active_stream, stream_id, mut tunnel_rx, mut service) = local_stream();
+
+        // the visitor is done sending, the local service may still respond
+        active_stream
+            .tx
+            .unbounded_send(StreamMessage::Data(b"ping".to_vec()))
+            .unwrap();
+        assert!(!active_stream.finish_remote());
+        active_stream
+            .tx
+            .unbounded_send(StreamMessage::Close)
+            .unwrap();
+        let mut request = vec![];
+        service.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"ping");
+
+        service.write_all(b"pong").await.unwrap();
+        service.shutdown().await.unwrap();
+        assert!(matches!(
+            tunnel_rx.next().await,
+            Some(ControlPacket::Data(id, data)) if id == stream_id && data == b"pong"
+        ));
+        assert!(matches!(
+            tunnel_rx.next().await,
+            Some(ControlPacket::End(id)) if id == stream_id
+        ));
+        // done both ways
+        assert!(!get_active_streams()
+            .read()
+            .unwrap()
+            .contains_key(&stream_id));
+    }
+
+    #[tokio::test]
+    async fn test_reset() {
+        let (active_stream, _, mut tunnel_rx, mut service) = local_stream();
+        active_stream
+            .tx
+            .unbounded_send(StreamMessage::Data(b"ping".to_vec()))
+            .unwrap();
+        let mut request = [0; 4];
+        service.read_exact(&mut request).await.unwrap();
+
+        // the visitor aborted: the local connection is dropped, nothing goes back
+        active_stream.reset();
+        let mut rest = vec![];
+        tokio::time::timeout(Duration::from_secs(1), service.read_to_end(&mut rest))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(rest.is_empty());
+        assert!(service.write_all(b"pong").await.is_err());
+        assert!(tunnel_rx.try_next().is_err());
+    }
+
+    #[test]
+    fn test_local_addr() {
+        assert_eq!(
+            LocalAddr::new("localhost", 8000),
+            LocalAddr::Tcp {
+                host: "localhost".into(),
+                port: 8000
+            }
+        );
+        assert_eq!(
+            LocalAddr::new("unix:/run/app.sock", 8000),
+            LocalAddr::Unix("/run/app.sock".into())
+        );
+        assert_eq!(
+            LocalAddr::new("unix:/run/app.sock", 0).to_string(),
+            "unix:/run/app.sock"
+        );
+        assert_eq!(
+            LocalAddr::new("127.0.0.1", 8443).url(true),
+            "https://127.0.0.1:8443"
+        );
+    }
+}
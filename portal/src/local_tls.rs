@@ -0,0 +1,148 @@
+use std::error::Error;
+use std::path::Path;
+
+use super::*;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio_rustls::TlsConnector;
+
+/// Build the connector for local TLS services
+pub fn connector(options: &LocalTlsOptions) -> Result<TlsConnector, Box<dyn Error>> {
+    let builder = if options.insecure {
+        ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification::new()))
+    } else {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        if let Some(ref ca) = options.ca {
+            for cert in load_certs(ca)? {
+                roots.add(cert)?;
+            }
+        }
+        ClientConfig::builder().with_root_certificates(roots)
+    };
+
+    let config = match (&options.cert, &options.key) {
+        (Some(cert), Some(key)) => {
+            builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err("a local client certificate needs both a cert and a key".into()),
+    };
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Accepts any certificate, for local development only.
+/// Signatures are still checked so the handshake stays sound.
+#[derive(Debug)]
+struct NoVerification(CryptoProvider);
+
+impl NoVerification {
+    fn new() -> Self {
+        NoVerification(crypto::aws_lc_rs::default_provider())
+    }
+}
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn open(path: &Path) -> Result<std::fs::File, Box<dyn Error>> {
+    std::fs::File::open(path)
+        .map_err(|e| format!("failed to open {}: {}", path.display(), e).into())
+}
+
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Box<dyn Error>> {
+    let mut reader = std::io::BufReader::new(open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in {}", path.display()).into());
+    }
+    Ok(certs)
+}
+
+pub fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, Box<dyn Error>> {
+    let mut reader = std::io::BufReader::new(open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| format!("no private key in {}", path.display()).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connector() {
+        assert!(connector(&LocalTlsOptions::default()).is_ok());
+
+        let cert_only = LocalTlsOptions {
+            cert: Some("cert.pem".into()),
+            ..Default::default()
+        };
+        let err = connector(&cert_only).err().unwrap();
+        assert!(err.to_string().contains("needs both a cert and a key"));
+
+        let empty = std::env::temp_dir().join(format!("portal-empty-{}.pem", uuid::Uuid::new_v4()));
+        std::fs::write(&empty, "").unwrap();
+        let empty_ca = LocalTlsOptions {
+            ca: Some(empty.clone()),
+            ..Default::default()
+        };
+        let err = connector(&empty_ca).err().unwrap();
+        assert!(err.to_string().contains("no certificates in"));
+
+        let missing_ca = LocalTlsOptions {
+            ca: Some(empty.with_extension("missing")),
+            ..Default::default()
+        };
+        assert!(connector(&missing_ca).is_err());
+        std::fs::remove_file(empty).unwrap();
+    }
+}
@@ -1,9 +1,10 @@
 use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::stream::SplitStream;
 use futures::{SinkExt, StreamExt};
 
-use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::ServerName;
 use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::WebSocketStream;
 
 use human_panic::setup_panic;
 pub use log::{debug, error, info, warn};
@@ -15,11 +16,19 @@ use std::sync::{Arc, OnceLock, RwLock};
 
 mod cli;
 mod config;
+mod control_tls;
+mod domains;
 mod error;
 mod introspect;
 mod local;
+mod local_tls;
+mod proxy;
+mod reconnect;
+mod router;
+mod serve;
 mod update;
-use cli::{Cli, CliInterface};
+use cli::{Cli, CliInterface, Commands};
+use reconnect::Backoff;
 
 pub use self::error::*;
 
@@ -27,17 +36,18 @@ pub use config::*;
 pub use portal_lib::*;
 
 use clap::Parser;
-use futures::future::Either;
 use std::time::Duration;
 use tokio::sync::Mutex;
 
-pub type ActiveStreams = Arc<RwLock<HashMap<StreamId, UnboundedSender<StreamMessage>>>>;
+pub type ActiveStreams = Arc<RwLock<HashMap<StreamId, local::ActiveStream>>>;
 
 static CLI: OnceLock<Cli> = OnceLock::new();
 static ACTIVE_STREAMS: OnceLock<ActiveStreams> = OnceLock::new();
 static RECONNECT_TOKEN: OnceLock<Arc<Mutex<Option<ReconnectToken>>>> = OnceLock::new();
 static CONFIG: OnceLock<Config> = OnceLock::new();
 static FIRST_RUN: OnceLock<Mutex<bool>> = OnceLock::new();
+static LOCAL_TLS: OnceLock<tokio_rustls::TlsConnector> = OnceLock::new();
+static CONTROL_TLS: OnceLock<tokio_rustls::TlsConnector> = OnceLock::new();
 
 pub fn get_cli() -> &'static Cli {
     CLI.get_or_init(Cli::parse)
@@ -54,7 +64,10 @@ pub fn get_reconnect_token() -> &'static Arc<Mutex<Option<ReconnectToken>>> {
 pub fn get_config() -> &'static Config {
     CONFIG.get_or_init(|| match get_cli().config {
         Some(ref config_path) => Config::load_from_file(config_path.to_str().unwrap()).unwrap(),
-        None => Config::load().unwrap(),
+        None => Config::load().unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1)
+        }),
     })
 }
 
@@ -62,56 +75,114 @@ pub fn get_first_run() -> &'static Mutex<bool> {
     FIRST_RUN.get_or_init(|| Mutex::new(true))
 }
 
+pub fn get_local_tls() -> &'static tokio_rustls::TlsConnector {
+    LOCAL_TLS
+        .get()
+        .expect("the local tls connector is built with the config")
+}
+
+pub fn get_control_tls() -> &'static tokio_rustls::TlsConnector {
+    CONTROL_TLS
+        .get()
+        .expect("the control tls connector is built with the config")
+}
+
 #[derive(Debug, Clone)]
 pub enum StreamMessage {
     Data(Vec<u8>),
+    /// the visitor is done sending, shut down our side of the local connection
     Close,
+    /// the visitor aborted, drop the local connection
+    Reset,
 }
 
 #[tokio::main]
 async fn main() {
     setup_panic!();
     let config = get_config();
+
+    if let Some(Commands::Domains { ref command }) = get_cli().command {
+        if let Err(e) = domains::run(config, command).await {
+            bunt::eprintln!("{$red}Error: {e}{/$}", e = e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     update::check().await;
 
     let introspect_dash_addr = introspect::start_introspect_web_dashboard(config.clone());
 
+    let backoff = Backoff::new(config.max_reconnect_attempts, config.reconnect_timeout);
+
     loop {
         let (restart_tx, mut restart_rx) = unbounded();
-        let wormhole = run_wormhole(config.clone(), introspect_dash_addr, restart_tx);
-        let result = futures::future::select(Box::pin(wormhole), restart_rx.next()).await;
-        let mut first_run = get_first_run().lock().await;
-        *first_run = false;
-
-        match result {
-            Either::Left((Err(e), _)) => match e {
-                Error::WebSocketError(_) | Error::NoResponseFromServer | Error::Timeout => {
-                    error!("Control error: {:?}. Retrying in 5 seconds.", e);
-                    tokio::time::sleep(Duration::from_secs(5)).await;
-                }
-                Error::AuthenticationFailed => {
-                    if config.secret_key.is_none() {
+        let wormhole = run_wormhole(config.clone(), introspect_dash_addr, restart_tx, &backoff);
+
+        // why we got disconnected, if we should wait before reconnecting
+        let reason = tokio::select! {
+            result = wormhole => match result {
+                Ok(_) => Some("connection closed".to_string()),
+                Err(e) => match e {
+                    Error::WebSocketError(_) | Error::NoResponseFromServer | Error::Timeout => {
+                        error!("Control error: {:?}.", e);
+                        Some(e.to_string())
+                    }
+                    Error::AuthenticationFailed => {
+                        if config.secret_key.is_none()
+                            && config.token.is_none()
+                            && config.token_file.is_none()
+                        {
+                            bunt::eprintln!(
+                                "{$yellow}>> Please use an access key with the `--key` option{/$}"
+                            );
+                        }
                         bunt::eprintln!(
-                            "{$yellow}>> Please use an access key with the `--key` option{/$}"
+                            "{$yellow}>> You can get your access key here: {/$}{$yellow+underline}https://dashboard.portal.illusiontech.cn{/$}"
                         );
+                        bunt::eprintln!("{$red}\nError: {e}{/$}", e = e);
+                        return;
+                    }
+                    _ => {
+                        bunt::eprintln!("{$red}Error: {e}{/$}", e = e);
+                        return;
                     }
+                },
+            },
+            restart = restart_rx.next() => match restart.flatten() {
+                Some(e) => {
+                    warn!("restarting from error: {:?}", e);
+                    Some(e.to_string())
+                }
+                None => Some("server closed the tunnel".to_string()),
+            },
+            _ = reconnect::network_changed(config) => None,
+        };
+
+        *get_first_run().lock().await = false;
+
+        if let Some(reason) = reason {
+            let delay = match backoff.next_delay() {
+                Some(delay) => delay,
+                None => {
                     bunt::eprintln!(
-                        "{$yellow}>> You can get your access key here: {/$}{$yellow+underline}https://dashboard.portal.illusiontech.cn{/$}"
+                        "{$red}Error: tunnel disconnected ({}), giving up after {} attempts.{/$}",
+                        reason,
+                        backoff.attempt() - 1
                     );
-                    bunt::eprintln!("{$red}\nError: {e}{/$}", e = e);
                     return;
                 }
-                _ => {
-                    bunt::eprintln!("{$red}Error: {e}{/$}", e = e);
-                    return;
+            };
+
+            let spinner = CliInterface::reconnecting(backoff.attempt(), delay, &reason);
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = reconnect::network_changed(config) => {
+                    info!("network changed, reconnecting now");
                 }
-            },
-            Either::Right((Some(e), _)) => {
-                warn!("restarting in 3 seconds...from error: {:?}", e);
-                tokio::time::sleep(Duration::from_secs(3)).await;
             }
-            _ => {}
-        };
+            spinner.finish_and_clear();
+        }
 
         info!("restarting wormhole");
     }
@@ -122,6 +193,7 @@ async fn run_wormhole(
     config: Config,
     introspect_web_addr: SocketAddr,
     mut restart_tx: UnboundedSender<Option<Error>>,
+    backoff: &Backoff,
 ) -> Result<(), Error> {
     let interface = CliInterface::start(config.clone(), introspect_web_addr);
     tokio::time::sleep(std::time::Duration::from_millis(500)).await;
@@ -130,6 +202,7 @@ async fn run_wormhole(
         sub_domain,
         hostname,
     } = connect_to_wormhole(&config).await?;
+    backoff.reset();
 
     interface.did_connect(&sub_domain, &hostname).await;
 
@@ -161,11 +234,11 @@ async fn run_wormhole(
     });
 
     // continuously read from websocket tunnel
-
     loop {
         match ws_stream.next().await {
             Some(Ok(message)) if message.is_close() => {
                 debug!("got close message");
+                tunnel_tx.close_channel();
                 let _ = restart_tx.send(None).await;
                 return Ok(());
             }
@@ -181,44 +254,103 @@ async fn run_wormhole(
                     Error::MalformedMessageFromServer
                 })?;
                 debug!("Processed packet: {:?}", packet.packet_type());
+
+                if let ControlPacket::Reconnect(_) = packet {
+                    info!("server is going away, reconnecting");
+                    // keep serving in-flight streams while we reconnect elsewhere
+                    tokio::spawn(drain_wormhole(config.clone(), tunnel_tx.clone(), ws_stream));
+                    let _ = restart_tx.send(None).await;
+                    return Ok(());
+                }
             }
             Some(Err(e)) => {
                 warn!("websocket read error: {:?}", e);
+                // detach our streams so they wait to be resumed
+                tunnel_tx.close_channel();
                 return Err(Error::Timeout);
             }
             None => {
                 warn!("websocket sent none");
+                tunnel_tx.close_channel();
                 return Err(Error::Timeout);
             }
         }
     }
 }
 
+/// Process messages for in-flight streams until the server closes the wormhole
+async fn drain_wormhole(
+    config: Config,
+    tunnel_tx: UnboundedSender<ControlPacket>,
+    mut ws_stream: SplitStream<WormholeStream>,
+) {
+    while let Some(Ok(message)) = ws_stream.next().await {
+        if message.is_close() {
+            break;
+        }
+
+        if let Err(e) =
+            process_control_flow_message(config.clone(), tunnel_tx.clone(), message.into_data())
+                .await
+        {
+            error!("Malformed protocol control packet: {:?}", e);
+        }
+    }
+
+    debug!("drained wormhole closed");
+}
+
+/// The websocket to the control server, over TLS unless turned off
+type WormholeStream = WebSocketStream<Box<dyn local::AnyTcpStream>>;
+
 struct Wormhole {
-    websocket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    websocket: WormholeStream,
     sub_domain: String,
     hostname: String,
 }
 
+/// Connect to the control server, through the proxy and over TLS if we use them
+async fn connect_to_control(config: &Config) -> std::io::Result<Box<dyn local::AnyTcpStream>> {
+    let proxy = proxy::proxy_for(config, config.portal_tls, &config.portal_host);
+    let stream = proxy::connect(proxy.as_ref(), &config.portal_host, config.portal_port).await?;
+    if !config.portal_tls {
+        return Ok(Box::new(stream));
+    }
+
+    let server_name = ServerName::try_from(config.portal_host.clone())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let stream = get_control_tls().connect(server_name, stream).await?;
+    Ok(Box::new(stream))
+}
+
 async fn connect_to_wormhole(config: &Config) -> Result<Wormhole, Error> {
     debug!("connecting to wormhole at {}", config.portal_url());
-    let (mut websocket, _) = tokio_tungstenite::connect_async(&config.portal_url()).await?;
+    let stream = connect_to_control(config)
+        .await
+        .map_err(tokio_tungstenite::tungstenite::Error::Io)?;
+    let (mut websocket, _) = tokio_tungstenite::client_async(config.portal_url(), stream).await?;
 
     // send our Client Hello message
-    let client_hello = match config.secret_key.clone() {
-        Some(secret_key) => ClientHello::generate(
-            config.sub_domain.clone(),
-            ClientType::Auth { key: secret_key },
-        ),
-        None => {
-            // if we have a reconnect token, use it.
-            if let Some(reconnect) = get_reconnect_token().lock().await.clone() {
-                ClientHello::reconnect(reconnect)
-            } else {
-                ClientHello::generate(config.sub_domain.clone(), ClientType::Anonymous)
-            }
+    // if we have a reconnect token, use it.
+    let reconnect_token = get_reconnect_token().lock().await.clone();
+    let client_type = match (config.secret_key.clone(), config.token()) {
+        (Some(key), _) => ClientType::Auth { key },
+        (None, Ok(Some(token))) => ClientType::Token { token },
+        (None, Ok(None)) => ClientType::Anonymous,
+        (None, Err(e)) => return Err(Error::TokenFileError(e)),
+    };
+    let mut client_hello = match (client_type, reconnect_token) {
+        (ClientType::Anonymous, Some(reconnect)) => ClientHello::reconnect(reconnect),
+        (client_type, reconnect_token) => {
+            let mut hello = ClientHello::generate(config.sub_domain.clone(), client_type);
+            hello.reconnect_token = reconnect_token;
+            hello.pool = config.pool;
+            hello
         }
     };
+    client_hello.maintenance_page = config
+        .maintenance_page()
+        .map_err(Error::MaintenancePageError)?;
 
     info!("connecting to wormhole...");
 
@@ -244,8 +376,10 @@ async fn connect_to_wormhole(config: &Config) -> Result<Wormhole, Error> {
             sub_domain,
             client_id,
             hostname,
+            stream_resume_grace,
         } => {
             info!("Server accepted our connection. I am client_{}", client_id);
+            local::set_resume_grace(stream_resume_grace.unwrap_or(STREAM_RESUME_GRACE));
             (sub_domain, hostname)
         }
         ServerHello::AuthFailed => {
@@ -289,27 +423,67 @@ async fn process_control_flow_message(
             }
             let _ = tunnel_tx.send(ControlPacket::Ping(None)).await;
         }
-        ControlPacket::Refused(_) => return Err("unexpected control packet".into()),
+        ControlPacket::Reconnect(reconnect_token) => {
+            log::info!(
+                "got reconnect. reconnect_token={}",
+                reconnect_token.is_some()
+            );
+
+            if let Some(reconnect) = reconnect_token {
+                let _ = get_reconnect_token()
+                    .lock()
+                    .await
+                    .replace(reconnect.clone());
+            }
+        }
+        ControlPacket::Resume(stream_id, offset) => {
+            info!(
+                "stream[{:?}] -> resume from {}",
+                stream_id.to_string(),
+                offset
+            );
+
+            let stream = get_active_streams().read().unwrap().get(stream_id).cloned();
+            let resumed = stream
+                .as_ref()
+                .is_some_and(|stream| stream.resume(stream_id, tunnel_tx.clone(), *offset));
+
+            if !resumed {
+                warn!("unable to resume stream [{:?}]", stream_id.to_string());
+                if let Some(stream) = stream {
+                    stream.reset();
+                    get_active_streams().write().unwrap().remove(stream_id);
+                }
+                tunnel_tx
+                    .send(ControlPacket::Reset(stream_id.clone(), CloseReason::Error))
+                    .await?;
+            }
+        }
+        ControlPacket::Reset(stream_id, reason) => {
+            info!(
+                "got reset stream [{:?}]: {:?}",
+                stream_id.to_string(),
+                reason
+            );
+
+            let stream = get_active_streams().write().unwrap().remove(stream_id);
+            if let Some(stream) = stream {
+                stream.reset();
+            }
+        }
         ControlPacket::End(stream_id) => {
-            // find the stream
-            let stream_id = stream_id.clone();
-
-            info!("got end stream [{:?}]", &stream_id);
-
-            tokio::spawn(async move {
-                let stream = get_active_streams()
-                    .read()
-                    .unwrap()
-                    .get(&stream_id)
-                    .cloned();
-                if let Some(mut tx) = stream {
-                    tokio::time::sleep(Duration::from_secs(5)).await;
-                    let _ = tx.send(StreamMessage::Close).await.map_err(|e| {
-                        error!("failed to send stream close: {:?}", e);
-                    });
-                    get_active_streams().write().unwrap().remove(&stream_id);
+            info!("got end stream [{:?}]", stream_id.to_string());
+
+            let stream = get_active_streams().read().unwrap().get(stream_id).cloned();
+            if let Some(mut stream) = stream {
+                // the local service may still be sending, the stream is over once it's done too
+                if stream.finish_remote() {
+                    get_active_streams().write().unwrap().remove(stream_id);
                 }
-            });
+                let _ = stream.tx.send(StreamMessage::Close).await.map_err(|e| {
+                    error!("failed to send stream close: {:?}", e);
+                });
+            }
         }
         ControlPacket::Data(stream_id, data) => {
             info!(
@@ -330,13 +504,14 @@ async fn process_control_flow_message(
             let active_stream = get_active_streams().read().unwrap().get(stream_id).cloned();
 
             // forward data to it
-            if let Some(mut tx) = active_stream {
-                tx.send(StreamMessage::Data(data.clone())).await?;
+            if let Some(mut stream) = active_stream {
+                stream.receive(data.len());
+                stream.tx.send(StreamMessage::Data(data.clone())).await?;
                 info!("forwarded to local tcp ({})", stream_id.to_string());
             } else {
                 error!("got data but no stream to send it to.");
                 tunnel_tx
-                    .send(ControlPacket::Refused(stream_id.clone()))
+                    .send(ControlPacket::Reset(stream_id.clone(), CloseReason::Error))
                     .await?;
             }
         }
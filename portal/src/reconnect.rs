@@ -0,0 +1,175 @@
+use super::*;
+use rand::Rng;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Instant;
+use tokio::net::UdpSocket;
+
+/// The first reconnect delay, doubled on each failed attempt
+const BASE_DELAY: Duration = Duration::from_secs(1);
+/// The longest we ever wait between attempts
+const MAX_DELAY: Duration = Duration::from_secs(60);
+/// How often we check if our route to the control server changed
+const NETWORK_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Exponential backoff with jitter between reconnect attempts
+pub struct Backoff {
+    attempt: AtomicU32,
+    disconnected_since: std::sync::Mutex<Option<Instant>>,
+    max_attempts: Option<u32>,
+    timeout: Option<Duration>,
+}
+
+impl Backoff {
+    pub fn new(max_attempts: Option<u32>, timeout: Option<Duration>) -> Self {
+        Self {
+            attempt: AtomicU32::new(0),
+            disconnected_since: std::sync::Mutex::new(None),
+            max_attempts,
+            timeout,
+        }
+    }
+
+    /// We're connected again: start over
+    pub fn reset(&self) {
+        self.attempt.store(0, Ordering::SeqCst);
+        *self.disconnected_since.lock().unwrap() = None;
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt.load(Ordering::SeqCst)
+    }
+
+    /// How long to wait before the next attempt, or None if we should give up
+    pub fn next_delay(&self) -> Option<Duration> {
+        let attempt = self.attempt.fetch_add(1, Ordering::SeqCst) + 1;
+        let disconnected_since = *self
+            .disconnected_since
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Instant::now);
+
+        if self.max_attempts.is_some_and(|max| attempt > max) {
+            return None;
+        }
+
+        if self
+            .timeout
+            .is_some_and(|timeout| disconnected_since.elapsed() >= timeout)
+        {
+            return None;
+        }
+
+        // equal jitter: wait at least half the backoff, so clients spread out
+        // without ever retrying immediately
+        let delay = BASE_DELAY
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(MAX_DELAY);
+        let half = delay / 2;
+        Some(half + half.mul_f64(rand::thread_rng().gen()))
+    }
+}
+
+/// The address of the control server, retrying until it resolves
+async fn resolve_control_server(config: &Config) -> SocketAddr {
+    loop {
+        match tokio::net::lookup_host((config.portal_host.as_str(), config.portal_port)).await {
+            Ok(mut addrs) => {
+                if let Some(addr) = addrs.next() {
+                    return addr;
+                }
+            }
+            Err(e) => debug!("failed to resolve control server: {:?}", e),
+        }
+        tokio::time::sleep(NETWORK_POLL_INTERVAL).await;
+    }
+}
+
+/// The local address we'd use to reach the control server
+async fn local_route(remote: SocketAddr) -> Option<IpAddr> {
+    let bind = if remote.is_ipv4() {
+        SocketAddr::from(([0, 0, 0, 0], 0))
+    } else {
+        SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], 0))
+    };
+
+    // connecting a udp socket sends nothing, it just picks a route
+    let socket = UdpSocket::bind(bind).await.ok()?;
+    socket.connect(remote).await.ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+/// Resolves once our route to the control server changes.
+/// Failed lookups don't count, the connection itself tells us if we're offline.
+pub async fn network_changed(config: &Config) {
+    let remote = resolve_control_server(config).await;
+    let mut initial = None;
+
+    loop {
+        if let Some(current) = local_route(remote).await {
+            match initial {
+                None => initial = Some(current),
+                Some(initial) if initial != current => {
+                    info!("network changed: {} => {}", initial, current);
+                    return;
+                }
+                Some(_) => {}
+            }
+        }
+
+        tokio::time::sleep(NETWORK_POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the delay of an attempt is between half and all of its backoff
+    fn assert_delay(delay: Option<Duration>, backoff: Duration) {
+        let delay = delay.expect("gave up too early");
+        assert!(delay >= backoff / 2 && delay <= backoff, "{:?}", delay);
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_cap() {
+        let backoff = Backoff::new(None, None);
+        assert_delay(backoff.next_delay(), Duration::from_secs(1));
+        assert_delay(backoff.next_delay(), Duration::from_secs(2));
+        assert_delay(backoff.next_delay(), Duration::from_secs(4));
+        assert_eq!(backoff.attempt(), 3);
+
+        for secs in [8, 16, 32] {
+            assert_delay(backoff.next_delay(), Duration::from_secs(secs));
+        }
+        // capped from then on, however long we keep failing
+        for _ in 0..40 {
+            assert_delay(backoff.next_delay(), MAX_DELAY);
+        }
+    }
+
+    #[test]
+    fn test_backoff_reset() {
+        let backoff = Backoff::new(Some(2), None);
+        backoff.next_delay();
+        backoff.next_delay();
+        assert!(backoff.next_delay().is_none());
+
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert_delay(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_backoff_gives_up() {
+        let backoff = Backoff::new(Some(3), None);
+        for _ in 0..3 {
+            assert!(backoff.next_delay().is_some());
+        }
+        assert!(backoff.next_delay().is_none());
+
+        // disconnected for longer than the timeout
+        let backoff = Backoff::new(None, Some(Duration::ZERO));
+        assert!(backoff.next_delay().is_none());
+    }
+}
@@ -0,0 +1,569 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::*;
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use tokio::io::{split, AsyncWriteExt, WriteHalf};
+use tokio::task::JoinHandle;
+
+use crate::introspect::{self, IntrospectChannels};
+use crate::local::{self, ActiveStream, AnyTcpStream, LocalAddr};
+
+/// How big a request head or chunk line may get before we stop parsing
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// How long a previous local service may take to finish its responses
+/// before the next request goes to another one
+const PREVIOUS_RESPONSE_WAIT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Send requests under a path prefix to another local service
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "RouteRuleConfig")]
+pub struct RouteRule {
+    pub prefix: String,
+    /// a host name, or a unix socket: `unix:/path/to.sock`
+    pub host: String,
+    /// ignored for unix sockets
+    pub port: u16,
+    /// remove the prefix from the path before forwarding
+    pub strip_prefix: bool,
+}
+
+/// A route in the config file, the port may only be left out for unix sockets
+#[derive(Deserialize)]
+struct RouteRuleConfig {
+    prefix: String,
+    #[serde(default = "default_route_host")]
+    host: String,
+    port: Option<u16>,
+    #[serde(default)]
+    strip_prefix: bool,
+}
+
+impl TryFrom<RouteRuleConfig> for RouteRule {
+    type Error = String;
+
+    fn try_from(config: RouteRuleConfig) -> Result<Self, Self::Error> {
+        let port = match config.port {
+            Some(port) => port,
+            None if config.host.starts_with(UNIX_SOCKET_PREFIX) => 0,
+            None => return Err(format!("route {} needs a port", config.prefix)),
+        };
+
+        Ok(RouteRule {
+            prefix: config.prefix,
+            host: config.host,
+            port,
+            strip_prefix: config.strip_prefix,
+        })
+    }
+}
+
+fn default_route_host() -> String {
+    DEFAULT_HOST.to_string()
+}
+
+impl RouteRule {
+    fn matches(&self, path: &str) -> bool {
+        let prefix = self.prefix.trim_end_matches('/');
+        match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || rest.starts_with('?'),
+            None => false,
+        }
+    }
+
+    pub fn addr(&self) -> LocalAddr {
+        LocalAddr::new(&self.host, self.port)
+    }
+}
+
+/// Parse `PREFIX=[HOST:]PORT`, i.e. `/api=8080`, `/api=127.0.0.1:8080`
+/// or `/api=unix:/path/to.sock`
+impl FromStr for RouteRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, target) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected PREFIX=[HOST:]PORT, got {}", s))?;
+        if !prefix.starts_with('/') {
+            return Err(format!("route prefix must start with '/': {}", prefix));
+        }
+
+        let (host, port) = match target.rsplit_once(':') {
+            _ if target.starts_with(UNIX_SOCKET_PREFIX) => (target.to_string(), 0),
+            Some((host, port)) => (host.to_string(), parse_port(port)?),
+            None => (default_route_host(), parse_port(target)?),
+        };
+
+        Ok(RouteRule {
+            prefix: prefix.to_string(),
+            host,
+            port,
+            strip_prefix: false,
+        })
+    }
+}
+
+fn parse_port(port: &str) -> Result<u16, String> {
+    port.parse()
+        .map_err(|_| format!("invalid route port: {}", port))
+}
+
+/// The rule with the longest prefix matching `path`
+fn find_rule<'a>(rules: &'a [RouteRule], path: &str) -> Option<&'a RouteRule> {
+    rules
+        .iter()
+        .filter(|rule| rule.matches(path))
+        .max_by_key(|rule| rule.prefix.len())
+}
+
+/// Route each request of a stream to the local service its path maps to
+pub fn spawn_routed_stream(
+    config: Config,
+    tunnel_tx: UnboundedSender<ControlPacket>,
+    stream_id: StreamId,
+) -> UnboundedSender<StreamMessage> {
+    let (tx, rx) = futures::channel::mpsc::unbounded();
+    let active_stream = ActiveStream::new(tx.clone(), tunnel_tx);
+    get_active_streams()
+        .write()
+        .unwrap()
+        .insert(stream_id.clone(), active_stream.clone());
+
+    tokio::spawn(async move {
+        route_requests(config, rx, active_stream, stream_id).await;
+    });
+
+    tx
+}
+
+/// The local connection serving the current request
+struct Upstream {
+    target: LocalAddr,
+    sink: WriteHalf<Box<dyn AnyTcpStream>>,
+    /// sends its responses back to the visitor
+    reader: JoinHandle<()>,
+}
+
+async fn route_requests(
+    config: Config,
+    mut queue: UnboundedReceiver<StreamMessage>,
+    active_stream: ActiveStream,
+    stream_id: StreamId,
+) {
+    let IntrospectChannels {
+        request: mut introspect_request,
+        response: introspect_response,
+    } = introspect::introspect_stream();
+
+    let mut framer = RequestFramer::default();
+    let mut upstream: Option<Upstream> = None;
+    // bumped on every new upstream, only the current one may end the stream
+    let generation = Arc::new(AtomicU64::new(0));
+
+    loop {
+        let data = match queue.next().await {
+            Some(StreamMessage::Data(data)) => data,
+            None | Some(StreamMessage::Close) => {
+                info!("visitor done sending, closing local stream for writing");
+                match upstream {
+                    Some(mut upstream) => {
+                        let _ = upstream.sink.shutdown().await;
+                    }
+                    // no local service got a request, none will answer either
+                    None => {
+                        get_active_streams().write().unwrap().remove(&stream_id);
+                        active_stream.send(ControlPacket::End(stream_id));
+                    }
+                }
+                return;
+            }
+            Some(StreamMessage::Reset) => {
+                warn!("stream reset, dropping local stream");
+                return;
+            }
+        };
+
+        for frame in framer.push(&data) {
+            let (target, data) = match frame {
+                Frame::Request(mut request) => {
+                    let rule = find_rule(&config.routes, &request.path);
+                    if let Some(rule) = rule.filter(|rule| rule.strip_prefix) {
+                        request.strip_prefix(&rule.prefix);
+                    }
+                    let target = match rule {
+                        Some(rule) => rule.addr(),
+                        None => config.local_addr.clone(),
+                    };
+                    debug!("routing {} to {}", request.path, target);
+                    (Some(target), request.head)
+                }
+                // not a request: it goes where the last one went
+                Frame::Data(data) if upstream.is_some() => (None, data),
+                Frame::Data(data) => (Some(config.local_addr.clone()), data),
+            };
+
+            if let Some(target) = target.filter(|t| upstream.as_ref().map(|u| &u.target) != Some(t))
+            {
+                if let Some(mut previous) = upstream.take() {
+                    // it may no longer end the stream
+                    generation.fetch_add(1, Ordering::SeqCst);
+                    let _ = previous.sink.shutdown().await;
+                    // a pipelined request must not get its response before the ones
+                    // sent ahead of it, let the previous service finish first
+                    if tokio::time::timeout(PREVIOUS_RESPONSE_WAIT, &mut previous.reader)
+                        .await
+                        .is_err()
+                    {
+                        warn!("{} still responding, dropping it", previous.target);
+                        previous.reader.abort();
+                    }
+                }
+
+                let local = match local::connect_local(&config, &target).await {
+                    Ok(local) => local,
+                    Err(e) => {
+                        error!("failed to connect to local service {}: {}", target, e);
+                        introspect::connect_failed();
+                        get_active_streams().write().unwrap().remove(&stream_id);
+                        active_stream.send(ControlPacket::Reset(stream_id, CloseReason::from(&e)));
+                        return;
+                    }
+                };
+
+                let (stream, sink) = split(local);
+                let current = generation.fetch_add(1, Ordering::SeqCst) + 1;
+                let generation = generation.clone();
+                let active_stream = active_stream.clone();
+                let stream_id = stream_id.clone();
+                let introspect = introspect_response.clone();
+                let reader = tokio::spawn(async move {
+                    local::process_local_tcp(stream, active_stream, stream_id, introspect, || {
+                        generation.load(Ordering::SeqCst) == current
+                    })
+                    .await;
+                });
+
+                upstream = Some(Upstream {
+                    target,
+                    sink,
+                    reader,
+                });
+            }
+
+            if let Some(upstream) = upstream.as_mut() {
+                if let Err(e) = upstream.sink.write_all(&data).await {
+                    error!("failed to write to local service: {}", e);
+                    get_active_streams().write().unwrap().remove(&stream_id);
+                    active_stream.send(ControlPacket::Reset(stream_id, CloseReason::from(&e)));
+                    active_stream.reset();
+                    return;
+                }
+                let _ = introspect_request.send(data).await;
+            }
+        }
+    }
+}
+
+/// The head of an HTTP request
+#[derive(Debug)]
+struct RequestHead {
+    path: String,
+    head: Vec<u8>,
+}
+
+impl RequestHead {
+    /// Remove `prefix` from the request target
+    fn strip_prefix(&mut self, prefix: &str) {
+        let prefix = prefix.trim_end_matches('/');
+        let path = match self.path.strip_prefix(prefix) {
+            Some(rest) if rest.starts_with('/') => rest.to_string(),
+            Some(rest) => format!("/{}", rest),
+            None => return,
+        };
+
+        // the request line is `METHOD SP path SP version`
+        let method_end = match self.head.iter().position(|b| *b == b' ') {
+            Some(i) => i + 1,
+            None => return,
+        };
+        let path_end = method_end + self.path.len();
+        self.head.splice(method_end..path_end, path.bytes());
+        self.path = path;
+    }
+}
+
+#[derive(Debug)]
+enum Frame {
+    /// a new request starts
+    Request(RequestHead),
+    /// the rest of the current request
+    Data(Vec<u8>),
+}
+
+/// Where we are in the request stream
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Head,
+    Body(u64),
+    ChunkSize,
+    /// chunk data and its trailing CRLF
+    ChunkData(u64),
+    Trailer,
+    /// an upgraded connection, or something we can't parse:
+    /// everything goes to the current service
+    Passthrough,
+}
+
+/// Splits the bytes of an HTTP/1 connection into requests
+#[derive(Debug)]
+struct RequestFramer {
+    buf: Vec<u8>,
+    state: State,
+}
+
+impl Default for RequestFramer {
+    fn default() -> Self {
+        RequestFramer {
+            buf: vec![],
+            state: State::Head,
+        }
+    }
+}
+
+impl RequestFramer {
+    fn push(&mut self, data: &[u8]) -> Vec<Frame> {
+        self.buf.extend_from_slice(data);
+        let mut frames = vec![];
+
+        loop {
+            match self.state {
+                State::Head => {
+                    let end = match find(&self.buf, b"\r\n\r\n") {
+                        Some(end) => end + 4,
+                        None if self.buf.len() > MAX_HEAD_SIZE => {
+                            self.state = State::Passthrough;
+                            continue;
+                        }
+                        None => break,
+                    };
+
+                    let head: Vec<u8> = self.buf.drain(..end).collect();
+                    match parse_head(&head) {
+                        Some((path, body)) => {
+                            self.state = body;
+                            frames.push(Frame::Request(RequestHead { path, head }));
+                        }
+                        None => {
+                            self.state = State::Passthrough;
+                            frames.push(Frame::Data(head));
+                        }
+                    }
+                }
+                State::Body(remaining) | State::ChunkData(remaining) => {
+                    if self.buf.is_empty() {
+                        break;
+                    }
+                    let n = remaining.min(self.buf.len() as u64);
+                    frames.push(Frame::Data(self.buf.drain(..n as usize).collect()));
+
+                    self.state = match (self.state, remaining - n) {
+                        (State::Body(_), 0) => State::Head,
+                        (State::ChunkData(_), 0) => State::ChunkSize,
+                        (State::ChunkData(_), left) => State::ChunkData(left),
+                        (_, left) => State::Body(left),
+                    };
+                }
+                State::ChunkSize | State::Trailer => {
+                    let end = match find(&self.buf, b"\r\n") {
+                        Some(end) => end + 2,
+                        None if self.buf.len() > MAX_HEAD_SIZE => {
+                            self.state = State::Passthrough;
+                            continue;
+                        }
+                        None => break,
+                    };
+
+                    let line: Vec<u8> = self.buf.drain(..end).collect();
+                    self.state = match self.state {
+                        State::ChunkSize => match parse_chunk_size(&line) {
+                            Some(0) => State::Trailer,
+                            Some(size) => State::ChunkData(size + 2),
+                            None => State::Passthrough,
+                        },
+                        _ if line == b"\r\n" => State::Head,
+                        _ => State::Trailer,
+                    };
+                    frames.push(Frame::Data(line));
+                }
+                State::Passthrough => {
+                    if !self.buf.is_empty() {
+                        frames.push(Frame::Data(std::mem::take(&mut self.buf)));
+                    }
+                    break;
+                }
+            }
+        }
+
+        frames
+    }
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).position(|w| w == needle)
+}
+
+/// The path of a request, and how its body is framed
+fn parse_head(head: &[u8]) -> Option<(String, State)> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    if !request.parse(head).ok()?.is_complete() {
+        return None;
+    }
+
+    let path = request.path?.to_string();
+    let header = |name: &str| {
+        request
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .and_then(|h| std::str::from_utf8(h.value).ok())
+            .map(|v| v.to_ascii_lowercase())
+    };
+
+    let is_upgrade =
+        header("upgrade").is_some() && header("connection").is_some_and(|c| c.contains("upgrade"));
+    let body = if is_upgrade || request.method == Some("CONNECT") {
+        State::Passthrough
+    } else if header("transfer-encoding").is_some_and(|te| te.contains("chunked")) {
+        State::ChunkSize
+    } else {
+        match header("content-length") {
+            Some(len) => match len.trim().parse::<u64>().ok()? {
+                0 => State::Head,
+                len => State::Body(len),
+            },
+            None => State::Head,
+        }
+    };
+
+    Some((path, body))
+}
+
+fn parse_chunk_size(line: &[u8]) -> Option<u64> {
+    let line = std::str::from_utf8(line).ok()?;
+    let size = line.trim_end().split(';').next()?.trim();
+    u64::from_str_radix(size, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(frames: &[Frame]) -> Vec<String> {
+        frames
+            .iter()
+            .filter_map(|f| match f {
+                Frame::Request(r) => Some(r.path.clone()),
+                Frame::Data(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_frame_keep_alive_requests() {
+        let mut framer = RequestFramer::default();
+        let frames = framer.push(
+            b"POST /api/users HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+              POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n",
+        );
+        assert_eq!(paths(&frames), vec!["/api/users", "/upload"]);
+
+        // the rest of the chunked body, split across reads
+        let mut frames = framer.push(b"0\r\n");
+        frames.extend(framer.push(b"\r\nGET / HTTP/1.1\r\nHost: foo\r\n\r\n"));
+        assert_eq!(paths(&frames), vec!["/"]);
+
+        let frames = framer.push(
+            b"GET /ws HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\nGET /x HTTP/1.1\r\n\r\n",
+        );
+        assert_eq!(paths(&frames), vec!["/ws"]);
+        assert_eq!(framer.state, State::Passthrough);
+    }
+
+    #[test]
+    fn test_route_rules() {
+        let rules: Vec<RouteRule> = [
+            "/api=8080",
+            "/api/v2=127.0.0.1:8082",
+            "/ws=unix:/run/ws.sock",
+        ]
+        .iter()
+        .map(|r| r.parse().unwrap())
+        .collect();
+
+        assert_eq!(find_rule(&rules, "/api").unwrap().port, 8080);
+        assert_eq!(find_rule(&rules, "/api/v2/users?id=1").unwrap().port, 8082);
+        assert_eq!(find_rule(&rules, "/api?id=1").unwrap().port, 8080);
+        assert!(find_rule(&rules, "/apis").is_none());
+        assert!(find_rule(&rules, "/").is_none());
+        assert_eq!(
+            find_rule(&rules, "/ws").unwrap().addr(),
+            LocalAddr::Unix("/run/ws.sock".into())
+        );
+
+        let mut request = RequestHead {
+            path: "/api/users?id=1".into(),
+            head: b"GET /api/users?id=1 HTTP/1.1\r\n\r\n".to_vec(),
+        };
+        request.strip_prefix("/api");
+        assert_eq!(request.head, b"GET /users?id=1 HTTP/1.1\r\n\r\n");
+
+        let mut request = RequestHead {
+            path: "/api?id=1".into(),
+            head: b"GET /api?id=1 HTTP/1.1\r\n\r\n".to_vec(),
+        };
+        request.strip_prefix("/api/");
+        assert_eq!(request.head, b"GET /?id=1 HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn test_parse_route_rules() {
+        let rule: RouteRule = "/ws=unix:/run/ws.sock".parse().unwrap();
+        assert_eq!(rule.host, "unix:/run/ws.sock");
+        assert_eq!(rule.addr(), LocalAddr::Unix("/run/ws.sock".into()));
+        assert!("/api".parse::<RouteRule>().is_err());
+        assert!("api=8080".parse::<RouteRule>().is_err());
+        assert!("/api=localhost:http".parse::<RouteRule>().is_err());
+
+        #[derive(Deserialize)]
+        struct Routes {
+            routes: Vec<RouteRule>,
+        }
+        let parse = |toml: &str| toml::from_str::<Routes>(toml).map(|r| r.routes);
+
+        let routes = parse(
+            r#"
+            [[routes]]
+            prefix = "/api"
+            port = 8080
+            strip_prefix = true
+
+            [[routes]]
+            prefix = "/ws"
+            host = "unix:/run/ws.sock"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(routes[0].addr(), LocalAddr::new(DEFAULT_HOST, 8080));
+        assert!(routes[0].strip_prefix);
+        assert_eq!(routes[1].addr(), LocalAddr::Unix("/run/ws.sock".into()));
+        assert!(!routes[1].strip_prefix);
+
+        // a tcp service needs a port
+        assert!(parse("[[routes]]\nprefix = \"/api\"\nhost = \"127.0.0.1\"").is_err());
+    }
+}
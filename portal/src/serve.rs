@@ -0,0 +1,204 @@
+use std::path::{Component, Path, PathBuf};
+
+use super::*;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tokio::io::DuplexStream;
+use warp::hyper::server::conn::Http;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+/// What to escape in the links of a listing
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'.')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'~');
+
+/// How much a connection to the file server buffers each way
+const CONNECTION_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Open a connection to the built-in file server for `path`:
+/// a directory, or a single file served at every path
+pub fn connect(path: &Path, spa: bool) -> DuplexStream {
+    let (client, server) = tokio::io::duplex(CONNECTION_BUFFER_SIZE);
+    let path = path.to_path_buf();
+
+    tokio::spawn(async move {
+        let result = if path.is_file() {
+            let file = get_or_head().and(warp::fs::file(path));
+            Http::new()
+                .serve_connection(server, warp::service(file))
+                .await
+        } else {
+            let files = get_or_head()
+                .and(warp::fs::dir(path.clone()))
+                .map(Reply::into_response)
+                .or(list_dir(path.clone()))
+                .unify()
+                .or(spa_fallback(path, spa))
+                .unify();
+            Http::new()
+                .serve_connection(server, warp::service(files))
+                .await
+        };
+
+        if let Err(e) = result {
+            debug!("file server connection closed: {:?}", e);
+        }
+    });
+
+    client
+}
+
+/// Only read requests, hyper leaves the body out of HEAD responses
+fn get_or_head() -> impl Filter<Extract = (), Error = Rejection> + Copy {
+    warp::get().or(warp::head()).unify()
+}
+
+/// List the entries of directories without an index.html
+fn list_dir(root: PathBuf) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    get_or_head()
+        .and(warp::path::full())
+        .and_then(move |path: warp::path::FullPath| {
+            let root = root.clone();
+            async move {
+                let dir = resolve(&root, path.as_str()).ok_or_else(warp::reject::not_found)?;
+                let entries = read_dir(&dir)
+                    .await
+                    .map_err(|_| warp::reject::not_found())?;
+                Ok::<_, Rejection>(listing(path.as_str(), entries))
+            }
+        })
+}
+
+/// Serve index.html for paths that aren't files, for single page apps
+fn spa_fallback(
+    root: PathBuf,
+    spa: bool,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            if spa {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+        .and(get_or_head())
+        .and(warp::fs::file(root.join("index.html")))
+        .map(Reply::into_response)
+}
+
+/// The directory under `root` a request path points to, if it stays in there
+fn resolve(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(request_path).decode_utf8().ok()?;
+    let mut path = root.to_path_buf();
+    for component in Path::new(decoded.as_ref()).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    path.is_dir().then_some(path)
+}
+
+/// The names of the entries in `dir`, directories ending with `/`
+async fn read_dir(dir: &Path) -> std::io::Result<Vec<String>> {
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    let mut entries = vec![];
+    while let Some(entry) = read_dir.next_entry().await? {
+        let mut name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type().await?.is_dir() {
+            name.push('/');
+        }
+        entries.push(name);
+    }
+
+    entries.sort();
+    Ok(entries)
+}
+
+fn listing(request_path: &str, entries: Vec<String>) -> Response {
+    let base = html_escape(request_path.trim_end_matches('/'));
+    let title = html_escape(&percent_decode_str(request_path).decode_utf8_lossy());
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\n<body><h1>Index of {title}</h1><ul>\n",
+        title = title
+    );
+    if !base.is_empty() {
+        html.push_str(&format!(
+            "<li><a href=\"{}/\">../</a></li>\n",
+            parent(&base)
+        ));
+    }
+    for name in entries {
+        let href = utf8_percent_encode(name.trim_end_matches('/'), PATH_SEGMENT).to_string();
+        let slash = if name.ends_with('/') { "/" } else { "" };
+        html.push_str(&format!(
+            "<li><a href=\"{}/{}{}\">{}</a></li>\n",
+            base,
+            href,
+            slash,
+            html_escape(&name)
+        ));
+    }
+    html.push_str("</ul></body></html>\n");
+
+    warp::reply::html(html).into_response()
+}
+
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/')
+        .map(|(parent, _)| parent)
+        .unwrap_or("")
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let root = std::env::temp_dir().join(format!("portal-serve-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("docs/img")).unwrap();
+
+        assert_eq!(resolve(&root, "/"), Some(root.clone()));
+        assert_eq!(resolve(&root, "/docs/./img/"), Some(root.join("docs/img")));
+        assert_eq!(resolve(&root, "/docs%2Fimg"), Some(root.join("docs/img")));
+        assert_eq!(resolve(&root, "/missing/"), None);
+
+        // nothing outside of root
+        assert_eq!(resolve(&root, "/.."), None);
+        assert_eq!(resolve(&root, "/docs/../../"), None);
+        assert_eq!(resolve(&root, "/%2e%2e/"), None);
+        assert_eq!(resolve(&root, "/docs/%2E%2E/%2e%2e"), None);
+        assert_eq!(resolve(&root, "/docs%2F..%2F.."), None);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_listing_escapes_names() {
+        let response = listing("/a\"b/", vec!["<script>.html".into(), "say \"hi\"/".into()]);
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let html = String::from_utf8(body.to_vec()).unwrap();
+
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("<a href=\"/a&quot;b/%3Cscript%3E.html\">&lt;script&gt;.html</a>"));
+        assert!(html.contains("<a href=\"/a&quot;b/say%20%22hi%22/\">say &quot;hi&quot;/</a>"));
+        assert!(html.contains("<a href=\"/\">../</a>"));
+    }
+}
@@ -2,12 +2,15 @@ use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
 
+use crate::{get_config, proxy};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Update {
     pub html_url: String,
     pub name: String,
 }
 
+const UPDATE_HOST: &str = "api.github.com";
 const UPDATE_URL: &str = "https://api.github.com/repos/illusion-tech/portal/releases/latest";
 const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -28,7 +31,13 @@ pub async fn check() {
 
 /// checks for a new release on github
 async fn check_inner() -> Result<Option<Update>, Box<dyn std::error::Error>> {
-    let update: Update = reqwest::Client::new()
+    let client = match proxy::proxy_for(get_config(), true, UPDATE_HOST) {
+        Some(proxy) => reqwest::Client::builder().proxy(reqwest::Proxy::all(proxy.url())?),
+        None => reqwest::Client::builder().no_proxy(),
+    };
+
+    let update: Update = client
+        .build()?
         .get(UPDATE_URL)
         .header("User-Agent", "portal-client")
         .header("Accept", "application/vnd.github.v3+json")
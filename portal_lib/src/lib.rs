@@ -1,8 +1,14 @@
 use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Utc};
 use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
+use std::collections::VecDeque;
 use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(transparent)]
@@ -34,6 +40,9 @@ pub enum ServerHello {
         sub_domain: String,
         hostname: String,
         client_id: ClientId,
+        /// how long (in seconds) the server holds streams for us to resume
+        #[serde(default)]
+        stream_resume_grace: Option<u64>,
     },
     SubDomainInUse,
     InvalidSubDomain,
@@ -54,7 +63,7 @@ impl ServerHello {
 
     #[allow(unused)]
     pub fn prefixed_random_domain(prefix: &str) -> String {
-        format!("{}", prefix)
+        format!("{}-{}", prefix, Self::random_domain())
     }
 }
 
@@ -65,6 +74,15 @@ pub struct ClientHello {
     pub sub_domain: Option<String>,
     pub client_type: ClientType,
     pub reconnect_token: Option<ReconnectToken>,
+    /// share the sub-domain with the other pool clients of this account
+    #[serde(default)]
+    pub pool: bool,
+    /// HTML served to visitors while the tunnel is down or the local service refuses them
+    #[serde(default)]
+    pub maintenance_page: Option<String>,
+    /// understands `ControlPacket::Reset`, older clients are sent `End` instead
+    #[serde(default)]
+    pub supports_reset: bool,
 }
 
 impl ClientHello {
@@ -74,6 +92,9 @@ impl ClientHello {
             client_type: typ,
             sub_domain,
             reconnect_token: None,
+            pool: false,
+            maintenance_page: None,
+            supports_reset: true,
         }
     }
 
@@ -83,16 +104,36 @@ impl ClientHello {
             sub_domain: None,
             client_type: ClientType::Anonymous,
             reconnect_token: Some(reconnect_token),
+            pool: false,
+            maintenance_page: None,
+            supports_reset: true,
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ClientType {
-    Auth { key: SecretKey },
+    Auth {
+        key: SecretKey,
+    },
+    /// a bearer JWT from an identity provider the server trusts
+    Token {
+        token: String,
+    },
     Anonymous,
 }
 
+/// A sub-domain reserved for an account, nobody else can open a tunnel on it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DomainReservation {
+    pub sub_domain: String,
+    pub hostname: String,
+    pub created: DateTime<Utc>,
+    /// reserving it again renews it
+    #[serde(default)]
+    pub expires: Option<DateTime<Utc>>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 #[serde(transparent)]
 pub struct ClientId(String);
@@ -109,6 +150,12 @@ impl ClientId {
         ClientId(general_purpose::URL_SAFE_NO_PAD.encode(id))
     }
 
+    /// The id of an account authenticated without a key, i.e. by client certificate
+    pub fn for_account(account: &str) -> ClientId {
+        let account = format!("account:{}", account);
+        ClientId(general_purpose::STANDARD_NO_PAD.encode(sha2::Sha256::digest(account.as_bytes())))
+    }
+
     pub fn safe_id(self) -> ClientId {
         ClientId(general_purpose::STANDARD.encode(sha2::Sha256::digest(self.0.as_bytes())))
     }
@@ -139,13 +186,157 @@ impl fmt::Display for StreamId {
 pub enum ControlPacket {
     Init(StreamId),
     Data(StreamId, Vec<u8>),
-    Refused(StreamId),
+    /// Abort the stream in both directions
+    Reset(StreamId, CloseReason),
+    /// The sender is done sending on the stream, it still receives
     End(StreamId),
     Ping(Option<ReconnectToken>),
+    /// The server is going away: reconnect elsewhere, optionally with this token
+    Reconnect(Option<ReconnectToken>),
+    /// Resume a stream after reconnecting: we received this many bytes of it
+    Resume(StreamId, u64),
+}
+
+/// Why a stream was reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// anything else, i.e. the connection was reset
+    Error,
+    /// the local service refused the connection
+    ConnectRefused,
+    /// the TLS handshake with the local service failed
+    TlsFailure,
+    /// the connection timed out
+    Timeout,
+}
+
+impl CloseReason {
+    fn code(self) -> u8 {
+        match self {
+            CloseReason::Error => 0x00,
+            CloseReason::ConnectRefused => 0x01,
+            CloseReason::TlsFailure => 0x02,
+            CloseReason::Timeout => 0x03,
+        }
+    }
+
+    /// Older clients only reset streams they couldn't connect, without a reason
+    fn from_code(code: Option<u8>) -> Self {
+        match code {
+            None | Some(0x01) => CloseReason::ConnectRefused,
+            Some(0x02) => CloseReason::TlsFailure,
+            Some(0x03) => CloseReason::Timeout,
+            Some(_) => CloseReason::Error,
+        }
+    }
+}
+
+impl From<&std::io::Error> for CloseReason {
+    fn from(error: &std::io::Error) -> Self {
+        match error.kind() {
+            std::io::ErrorKind::ConnectionRefused => CloseReason::ConnectRefused,
+            std::io::ErrorKind::TimedOut => CloseReason::Timeout,
+            // how rustls reports handshake and certificate errors
+            std::io::ErrorKind::InvalidData => CloseReason::TlsFailure,
+            _ => CloseReason::Error,
+        }
+    }
 }
 
 pub const PING_INTERVAL: u64 = 30;
 
+/// How long (in seconds) streams are held while the control connection is down,
+/// unless the server says otherwise
+pub const STREAM_RESUME_GRACE: u64 = 30;
+
+/// The largest maintenance page a client may upload, in bytes
+pub const MAX_MAINTENANCE_PAGE_SIZE: usize = 256 * 1024;
+
+/// How many sent bytes we keep per stream to replay on resume
+pub const REPLAY_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// How many sent bytes we keep across all streams, streams sending past it
+/// drop their oldest bytes and may no longer resume
+pub const REPLAY_BUDGET: usize = 64 * 1024 * 1024;
+
+/// bytes held by every replay buffer of this process
+static REPLAY_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// The most recent bytes sent on a stream, so they can be replayed if the
+/// control connection drops. Offsets count every byte ever sent.
+#[derive(Debug, Default)]
+pub struct ReplayBuffer {
+    start: u64,
+    data: VecDeque<u8>,
+}
+
+impl ReplayBuffer {
+    pub fn push(&mut self, data: &[u8]) {
+        self.data.extend(data);
+        let used = REPLAY_BYTES.fetch_add(data.len(), Ordering::Relaxed) + data.len();
+
+        let over_budget = used.saturating_sub(REPLAY_BUDGET);
+        let over_size = self.data.len().saturating_sub(REPLAY_BUFFER_SIZE);
+        let excess = over_budget.max(over_size).min(self.data.len());
+        if excess > 0 {
+            self.data.drain(..excess);
+            self.start += excess as u64;
+            REPLAY_BYTES.fetch_sub(excess, Ordering::Relaxed);
+            // free the memory of streams past the budget
+            if self.data.is_empty() {
+                self.data = VecDeque::new();
+            }
+        }
+    }
+
+    /// total bytes sent
+    pub fn sent(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+
+    /// the bytes sent after `offset`, None if they are no longer buffered
+    pub fn replay_from(&self, offset: u64) -> Option<Vec<u8>> {
+        if offset < self.start || offset > self.sent() {
+            return None;
+        }
+
+        let skip = (offset - self.start) as usize;
+        Some(self.data.iter().skip(skip).copied().collect())
+    }
+}
+
+impl Drop for ReplayBuffer {
+    fn drop(&mut self) {
+        REPLAY_BYTES.fetch_sub(self.data.len(), Ordering::Relaxed);
+    }
+}
+
+/// Whether a stream is attached to a control connection,
+/// so its readers can wait for it to be resumed while it's down
+#[derive(Debug, Clone)]
+pub struct Attachment(Arc<watch::Sender<bool>>);
+
+impl Default for Attachment {
+    fn default() -> Self {
+        Attachment(Arc::new(watch::channel(true).0))
+    }
+}
+
+impl Attachment {
+    pub fn set(&self, attached: bool) {
+        self.0.send_replace(attached);
+    }
+
+    /// Wait for the stream to be attached again.
+    /// Returns false if it wasn't within `grace`.
+    pub async fn wait(&self, grace: Duration) -> bool {
+        let mut attached = self.0.subscribe();
+        tokio::time::timeout(grace, attached.wait_for(|attached| *attached))
+            .await
+            .is_ok_and(|attached| attached.is_ok())
+    }
+}
+
 const EMPTY_STREAM: StreamId = StreamId([0xF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
 const TOKEN_STREAM: StreamId = StreamId([0xF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01]);
 
@@ -154,13 +345,14 @@ impl ControlPacket {
         match self {
             ControlPacket::Init(sid) => [vec![0x01], sid.0.to_vec()].concat(),
             ControlPacket::Data(sid, data) => [vec![0x02], sid.0.to_vec(), data].concat(),
-            ControlPacket::Refused(sid) => [vec![0x03], sid.0.to_vec()].concat(),
+            ControlPacket::Reset(sid, reason) => {
+                [vec![0x03], sid.0.to_vec(), vec![reason.code()]].concat()
+            }
             ControlPacket::End(sid) => [vec![0x04], sid.0.to_vec()].concat(),
-            ControlPacket::Ping(tok) => {
-                let data = tok.map_or(EMPTY_STREAM.0.to_vec(), |t| {
-                    [TOKEN_STREAM.0.to_vec(), t.0.into_bytes()].concat()
-                });
-                [vec![0x05], data].concat()
+            ControlPacket::Ping(tok) => [vec![0x05], serialize_token(tok)].concat(),
+            ControlPacket::Reconnect(tok) => [vec![0x06], serialize_token(tok)].concat(),
+            ControlPacket::Resume(sid, offset) => {
+                [vec![0x07], sid.0.to_vec(), offset.to_be_bytes().to_vec()].concat()
             }
         }
     }
@@ -170,8 +362,10 @@ impl ControlPacket {
             ControlPacket::Ping(_) => "PING",
             ControlPacket::Init(_) => "INIT STREAM",
             ControlPacket::Data(_, _) => "STREAM DATA",
-            ControlPacket::Refused(_) => "REFUSED",
+            ControlPacket::Reset(_, _) => "RESET STREAM",
             ControlPacket::End(_) => "END STREAM",
+            ControlPacket::Reconnect(_) => "RECONNECT",
+            ControlPacket::Resume(_, _) => "RESUME STREAM",
         }
     }
 
@@ -187,16 +381,15 @@ impl ControlPacket {
         let packet = match data[0] {
             0x01 => ControlPacket::Init(stream_id),
             0x02 => ControlPacket::Data(stream_id, data[9..].to_vec()),
-            0x03 => ControlPacket::Refused(stream_id),
+            0x03 => ControlPacket::Reset(stream_id, CloseReason::from_code(data.get(9).copied())),
             0x04 => ControlPacket::End(stream_id),
-            0x05 => {
-                if stream_id == EMPTY_STREAM {
-                    ControlPacket::Ping(None)
-                } else {
-                    ControlPacket::Ping(Some(ReconnectToken(
-                        String::from_utf8_lossy(&data[9..]).to_string(),
-                    )))
-                }
+            0x05 => ControlPacket::Ping(deserialize_token(&stream_id, &data[9..])),
+            0x06 => ControlPacket::Reconnect(deserialize_token(&stream_id, &data[9..])),
+            0x07 => {
+                let offset: [u8; 8] = data[9..]
+                    .try_into()
+                    .map_err(|_| "invalid resume packet, missing offset")?;
+                ControlPacket::Resume(stream_id, u64::from_be_bytes(offset))
             }
             _ => return Err("invalid control byte in DataPacket".into()),
         };
@@ -204,3 +397,100 @@ impl ControlPacket {
         Ok(packet)
     }
 }
+
+fn serialize_token(tok: Option<ReconnectToken>) -> Vec<u8> {
+    tok.map_or(EMPTY_STREAM.0.to_vec(), |t| {
+        [TOKEN_STREAM.0.to_vec(), t.0.into_bytes()].concat()
+    })
+}
+
+fn deserialize_token(stream_id: &StreamId, data: &[u8]) -> Option<ReconnectToken> {
+    if stream_id == &EMPTY_STREAM {
+        None
+    } else {
+        Some(ReconnectToken(String::from_utf8_lossy(data).to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_attachment() {
+        let attachment = Attachment::default();
+        assert!(attachment.wait(Duration::ZERO).await);
+
+        attachment.set(false);
+        assert!(!attachment.wait(Duration::from_millis(10)).await);
+
+        // wakes up as soon as it's resumed
+        let resumed = attachment.clone();
+        tokio::spawn(async move { resumed.set(true) });
+        assert!(attachment.wait(Duration::from_secs(5)).await);
+    }
+
+    #[test]
+    fn test_replay_buffer() {
+        let mut buffer = ReplayBuffer::default();
+        buffer.push(b"hello ");
+        buffer.push(b"world");
+
+        assert_eq!(buffer.sent(), 11);
+        assert_eq!(buffer.replay_from(6), Some(b"world".to_vec()));
+        assert_eq!(buffer.replay_from(11), Some(vec![]));
+        assert_eq!(buffer.replay_from(12), None);
+
+        buffer.push(&vec![0; REPLAY_BUFFER_SIZE]);
+        assert_eq!(buffer.replay_from(0), None);
+        assert_eq!(buffer.replay_from(buffer.sent()), Some(vec![]));
+        drop(buffer);
+
+        // the buffers of all streams share a budget
+        let mut full: Vec<_> = (0..REPLAY_BUDGET / REPLAY_BUFFER_SIZE)
+            .map(|_| {
+                let mut buffer = ReplayBuffer::default();
+                buffer.push(&vec![0; REPLAY_BUFFER_SIZE]);
+                buffer
+            })
+            .collect();
+        let mut over = ReplayBuffer::default();
+        over.push(b"hello");
+        assert_eq!(over.replay_from(0), None);
+        assert_eq!(over.sent(), 5);
+
+        full.pop();
+        over.push(b"world");
+        assert_eq!(over.replay_from(5), Some(b"world".to_vec()));
+    }
+
+    #[test]
+    fn test_resume_packet() {
+        let sid = StreamId::generate();
+        let data = ControlPacket::Resume(sid.clone(), 42).serialize();
+        match ControlPacket::deserialize(&data).unwrap() {
+            ControlPacket::Resume(id, offset) => {
+                assert_eq!(id, sid);
+                assert_eq!(offset, 42);
+            }
+            packet => panic!("unexpected packet: {}", packet.packet_type()),
+        }
+    }
+
+    #[test]
+    fn test_reset_packet() {
+        let sid = StreamId::generate();
+        let data = ControlPacket::Reset(sid.clone(), CloseReason::TlsFailure).serialize();
+        assert!(matches!(
+            ControlPacket::deserialize(&data).unwrap(),
+            ControlPacket::Reset(id, CloseReason::TlsFailure) if id == sid
+        ));
+
+        // as sent by clients from before reset reasons
+        let data = [vec![0x03], sid.0.to_vec()].concat();
+        assert!(matches!(
+            ControlPacket::deserialize(&data).unwrap(),
+            ControlPacket::Reset(_, CloseReason::ConnectRefused)
+        ));
+    }
+}
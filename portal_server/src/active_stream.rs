@@ -3,28 +3,253 @@ pub struct ActiveStream {
     pub id: StreamId,
     pub client: ConnectedClient,
     pub tx: UnboundedSender<StreamMessage>,
+    state: Arc<Mutex<StreamState>>,
+    /// set once the stream is aborted, so we stop reading from the visitor
+    aborted: Arc<watch::Sender<bool>>,
+    /// counts towards the open streams of its client until every clone is dropped
+    _open: Arc<OpenStream>,
+}
+
+#[derive(Debug)]
+struct OpenStream(Arc<AtomicUsize>);
+
+impl OpenStream {
+    fn new(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        OpenStream(count.clone())
+    }
+}
+
+impl Drop for OpenStream {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// What we need to resume a stream when its client reconnects
+#[derive(Debug)]
+struct StreamState {
+    /// bytes sent to the client
+    sent: ReplayBuffer,
+    /// how many bytes we received from the client
+    received: u64,
+    /// the control connection we are sending on, if any
+    attached: Option<UnboundedSender<ControlPacket>>,
+    /// follows `attached`, for readers waiting on a resume
+    attachment: Attachment,
 }
 
 impl ActiveStream {
     pub fn new(client: ConnectedClient) -> (Self, UnboundedReceiver<StreamMessage>) {
         let (tx, rx) = unbounded();
+        let state = StreamState {
+            sent: ReplayBuffer::default(),
+            received: 0,
+            attached: Some(client.tx.clone()),
+            attachment: Attachment::default(),
+        };
+        let open = Arc::new(OpenStream::new(&client.open_streams));
+
         (
             ActiveStream {
                 id: StreamId::generate(),
                 client,
                 tx,
+                state: Arc::new(Mutex::new(state)),
+                aborted: Arc::new(watch::channel(false).0),
+                _open: open,
             },
             rx,
         )
     }
+
+    pub fn is_attached(&self) -> bool {
+        self.state.lock().unwrap().attached.is_some()
+    }
+
+    /// Wait for the client to resume this stream.
+    /// Returns false if it wasn't resumed within the grace period.
+    pub async fn wait_for_resume(&self) -> bool {
+        if !self.is_attached() {
+            tracing::debug!(stream_id = %self.id, "waiting for client to resume stream");
+        }
+        let attachment = self.state.lock().unwrap().attachment.clone();
+        attachment.wait(get_config().stream_resume_grace).await
+    }
+
+    pub fn is_attached_to(&self, conn: &UnboundedSender<ControlPacket>) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .attached
+            .as_ref()
+            .is_some_and(|attached| attached.same_receiver(conn))
+    }
+
+    /// Abort the stream in both directions
+    pub fn abort(&self) {
+        self.aborted.send_replace(true);
+    }
+
+    /// Resolves once the stream is aborted
+    pub async fn aborted(&self) {
+        let _ = self.aborted.subscribe().wait_for(|aborted| *aborted).await;
+    }
+
+    /// Detach the streams of a disconnected client,
+    /// closing those it doesn't resume within the grace period
+    pub fn hold_for_resume(client: &ConnectedClient) {
+        let streams: Vec<ActiveStream> = get_active_streams()
+            .iter()
+            .filter(|s| s.client.session_id == client.session_id && s.is_attached_to(&client.tx))
+            .map(|s| s.value().clone())
+            .collect();
+
+        if streams.is_empty() {
+            return;
+        }
+
+        for stream in &streams {
+            stream.detach();
+        }
+
+        let grace = get_config().stream_resume_grace;
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            for mut stream in streams.into_iter().filter(|s| !s.is_attached()) {
+                tracing::debug!(stream_id=%stream.id, "stream not resumed, closing");
+                let _ = stream.tx.send(StreamMessage::NoClientTunnel).await;
+                stream.tx.close_channel();
+            }
+        });
+    }
+
+    /// Send a packet to the client. Returns false if it's not connected.
+    pub fn send(&self, packet: ControlPacket) -> bool {
+        // older clients fail the whole connection on a Reset, ending the stream is the closest they know
+        let packet = match packet {
+            ControlPacket::Reset(id, _) if !self.client.supports_reset => ControlPacket::End(id),
+            packet => packet,
+        };
+        let mut state = self.state.lock().unwrap();
+        send_attached(&mut state, packet)
+    }
+
+    /// Send data to the client, keeping a copy to replay on resume.
+    /// Returns false if it's not connected.
+    pub fn send_data(&self, data: Vec<u8>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.sent.push(&data);
+        send_attached(&mut state, ControlPacket::Data(self.id.clone(), data))
+    }
+
+    /// Count data the client sent us on `conn`.
+    /// Returns false if it arrived on a stale connection and must be dropped.
+    pub fn receive(&self, conn: &UnboundedSender<ControlPacket>, len: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.attached {
+            Some(ref attached) if attached.same_receiver(conn) => {
+                state.received += len as u64;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Stop sending on the current control connection.
+    /// Returns how many bytes we received, for the client to resume from.
+    pub fn detach(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.attached = None;
+        state.attachment.set(false);
+        state.received
+    }
+
+    /// Attach to a new control connection, replaying all data after `offset`.
+    /// Returns false if that data is no longer buffered.
+    pub fn resume(&self, conn: UnboundedSender<ControlPacket>, offset: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let replay = match state.sent.replay_from(offset) {
+            Some(replay) => replay,
+            None => return false,
+        };
+
+        tracing::debug!(stream_id=%self.id, num_bytes=%replay.len(), "resuming stream");
+        state.attached = Some(conn);
+        state.attachment.set(true);
+        replay.is_empty() || send_attached(&mut state, ControlPacket::Data(self.id.clone(), replay))
+    }
+}
+
+fn send_attached(state: &mut StreamState, packet: ControlPacket) -> bool {
+    let sent = match state.attached {
+        Some(ref conn) => conn.unbounded_send(packet).is_ok(),
+        None => false,
+    };
+
+    if !sent {
+        state.attached = None;
+        state.attachment.set(false);
+    }
+    sent
 }
 
 pub type ActiveStreams = Arc<DashMap<StreamId, ActiveStream>>;
 
 use super::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tokio::sync::watch;
 #[derive(Debug, Clone)]
 pub enum StreamMessage {
     Data(Vec<u8>),
-    TunnelRefused,
+    /// the client aborted the stream
+    Reset(CloseReason),
     NoClientTunnel,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AccountLimits;
+
+    fn connected_client(
+        supports_reset: bool,
+    ) -> (ConnectedClient, UnboundedReceiver<ControlPacket>) {
+        let (tx, rx) = unbounded();
+        let client = ConnectedClient {
+            id: ClientId::generate(),
+            host: "foo".into(),
+            is_anonymous: false,
+            limits: AccountLimits::default(),
+            session_expires: None,
+            pool: false,
+            supports_reset,
+            session_id: uuid::Uuid::new_v4(),
+            authenticated: chrono::Utc::now(),
+            open_streams: Arc::default(),
+            tx,
+        };
+        (client, rx)
+    }
+
+    #[tokio::test]
+    async fn test_reset_older_clients() {
+        let (client, mut rx) = connected_client(true);
+        let (stream, _) = ActiveStream::new(client);
+        assert!(stream.send(ControlPacket::Reset(stream.id.clone(), CloseReason::Error)));
+        assert!(matches!(
+            rx.next().await,
+            Some(ControlPacket::Reset(id, CloseReason::Error)) if id == stream.id
+        ));
+
+        // clients that don't know Reset have the stream ended instead
+        let (client, mut rx) = connected_client(false);
+        let (stream, _) = ActiveStream::new(client);
+        assert!(stream.send(ControlPacket::Reset(stream.id.clone(), CloseReason::Error)));
+        assert!(matches!(
+            rx.next().await,
+            Some(ControlPacket::End(id)) if id == stream.id
+        ));
+    }
+}
@@ -1,20 +1,42 @@
 use crate::auth::reconnect_token::ReconnectTokenPayload;
-use crate::auth::{AuthResult, AuthService};
-use crate::{get_config, ReconnectToken};
+use crate::auth::{AccountLimits, AuthResult, AuthService};
+use crate::config::{AnonymousPolicy, CertIdentity};
+use crate::{get_config, sub_domain};
+use chrono::{DateTime, Utc};
 use futures::{SinkExt, StreamExt};
-use portal_lib::{ClientHello, ClientId, ClientType, ServerHello};
+use portal_lib::{
+    ClientHello, ClientId, ClientType, ReconnectToken, ServerHello, MAX_MAINTENANCE_PAGE_SIZE,
+};
 use tracing::{debug, error};
+use uuid::Uuid;
 use warp::filters::ws::{Message, WebSocket};
 
 pub struct ClientHandshake {
     pub id: ClientId,
     pub sub_domain: String,
     pub is_anonymous: bool,
+    pub limits: AccountLimits,
+    /// when this tunnel must be closed, if ever
+    pub session_expires: Option<DateTime<Utc>>,
+    /// share the sub-domain with the other pool clients of this account
+    pub pool: bool,
+    /// served to visitors while the tunnel is down
+    pub maintenance_page: Option<String>,
+    /// understands `ControlPacket::Reset`
+    pub supports_reset: bool,
+    /// the control connection this one replaces, if reconnecting
+    pub session_id: Option<Uuid>,
+    /// when the client last authenticated, a reconnect keeps it
+    pub authenticated: DateTime<Utc>,
 }
 
+/// how long an account reconnects on tokens before it authenticates again
+const MAX_RECONNECT_AGE_SECS: i64 = 60 * 60;
+
 #[tracing::instrument(skip(websocket))]
 pub async fn auth_client_handshake(
     mut websocket: WebSocket,
+    identity: Option<CertIdentity>,
 ) -> Option<(WebSocket, ClientHandshake)> {
     let client_hello_data = match websocket.next().await {
         Some(Ok(msg)) => msg,
@@ -24,16 +46,17 @@ pub async fn auth_client_handshake(
         }
     };
     debug!("got client init message: {:?}", client_hello_data);
-    auth_client(client_hello_data.as_bytes(), websocket).await
+    auth_client(client_hello_data.as_bytes(), identity, websocket).await
 }
 
 #[tracing::instrument(skip(client_hello_data, websocket))]
 async fn auth_client(
     client_hello_data: &[u8],
+    identity: Option<CertIdentity>,
     mut websocket: WebSocket,
 ) -> Option<(WebSocket, ClientHandshake)> {
     // parse the client hello
-    let client_hello: ClientHello = match serde_json::from_slice(client_hello_data) {
+    let mut client_hello: ClientHello = match serde_json::from_slice(client_hello_data) {
         Ok(ch) => ch,
         Err(error) => {
             error!(?error, "invalid client hello");
@@ -45,24 +68,93 @@ async fn auth_client(
 
     debug!("got client hello: {:?}", client_hello);
 
-    let (auth_key, client_id, requested_sub_domain) = match client_hello.client_type {
+    // kept whichever way the client authenticates
+    let maintenance_page = client_hello.maintenance_page.take().filter(|page| {
+        let fits = page.len() <= MAX_MAINTENANCE_PAGE_SIZE;
+        if !fits {
+            tracing::warn!(size = page.len(), "maintenance page too large, ignoring it");
+        }
+        fits
+    });
+    let supports_reset = client_hello.supports_reset;
+    let (websocket, mut handshake) = auth_client_hello(client_hello, identity, websocket).await?;
+    handshake.supports_reset = supports_reset;
+    // anonymous hosts are short-lived and random, not worth keeping a page for
+    handshake.maintenance_page = maintenance_page.filter(|_| {
+        if handshake.is_anonymous {
+            tracing::info!("ignoring the maintenance page of an anonymous client");
+        }
+        !handshake.is_anonymous
+    });
+    Some((websocket, handshake))
+}
+
+/// Authenticate the client as its hello says
+async fn auth_client_hello(
+    client_hello: ClientHello,
+    identity: Option<CertIdentity>,
+    mut websocket: WebSocket,
+) -> Option<(WebSocket, ClientHandshake)> {
+    // only accounts can pool their tunnels
+    let pool = client_hello.pool;
+
+    // a client certificate stands in for the key of clients without one
+    if let (Some(identity), ClientType::Anonymous) = (identity, &client_hello.client_type) {
+        return auth_client_cert(client_hello, identity, websocket).await;
+    }
+
+    let (auth_key, client_id, requested_sub_domain) = match client_hello.client_type.clone() {
+        ClientType::Token { token } => {
+            return auth_client_token(client_hello, token, websocket).await;
+        }
         ClientType::Anonymous => {
-            // let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
-            // let _ = websocket.send(Message::binary(data)).await;
-            // return None;
+            let config = get_config();
+            if config.anonymous_policy == AnonymousPolicy::Disallow {
+                tracing::info!("anonymous clients are not allowed");
+                let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
+                let _ = websocket.send(Message::binary(data)).await;
+                return None;
+            }
 
             // determine the client and subdomain
-            let (client_id, sub_domain) =
-                match (client_hello.reconnect_token, client_hello.sub_domain) {
-                    (Some(token), _) => {
-                        return handle_reconnect_token(token, websocket).await;
-                    }
-                    (None, Some(sd)) => (
-                        ClientId::generate(),
+            let client_id = ClientId::generate();
+            let sub_domain = match (
+                client_hello.reconnect_token,
+                client_hello.sub_domain,
+                config.anonymous_policy,
+            ) {
+                (Some(token), _, _) => {
+                    let payload = match ReconnectTokenPayload::verify(token, &config.sig_keys) {
+                        Ok(payload) if payload.is_anonymous => payload,
+                        Ok(_) => {
+                            error!("account reconnect token used without its key");
+                            let data =
+                                serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
+                            let _ = websocket.send(Message::binary(data)).await;
+                            return None;
+                        }
+                        Err(error) => {
+                            error!(?error, "invalid reconnect token");
+                            let data =
+                                serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
+                            let _ = websocket.send(Message::binary(data)).await;
+                            return None;
+                        }
+                    };
+                    return handle_reconnect_token(payload, false, websocket).await;
+                }
+                (None, Some(sd), AnonymousPolicy::Prefixed) => {
+                    let (ws, sub_domain) = sanitize_sub_domain_and_pre_validate(
+                        websocket,
                         ServerHello::prefixed_random_domain(&sd),
-                    ),
-                    (None, None) => (ClientId::generate(), ServerHello::random_domain()),
-                };
+                        &client_id,
+                    )
+                    .await?;
+                    websocket = ws;
+                    sub_domain
+                }
+                _ => ServerHello::random_domain(),
+            };
 
             debug!(
                 ?client_id,
@@ -75,36 +167,53 @@ async fn auth_client(
                     id: client_id,
                     sub_domain,
                     is_anonymous: true,
+                    limits: AccountLimits::default(),
+                    session_expires: config
+                        .max_anonymous_session
+                        .and_then(|lifetime| chrono::Duration::from_std(lifetime).ok())
+                        .map(|lifetime| Utc::now() + lifetime),
+                    pool: false,
+                    maintenance_page: None,
+                    supports_reset: false,
+                    session_id: None,
+                    authenticated: Utc::now(),
                 },
             ));
         }
-        ClientType::Auth { key } => match client_hello.sub_domain {
-            Some(requested_sub_domain) => {
-                let client_id = key.client_id();
-                let (ws, sub_domain) = match sanitize_sub_domain_and_pre_validate(
-                    websocket,
-                    requested_sub_domain,
+        ClientType::Auth { key } => {
+            let client_id = key.client_id();
+
+            // an account reconnecting gets its sub-domain back without re-auth
+            if let Some(payload) = client_hello.reconnect_token.and_then(|token| {
+                verify_account_reconnect(
+                    token,
                     &client_id,
+                    client_hello.sub_domain.as_ref(),
+                    |_| true,
                 )
-                .await
-                {
-                    Some(s) => s,
-                    None => return None,
-                };
-                websocket = ws;
-
-                (key, client_id, sub_domain)
+            }) {
+                return handle_reconnect_token(payload, pool, websocket).await;
             }
-            None => {
-                if let Some(token) = client_hello.reconnect_token {
-                    return handle_reconnect_token(token, websocket).await;
-                } else {
-                    let sub_domain = ServerHello::random_domain();
-                    let client_id = key.client_id();
+
+            match client_hello.sub_domain {
+                Some(requested_sub_domain) => {
+                    let (ws, sub_domain) = match sanitize_sub_domain_and_pre_validate(
+                        websocket,
+                        requested_sub_domain,
+                        &client_id,
+                    )
+                    .await
+                    {
+                        Some(s) => s,
+                        None => return None,
+                    };
+                    websocket = ws;
+
                     (key, client_id, sub_domain)
                 }
+                None => (key, client_id, ServerHello::random_domain()),
             }
-        },
+        }
     };
 
     tracing::info!(requested_sub_domain=%requested_sub_domain, "will auth sub domain");
@@ -137,31 +246,235 @@ async fn auth_client(
 
     tracing::info!(subdomain=%sub_domain, "did auth sub_domain");
 
+    let limits = match crate::get_auth_db_service().account_limits(&auth_key.0) {
+        Ok(limits) => limits,
+        Err(error) => {
+            error!(?error, "error getting account limits");
+            let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
+            let _ = websocket.send(Message::binary(data)).await;
+            return None;
+        }
+    };
+
     Some((
         websocket,
         ClientHandshake {
             id: client_id,
             sub_domain,
             is_anonymous: false,
+            limits,
+            session_expires: None,
+            pool,
+            maintenance_page: None,
+            supports_reset: false,
+            session_id: None,
+            authenticated: Utc::now(),
         },
     ))
 }
 
-#[tracing::instrument(skip(token, websocket))]
-async fn handle_reconnect_token(
-    token: ReconnectToken,
+/// Authenticate a client as the account of its certificate
+#[tracing::instrument(skip(client_hello, websocket))]
+async fn auth_client_cert(
+    client_hello: ClientHello,
+    identity: CertIdentity,
+    mut websocket: WebSocket,
+) -> Option<(WebSocket, ClientHandshake)> {
+    let client_id = ClientId::for_account(&identity.account);
+
+    // the account reconnecting gets its sub-domain back
+    if let Some(payload) = client_hello.reconnect_token.and_then(|token| {
+        verify_account_reconnect(
+            token,
+            &client_id,
+            client_hello.sub_domain.as_ref(),
+            |sub_domain| identity.allows(sub_domain),
+        )
+    }) {
+        return handle_reconnect_token(payload, client_hello.pool, websocket).await;
+    }
+
+    let requested_sub_domain = client_hello
+        .sub_domain
+        .or_else(|| identity.sub_domains.first().cloned());
+    let sub_domain = match requested_sub_domain {
+        Some(requested_sub_domain) => {
+            let (ws, sub_domain) =
+                sanitize_sub_domain_and_pre_validate(websocket, requested_sub_domain, &client_id)
+                    .await?;
+            websocket = ws;
+            sub_domain
+        }
+        None => ServerHello::random_domain(),
+    };
+
+    if !identity.allows(&sub_domain) {
+        tracing::info!(account=%identity.account, %sub_domain, "sub-domain not allowed for certificate");
+        let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
+        let _ = websocket.send(Message::binary(data)).await;
+        return None;
+    }
+
+    tracing::info!(account=%identity.account, subdomain=%sub_domain, "did auth client certificate");
+
+    Some((
+        websocket,
+        ClientHandshake {
+            id: client_id,
+            sub_domain,
+            is_anonymous: false,
+            limits: AccountLimits::default(),
+            session_expires: None,
+            pool: client_hello.pool,
+            maintenance_page: None,
+            supports_reset: false,
+            session_id: None,
+            authenticated: Utc::now(),
+        },
+    ))
+}
+
+/// Authenticate a client by the bearer JWT of its account
+#[tracing::instrument(skip(client_hello, token, websocket))]
+async fn auth_client_token(
+    client_hello: ClientHello,
+    token: String,
     mut websocket: WebSocket,
 ) -> Option<(WebSocket, ClientHandshake)> {
-    let payload = match ReconnectTokenPayload::verify(token, &get_config().master_sig_key) {
-        Ok(payload) => payload,
+    let Some(jwt_auth) = crate::get_jwt_auth() else {
+        tracing::info!("token authentication is not enabled");
+        let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
+        let _ = websocket.send(Message::binary(data)).await;
+        return None;
+    };
+
+    let claims = match jwt_auth.validate(&token).await {
+        Ok(claims) => claims,
+        Err(error) => {
+            tracing::info!(%error, "invalid client token");
+            let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
+            let _ = websocket.send(Message::binary(data)).await;
+            return None;
+        }
+    };
+    let client_id = ClientId::for_account(&claims.account);
+
+    // the account reconnecting gets its sub-domain back, if its token still allows it
+    if let Some(payload) = client_hello.reconnect_token.and_then(|token| {
+        verify_account_reconnect(
+            token,
+            &client_id,
+            client_hello.sub_domain.as_ref(),
+            |sub_domain| claims.allows(sub_domain),
+        )
+    }) {
+        // the session lasts as long as the token it was renewed with
+        let payload = ReconnectTokenPayload {
+            session_expires: claims.expires,
+            ..payload
+        };
+        return handle_reconnect_token(payload, client_hello.pool, websocket).await;
+    }
+
+    let requested_sub_domain = client_hello
+        .sub_domain
+        .or_else(|| claims.default_sub_domain());
+    let sub_domain = match requested_sub_domain {
+        Some(requested_sub_domain) => {
+            let (ws, sub_domain) =
+                sanitize_sub_domain_and_pre_validate(websocket, requested_sub_domain, &client_id)
+                    .await?;
+            websocket = ws;
+            sub_domain
+        }
+        None => ServerHello::random_domain(),
+    };
+
+    let limits = match jwt_auth
+        .auth_sub_domain(&claims, &sub_domain)
+        .and_then(|_| jwt_auth.account_limits(&claims))
+    {
+        Ok(limits) => limits,
         Err(error) => {
-            error!(?error, "invalid reconnect token");
+            tracing::info!(account=%claims.account, %sub_domain, %error, "token denied sub-domain");
             let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
             let _ = websocket.send(Message::binary(data)).await;
             return None;
         }
     };
 
+    tracing::info!(account=%claims.account, subdomain=%sub_domain, "did auth client token");
+
+    Some((
+        websocket,
+        ClientHandshake {
+            id: client_id,
+            sub_domain,
+            is_anonymous: false,
+            limits,
+            session_expires: claims.expires,
+            pool: client_hello.pool,
+            maintenance_page: None,
+            supports_reset: false,
+            session_id: None,
+            authenticated: Utc::now(),
+        },
+    ))
+}
+
+/// The payload of an account's reconnect token,
+/// if it's still good for the sub-domain the client asks for
+fn verify_account_reconnect(
+    token: ReconnectToken,
+    client_id: &ClientId,
+    requested_sub_domain: Option<&String>,
+    is_allowed: impl Fn(&str) -> bool,
+) -> Option<ReconnectTokenPayload> {
+    match ReconnectTokenPayload::verify(token, &get_config().sig_keys) {
+        Ok(payload)
+            if !payload.is_anonymous
+                && &payload.client_id == client_id
+                && requested_sub_domain
+                    .is_none_or(|sd| sd.to_lowercase() == payload.sub_domain)
+                && is_allowed(&payload.sub_domain)
+                && payload.authenticated.is_some_and(|authenticated| {
+                    Utc::now() - authenticated < chrono::Duration::seconds(MAX_RECONNECT_AGE_SECS)
+                }) =>
+        {
+            Some(payload)
+        }
+        Ok(_) => {
+            debug!("reconnect token doesn't match account or is too old, re-authenticating");
+            None
+        }
+        Err(error) => {
+            debug!(?error, "invalid reconnect token, re-authenticating");
+            None
+        }
+    }
+}
+
+#[tracing::instrument(skip(payload, websocket))]
+async fn handle_reconnect_token(
+    payload: ReconnectTokenPayload,
+    pool: bool,
+    mut websocket: WebSocket,
+) -> Option<(WebSocket, ClientHandshake)> {
+    if payload
+        .session_expires
+        .is_some_and(|session_expires| Utc::now() > session_expires)
+    {
+        tracing::info!(client_id=%&payload.client_id, anonymous=payload.is_anonymous, "session expired");
+        let message = if payload.is_anonymous {
+            "Anonymous session expired. Please use an access key for longer sessions."
+        } else {
+            "Session expired with the token it was opened with. Please use a new token."
+        };
+        let data = serde_json::to_vec(&ServerHello::Error(message.into())).unwrap_or_default();
+        let _ = websocket.send(Message::binary(data)).await;
+        return None;
+    }
+
     tracing::debug!(
         client_id=%&payload.client_id,
         "accepting reconnect token from client",
@@ -172,7 +485,14 @@ async fn handle_reconnect_token(
         ClientHandshake {
             id: payload.client_id,
             sub_domain: payload.sub_domain,
-            is_anonymous: true,
+            is_anonymous: payload.is_anonymous,
+            limits: payload.limits,
+            session_expires: payload.session_expires,
+            pool: pool && !payload.is_anonymous,
+            maintenance_page: None,
+            supports_reset: false,
+            session_id: payload.session_id,
+            authenticated: payload.authenticated.unwrap_or_else(Utc::now),
         },
     ))
 }
@@ -185,40 +505,79 @@ async fn sanitize_sub_domain_and_pre_validate(
     // ignore uppercase
     let sub_domain = requested_sub_domain.to_lowercase();
 
-    if sub_domain
-        .chars()
-        .filter(|c| !(c.is_alphanumeric() || c == &'-'))
-        .count()
-        > 0
-    {
+    if !sub_domain::is_valid(&sub_domain) {
         error!("invalid client hello: only alphanumeric/hyphen chars allowed!");
         let data = serde_json::to_vec(&ServerHello::InvalidSubDomain).unwrap_or_default();
         let _ = websocket.send(Message::binary(data)).await;
         return None;
     }
 
-    // ensure it's not a restricted one
-    if get_config().blocked_sub_domains.contains(&sub_domain) {
+    // ensure it's not a restricted one, or below one
+    let config = get_config();
+    if sub_domain::parents(&sub_domain)
+        .any(|name| config.blocked_sub_domains.iter().any(|b| b == name))
+    {
         error!("invalid client hello: sub-domain restrict!");
         let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
         let _ = websocket.send(Message::binary(data)).await;
         return None;
     }
 
-    // ensure this sub-domain isn't taken
-    // check all instances
-    match crate::network::instance_for_host(&sub_domain).await {
-        Err(crate::network::Error::DoesNotServeHost) => {}
-        Ok((_, existing_client)) => {
-            if &existing_client != client_id {
-                error!("invalid client hello: requested sub domain in use already!");
+    // reserved sub-domains are only for their owner
+    let reserved = {
+        let (sub_domain, client_id) = (sub_domain.clone(), client_id.clone());
+        super::reservations::blocking(move |reservations| {
+            reservations.check(&sub_domain, &client_id)
+        })
+        .await
+    };
+    match reserved {
+        AuthResult::ReservedByOther => {
+            error!("invalid client hello: sub-domain reserved by someone else!");
+            let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
+            let _ = websocket.send(Message::binary(data)).await;
+            return None;
+        }
+        // a wildcard takes every host below a name, only its owner may have them
+        AuthResult::ReservedByYou => {}
+        _ if sub_domain.starts_with("*.") => {
+            error!("invalid client hello: wildcard for a name that isn't reserved!");
+            let name = sub_domain.trim_start_matches("*.");
+            let data = serde_json::to_vec(&ServerHello::Error(format!(
+                "Reserve {} to open a wildcard tunnel below it: portal domains reserve {}",
+                name, name
+            )))
+            .unwrap_or_default();
+            let _ = websocket.send(Message::binary(data)).await;
+            return None;
+        }
+        _ => {}
+    }
+
+    // ensure this sub-domain, a wildcard above it, or a name it's below
+    // isn't taken on any instance, the handshake claims it for good once authenticated
+    let parents = sub_domain::parents(&sub_domain)
+        .filter(|parent| *parent != sub_domain)
+        .map(str::to_string);
+    let hosts = sub_domain::route_candidates(&sub_domain)
+        .into_iter()
+        .chain(parents)
+        .collect::<Vec<_>>();
+    match crate::get_routes().lookup(&hosts).await {
+        Ok(routes) => {
+            if let Some((host, _)) = hosts
+                .iter()
+                .zip(routes)
+                .find(|(_, route)| route.as_ref().is_some_and(|r| &r.client_id != client_id))
+            {
+                error!(%host, "invalid client hello: requested sub domain in use already!");
                 let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
                 let _ = websocket.send(Message::binary(data)).await;
                 return None;
             }
         }
         Err(e) => {
-            tracing::debug!("Got error checking instances: {:?}", e);
+            tracing::debug!("Got error checking routes: {:?}", e);
         }
     }
 
@@ -0,0 +1,343 @@
+use crate::auth::{AccountLimits, AuthResult, AuthService};
+use crate::config::JwtConfig;
+use chrono::{DateTime, Utc};
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::{Mutex, RwLock};
+
+/// Don't reload the keys more often than this when a token has an unknown key id
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+/// How long fetching the keys from the provider may take
+const JWKS_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum JwtError {
+    #[error("invalid token: {0}")]
+    Invalid(#[from] jsonwebtoken::errors::Error),
+    #[error("no key to verify the token with, kid: {0:?}")]
+    UnknownKey(Option<String>),
+    #[error("failed to load jwks: {0}")]
+    Jwks(String),
+    #[error("token has no {0} claim")]
+    MissingClaim(String),
+    #[error("sub-domain not allowed by token")]
+    SubDomainNotAllowed,
+}
+
+/// What a valid token says about its account
+#[derive(Debug, Clone)]
+pub struct TokenClaims {
+    pub account: String,
+    /// the sub-domains it may use, i.e. `acme-*`, any if empty
+    pub sub_domains: Vec<String>,
+    pub limits: AccountLimits,
+    /// when the token expires, tunnels opened with it close then
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl TokenClaims {
+    pub fn allows(&self, sub_domain: &str) -> bool {
+        self.sub_domains.is_empty()
+            || self
+                .sub_domains
+                .iter()
+                .any(|pattern| matches_pattern(pattern, sub_domain))
+    }
+
+    /// The sub-domain to use when the client doesn't ask for one
+    pub fn default_sub_domain(&self) -> Option<String> {
+        self.sub_domains
+            .iter()
+            .find(|pattern| !pattern.contains('*'))
+            .cloned()
+    }
+}
+
+/// Authenticates clients by bearer JWTs, verified with the keys of a JWKS
+pub struct JwtAuth {
+    config: JwtConfig,
+    keys: RwLock<Keys>,
+    /// held while reloading the keys for an unknown key id, so only one request does
+    refresh: Mutex<()>,
+    http: reqwest::Client,
+}
+
+struct Keys {
+    set: JwkSet,
+    loaded: Option<Instant>,
+}
+
+impl Keys {
+    /// The key with id `kid`, or the only key if the token doesn't name one
+    fn find(&self, kid: Option<&str>) -> Option<Jwk> {
+        match kid {
+            Some(kid) => self.set.find(kid).cloned(),
+            None if self.set.keys.len() == 1 => self.set.keys.first().cloned(),
+            None => None,
+        }
+    }
+}
+
+impl JwtAuth {
+    pub fn new(config: JwtConfig) -> Self {
+        JwtAuth {
+            config,
+            keys: RwLock::new(Keys {
+                set: JwkSet { keys: vec![] },
+                loaded: None,
+            }),
+            refresh: Mutex::new(()),
+            http: reqwest::Client::builder()
+                .timeout(JWKS_FETCH_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// (Re)load the keys from the JWKS file or URL
+    pub async fn load_keys(&self) -> Result<(), JwtError> {
+        let jwks = &self.config.jwks;
+        let set: JwkSet = if jwks.starts_with("http://") || jwks.starts_with("https://") {
+            self.http
+                .get(jwks)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| JwtError::Jwks(e.to_string()))?
+                .json()
+                .await
+                .map_err(|e| JwtError::Jwks(e.to_string()))?
+        } else {
+            let data = tokio::fs::read_to_string(jwks)
+                .await
+                .map_err(|e| JwtError::Jwks(format!("{}: {}", jwks, e)))?;
+            serde_json::from_str(&data).map_err(|e| JwtError::Jwks(e.to_string()))?
+        };
+
+        tracing::info!(jwks=%jwks, keys=set.keys.len(), "loaded jwks");
+        *self.keys.write().await = Keys {
+            set,
+            loaded: Some(Instant::now()),
+        };
+        Ok(())
+    }
+
+    async fn find_key(&self, kid: Option<&str>) -> Result<Jwk, JwtError> {
+        if let Some(jwk) = self.keys.read().await.find(kid) {
+            return Ok(jwk);
+        }
+
+        // the provider may have rotated its keys. Requests with unknown key ids
+        // wait for the one reloading them, rather than all fetching the keys.
+        let _refreshing = self.refresh.lock().await;
+        let stale = self
+            .keys
+            .read()
+            .await
+            .loaded
+            .is_none_or(|loaded| loaded.elapsed() > JWKS_REFRESH_INTERVAL);
+        if stale {
+            self.load_keys().await?;
+        }
+        self.keys
+            .read()
+            .await
+            .find(kid)
+            .ok_or_else(|| JwtError::UnknownKey(kid.map(String::from)))
+    }
+
+    /// Verify a token and read its claims
+    pub async fn validate(&self, token: &str) -> Result<TokenClaims, JwtError> {
+        let header = decode_header(token)?;
+        let jwk = self.find_key(header.kid.as_deref()).await?;
+
+        // a key made for one algorithm only verifies that one
+        let algorithm = match jwk.common.key_algorithm {
+            Some(key_algorithm) => Algorithm::from_str(&key_algorithm.to_string())?,
+            None => header.alg,
+        };
+        let mut validation = Validation::new(algorithm);
+        match self.config.audience {
+            Some(ref audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        if let Some(ref issuer) = self.config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+
+        let claims = decode::<Value>(token, &DecodingKey::from_jwk(&jwk)?, &validation)?.claims;
+        self.read_claims(&claims)
+    }
+
+    fn read_claims(&self, claims: &Value) -> Result<TokenClaims, JwtError> {
+        let account = claims
+            .get(&self.config.account_claim)
+            .and_then(Value::as_str)
+            .ok_or_else(|| JwtError::MissingClaim(self.config.account_claim.clone()))?
+            .to_string();
+
+        // a list, or space separated like scopes
+        let sub_domains = match claims.get(&self.config.sub_domains_claim) {
+            Some(Value::Array(patterns)) => patterns
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_lowercase)
+                .collect(),
+            Some(Value::String(patterns)) => {
+                patterns.split_whitespace().map(str::to_lowercase).collect()
+            }
+            _ => vec![],
+        };
+
+        let limits = match claims.get(&self.config.limits_claim) {
+            Some(limits) => serde_json::from_value(limits.clone()).unwrap_or_else(|error| {
+                tracing::warn!(?error, "ignoring invalid limits claim");
+                AccountLimits::default()
+            }),
+            None => AccountLimits::default(),
+        };
+
+        let expires = claims
+            .get("exp")
+            .and_then(Value::as_i64)
+            .and_then(|exp| DateTime::from_timestamp(exp, 0));
+
+        Ok(TokenClaims {
+            account,
+            sub_domains,
+            limits,
+            expires,
+        })
+    }
+}
+
+impl AuthService for JwtAuth {
+    type Error = JwtError;
+    type AuthKey = TokenClaims;
+
+    fn auth_sub_domain(
+        &self,
+        claims: &Self::AuthKey,
+        subdomain: &str,
+    ) -> Result<AuthResult, Self::Error> {
+        if claims
+            .sub_domains
+            .iter()
+            .any(|pattern| pattern == subdomain)
+        {
+            Ok(AuthResult::ReservedByYou)
+        } else if claims.allows(subdomain) {
+            Ok(AuthResult::Available)
+        } else {
+            Err(JwtError::SubDomainNotAllowed)
+        }
+    }
+
+    fn account_limits(&self, claims: &Self::AuthKey) -> Result<AccountLimits, Self::Error> {
+        Ok(claims.limits.clone())
+    }
+}
+
+/// Match a sub-domain against a pattern where `*` stands for any characters
+fn matches_pattern(pattern: &str, sub_domain: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == sub_domain,
+        Some((prefix, rest)) => {
+            let Some(tail) = sub_domain.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=tail.len())
+                .filter(|&i| tail.is_char_boundary(i))
+                .any(|i| matches_pattern(rest, &tail[i..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    const PRIVATE_KEY: &[u8] = include_bytes!("../../tests/jwt/key.pem");
+
+    fn jwt_auth() -> JwtAuth {
+        JwtAuth::new(JwtConfig {
+            jwks: "tests/jwt/jwks.json".into(),
+            issuer: Some("https://id.acme.test".into()),
+            audience: Some("portal".into()),
+            account_claim: "sub".into(),
+            sub_domains_claim: "sub_domains".into(),
+            limits_claim: "limits".into(),
+        })
+    }
+
+    fn token(kid: &str, claims: Value) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(kid.into());
+        encode(
+            &header,
+            &claims,
+            &EncodingKey::from_rsa_pem(PRIVATE_KEY).unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn payload(exp: i64) -> Value {
+        json!({
+            "sub": "acme",
+            "iss": "https://id.acme.test",
+            "aud": "portal",
+            "exp": exp,
+            "sub_domains": ["acme-*", "demo"],
+            "limits": { "max_tunnels": 2 },
+        })
+    }
+
+    #[tokio::test]
+    async fn test_validate() {
+        let auth = jwt_auth();
+        let exp = chrono::Utc::now().timestamp() + 300;
+
+        let claims = auth.validate(&token("test", payload(exp))).await.unwrap();
+        assert_eq!(claims.account, "acme");
+        assert_eq!(claims.expires.map(|expires| expires.timestamp()), Some(exp));
+        assert_eq!(claims.limits.max_tunnels, Some(2));
+        assert_eq!(claims.default_sub_domain().as_deref(), Some("demo"));
+        assert!(matches!(
+            auth.auth_sub_domain(&claims, "acme-web"),
+            Ok(AuthResult::Available)
+        ));
+        assert!(matches!(
+            auth.auth_sub_domain(&claims, "demo"),
+            Ok(AuthResult::ReservedByYou)
+        ));
+        assert!(auth.auth_sub_domain(&claims, "other").is_err());
+
+        // expired
+        assert!(auth
+            .validate(&token("test", payload(exp - 3600)))
+            .await
+            .is_err());
+        // signed with a key the jwks doesn't have
+        assert!(auth.validate(&token("other", payload(exp))).await.is_err());
+        // for someone else
+        let mut wrong_audience = payload(exp);
+        wrong_audience["aud"] = "dashboard".into();
+        assert!(auth.validate(&token("test", wrong_audience)).await.is_err());
+    }
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("acme-*", "acme-web"));
+        assert!(matches_pattern("acme-*", "acme-"));
+        assert!(matches_pattern("*-staging", "web-staging"));
+        assert!(matches_pattern("a*b*c", "axxbyyc"));
+        assert!(!matches_pattern("acme-*", "acme"));
+        assert!(!matches_pattern("demo", "demo2"));
+    }
+}
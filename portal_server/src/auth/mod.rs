@@ -1,10 +1,16 @@
+use crate::rate_limit::RateLimit;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use sha2::Digest;
 use std::convert::TryInto;
 use std::fmt::Formatter;
+use std::path::Path;
+use subtle::ConstantTimeEq;
 
 pub mod client_auth;
+pub mod jwt;
 pub mod reconnect_token;
+pub mod reservations;
 
 #[derive(Clone, Default)]
 pub struct SigKey([u8; 32]);
@@ -19,6 +25,16 @@ impl std::fmt::Debug for SigKey {
 #[serde(transparent)]
 pub struct Signature(String);
 
+impl Signature {
+    pub fn new(hex: String) -> Self {
+        Signature(hex)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
 impl SigKey {
     pub fn generate() -> Self {
         SigKey(rand::thread_rng().gen::<[u8; 32]>())
@@ -42,8 +58,108 @@ impl SigKey {
             Ok(s) => s,
             Err(_) => return false,
         };
-        let expected = hmac_sha256::HMAC::mac(data, self.0).to_vec();
-        signature == expected
+        let expected = hmac_sha256::HMAC::mac(data, self.0);
+        signature.ct_eq(&expected).into()
+    }
+
+    /// A short public fingerprint of this key, the same on every instance
+    pub fn id(&self) -> String {
+        hex::encode(&sha2::Sha256::digest(self.0)[..4])
+    }
+}
+
+/// The keys we sign with: the current one signs,
+/// the previous ones still verify tokens issued before a rotation
+#[derive(Clone)]
+pub struct SigKeyRing {
+    current: SigKey,
+    previous: Vec<SigKey>,
+    /// randomly generated, not shared with other instances
+    ephemeral: bool,
+}
+
+impl std::fmt::Debug for SigKeyRing {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigKeyRing")
+            .field("current", &self.current.id())
+            .field(
+                "previous",
+                &self.previous.iter().map(SigKey::id).collect::<Vec<_>>(),
+            )
+            .field("ephemeral", &self.ephemeral)
+            .finish()
+    }
+}
+
+impl PartialEq for SigKeyRing {
+    fn eq(&self, other: &Self) -> bool {
+        self.ids() == other.ids()
+    }
+}
+
+impl SigKeyRing {
+    pub fn new(current: SigKey, previous: Vec<SigKey>) -> Self {
+        SigKeyRing {
+            current,
+            previous,
+            ephemeral: false,
+        }
+    }
+
+    pub fn generate() -> Self {
+        SigKeyRing {
+            current: SigKey::generate(),
+            previous: vec![],
+            ephemeral: true,
+        }
+    }
+
+    /// Load hex keys from files, the first one is the current key
+    pub fn from_files<P: AsRef<Path>>(paths: &[P]) -> Result<Self, String> {
+        let mut keys = paths
+            .iter()
+            .map(|path| {
+                let path = path.as_ref();
+                let hex = std::fs::read_to_string(path)
+                    .map_err(|e| format!("failed to read key {}: {}", path.display(), e))?;
+                SigKey::from_hex(hex.trim()).map_err(|_| {
+                    format!(
+                        "invalid key {}: not hex or length incorrect",
+                        path.display()
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if keys.is_empty() {
+            return Err("no signature keys".to_string());
+        }
+
+        let current = keys.remove(0);
+        Ok(SigKeyRing::new(current, keys))
+    }
+
+    pub fn is_ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+
+    /// The key new signatures are made with
+    pub fn current(&self) -> &SigKey {
+        &self.current
+    }
+
+    /// Find a key we still accept by its id
+    pub fn get(&self, id: &str) -> Option<&SigKey> {
+        self.keys().find(|key| key.id() == id)
+    }
+
+    /// Every key we accept, current first
+    pub fn keys(&self) -> impl Iterator<Item = &SigKey> {
+        std::iter::once(&self.current).chain(self.previous.iter())
+    }
+
+    fn ids(&self) -> Vec<String> {
+        self.keys().map(SigKey::id).collect()
     }
 }
 
@@ -58,6 +174,25 @@ pub trait AuthService {
         auth_key: &Self::AuthKey,
         subdomain: &str,
     ) -> Result<AuthResult, Self::Error>;
+
+    /// Look up the limits for the account behind an AuthKey
+    fn account_limits(&self, _auth_key: &Self::AuthKey) -> Result<AccountLimits, Self::Error> {
+        Ok(AccountLimits::default())
+    }
+
+    /// Whether keys are checked against real accounts, rather than any key being accepted
+    fn verifies_keys(&self) -> bool {
+        true
+    }
+}
+
+/// Per-account limits, unset limits fall back to the server config
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AccountLimits {
+    pub max_tunnels: Option<usize>,
+    pub max_reservations: Option<usize>,
+    pub tunnel_request_limit: Option<RateLimit>,
+    pub bandwidth_limit: Option<RateLimit>,
 }
 
 /// A result for authenticating a subdomain
@@ -85,4 +220,8 @@ impl AuthService for NoAuth {
     ) -> Result<AuthResult, Self::Error> {
         Ok(AuthResult::Available)
     }
+
+    fn verifies_keys(&self) -> bool {
+        false
+    }
 }
@@ -1,9 +1,10 @@
-use crate::auth::{SigKey, Signature};
+use crate::auth::{AccountLimits, SigKeyRing, Signature};
 use base64::{engine::general_purpose, Engine};
 use chrono::{DateTime, Utc};
+use portal_lib::{ClientId, ReconnectToken};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use portal_lib::{ClientId, ReconnectToken};
+use uuid::Uuid;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -16,6 +17,9 @@ pub enum Error {
     #[error("invalid reconnect token (signature)")]
     InvalidSignature,
 
+    #[error("reconnect token signed with an unknown key")]
+    UnknownKey,
+
     #[error("reconnect token expired")]
     Expired,
 }
@@ -25,21 +29,57 @@ pub struct ReconnectTokenPayload {
     pub sub_domain: String,
     pub client_id: ClientId,
     pub expires: DateTime<Utc>,
+    /// when the tunnel session ends, regardless of reconnects
+    #[serde(default)]
+    pub session_expires: Option<DateTime<Utc>>,
+    /// tokens minted before accounts got them are all anonymous
+    #[serde(default = "default_anonymous")]
+    pub is_anonymous: bool,
+    /// the account limits, so reconnecting skips the auth backend
+    #[serde(default)]
+    pub limits: AccountLimits,
+    /// the control connection this one replaces, to resume its streams
+    #[serde(default)]
+    pub session_id: Option<Uuid>,
+    /// when the account last authenticated, reconnects don't renew it
+    #[serde(default)]
+    pub authenticated: Option<DateTime<Utc>>,
+}
+
+fn default_anonymous() -> bool {
+    true
 }
+
 impl ReconnectTokenPayload {
-    pub fn to_token(&self, key: &SigKey) -> Result<ReconnectToken, Error> {
+    pub fn to_token(&self, keys: &SigKeyRing) -> Result<ReconnectToken, Error> {
         let payload = serde_json::to_string(&self)?;
+        let key = keys.current();
         let sig = key.sign(payload.as_bytes());
-        let tok = ReconnectTokenInner { payload, sig };
+        let tok = ReconnectTokenInner {
+            payload,
+            sig,
+            key_id: Some(key.id()),
+        };
         let tok = general_purpose::STANDARD.encode(serde_json::to_vec(&tok)?);
         Ok(ReconnectToken(tok))
     }
 
-    pub fn verify(tok: ReconnectToken, key: &SigKey) -> Result<ReconnectTokenPayload, Error> {
+    pub fn verify(tok: ReconnectToken, keys: &SigKeyRing) -> Result<ReconnectTokenPayload, Error> {
         let tok = general_purpose::STANDARD.decode(tok.0.as_str())?;
         let tok: ReconnectTokenInner = serde_json::from_slice(&tok)?;
 
-        if !key.verify(tok.payload.as_bytes(), &tok.sig) {
+        let valid = match tok.key_id {
+            Some(ref key_id) => keys
+                .get(key_id)
+                .ok_or(Error::UnknownKey)?
+                .verify(tok.payload.as_bytes(), &tok.sig),
+            // tokens from before key rotation don't say which key signed them
+            None => keys
+                .keys()
+                .any(|key| key.verify(tok.payload.as_bytes(), &tok.sig)),
+        };
+
+        if !valid {
             return Err(Error::InvalidSignature);
         }
 
@@ -57,4 +97,77 @@ impl ReconnectTokenPayload {
 struct ReconnectTokenInner {
     payload: String,
     sig: Signature,
+    #[serde(default)]
+    key_id: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::auth::SigKey;
+
+    #[test]
+    fn test_account_token_roundtrip() {
+        let key = SigKeyRing::generate();
+        let payload = ReconnectTokenPayload {
+            sub_domain: "foo".into(),
+            client_id: ClientId::generate(),
+            expires: Utc::now() + chrono::Duration::minutes(2),
+            session_expires: None,
+            is_anonymous: false,
+            limits: AccountLimits {
+                max_tunnels: Some(3),
+                ..Default::default()
+            },
+            session_id: Some(Uuid::new_v4()),
+            authenticated: Some(Utc::now()),
+        };
+
+        let token = payload.to_token(&key).unwrap();
+        let verified = ReconnectTokenPayload::verify(token.clone(), &key).unwrap();
+        assert!(!verified.is_anonymous);
+        assert_eq!(verified.limits.max_tunnels, Some(3));
+        assert_eq!(verified.session_id, payload.session_id);
+        assert!(ReconnectTokenPayload::verify(token, &SigKeyRing::generate()).is_err());
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let old = SigKey::generate();
+        let new = SigKey::generate();
+        let payload = ReconnectTokenPayload {
+            sub_domain: "foo".into(),
+            client_id: ClientId::generate(),
+            expires: Utc::now() + chrono::Duration::minutes(2),
+            session_expires: None,
+            is_anonymous: true,
+            limits: AccountLimits::default(),
+            session_id: None,
+            authenticated: None,
+        };
+
+        let token = payload
+            .to_token(&SigKeyRing::new(old.clone(), vec![]))
+            .unwrap();
+
+        let rotated = SigKeyRing::new(new.clone(), vec![old]);
+        assert!(ReconnectTokenPayload::verify(token.clone(), &rotated).is_ok());
+
+        let retired = SigKeyRing::new(new, vec![]);
+        assert!(matches!(
+            ReconnectTokenPayload::verify(token, &retired),
+            Err(Error::UnknownKey)
+        ));
+    }
+
+    #[test]
+    fn test_legacy_token_is_anonymous() {
+        let payload: ReconnectTokenPayload = serde_json::from_str(
+            r#"{"sub_domain":"foo","client_id":"abc","expires":"2030-01-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+        assert!(payload.is_anonymous);
+        assert!(payload.session_id.is_none());
+    }
 }
@@ -0,0 +1,409 @@
+use crate::auth::AuthResult;
+use crate::sub_domain::parents;
+use chrono::{DateTime, Utc};
+use portal_lib::ClientId;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ReservationError {
+    #[error("sub-domain is reserved by someone else")]
+    ReservedByOther,
+    #[error("sub-domain is not reserved by you")]
+    NotReserved,
+    #[error("no more than {0} sub-domains may be reserved")]
+    LimitReached(usize),
+    #[error("failed to save reservations: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to read reservations: {0}")]
+    Unreadable(String),
+}
+
+/// Who a sub-domain is reserved for
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Reservation {
+    pub owner: ClientId,
+    pub created: DateTime<Utc>,
+    /// reserving it again renews it, kept for good if unset
+    #[serde(default)]
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl Reservation {
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// Sub-domains reserved by accounts. Kept in a JSON file if there is one,
+/// which is read again when it changes, so instances can share it.
+/// Changes hold a lock on `<file>.lock`, so they don't undo each other's
+pub struct Reservations {
+    path: Option<PathBuf>,
+    state: RwLock<State>,
+}
+
+#[derive(Default)]
+struct State {
+    domains: BTreeMap<String, Reservation>,
+    /// the modification time of the file when we read it
+    modified: Option<SystemTime>,
+}
+
+impl Reservations {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Reservations {
+            path,
+            state: RwLock::new(State::default()),
+        }
+    }
+
+    /// Whether `client_id` may open a tunnel on `sub_domain`,
+    /// a reservation covers every host below it
+    pub fn check(&self, sub_domain: &str, client_id: &ClientId) -> AuthResult {
+        self.refresh();
+        let state = self.state.read().unwrap();
+        let now = Utc::now();
+        let owners = parents(sub_domain)
+            .filter_map(|name| state.domains.get(name))
+            .filter(|reservation| !reservation.is_expired(now))
+            .map(|reservation| &reservation.owner)
+            .collect::<Vec<_>>();
+
+        if owners.iter().any(|owner| *owner != client_id) {
+            AuthResult::ReservedByOther
+        } else if owners.is_empty() {
+            AuthResult::Available
+        } else {
+            AuthResult::ReservedByYou
+        }
+    }
+
+    /// The sub-domains reserved by `owner`
+    pub fn list(&self, owner: &ClientId) -> Vec<(String, Reservation)> {
+        self.refresh();
+        let now = Utc::now();
+        self.state
+            .read()
+            .unwrap()
+            .domains
+            .iter()
+            .filter(|(_, reservation)| &reservation.owner == owner && !reservation.is_expired(now))
+            .map(|(sub_domain, reservation)| (sub_domain.clone(), reservation.clone()))
+            .collect()
+    }
+
+    /// Reserve `sub_domain` for `owner`, who may hold `max` reservations, for `ttl`.
+    /// Reserving it again renews it. Returns the reservation and whether it is new
+    pub fn reserve(
+        &self,
+        sub_domain: &str,
+        owner: &ClientId,
+        max: usize,
+        ttl: Duration,
+    ) -> Result<(Reservation, bool), ReservationError> {
+        let _lock = self.lock_file()?;
+        let mut state = self.state.write().unwrap();
+        self.reload(&mut state, true)?;
+
+        let now = Utc::now();
+        let expires = chrono::Duration::from_std(ttl)
+            .ok()
+            .and_then(|ttl| now.checked_add_signed(ttl));
+        let mut domains = state.domains.clone();
+        domains.retain(|_, reservation| !reservation.is_expired(now));
+
+        if let Some(reservation) = domains
+            .get_mut(sub_domain)
+            .filter(|reservation| &reservation.owner == owner)
+        {
+            reservation.expires = expires;
+            let reservation = reservation.clone();
+            self.save(&mut state, domains)?;
+            return Ok((reservation, false));
+        }
+
+        // nor above or below someone else's
+        let is_taken = domains.iter().any(|(name, reservation)| {
+            &reservation.owner != owner
+                && (parents(sub_domain).any(|parent| parent == name)
+                    || parents(name).any(|parent| parent == sub_domain))
+        });
+        if is_taken {
+            return Err(ReservationError::ReservedByOther);
+        }
+        let held = domains
+            .values()
+            .filter(|reservation| &reservation.owner == owner)
+            .count();
+        if held >= max {
+            return Err(ReservationError::LimitReached(max));
+        }
+
+        let reservation = Reservation {
+            owner: owner.clone(),
+            created: now,
+            expires,
+        };
+        domains.insert(sub_domain.to_string(), reservation.clone());
+        self.save(&mut state, domains)?;
+        Ok((reservation, true))
+    }
+
+    /// Give up a sub-domain `owner` reserved
+    pub fn release(&self, sub_domain: &str, owner: &ClientId) -> Result<(), ReservationError> {
+        let _lock = self.lock_file()?;
+        let mut state = self.state.write().unwrap();
+        self.reload(&mut state, true)?;
+
+        match state.domains.get(sub_domain) {
+            Some(reservation) if &reservation.owner == owner => {}
+            _ => return Err(ReservationError::NotReserved),
+        }
+
+        let mut domains = state.domains.clone();
+        domains.remove(sub_domain);
+        self.save(&mut state, domains)?;
+        Ok(())
+    }
+
+    fn refresh(&self) {
+        let Some(ref path) = self.path else {
+            return;
+        };
+
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified == self.state.read().unwrap().modified {
+            return;
+        }
+        // what we read last is still better than nothing to check tunnels against
+        if let Err(error) = self.reload(&mut self.state.write().unwrap(), false) {
+            tracing::warn!(%error, path=%path.display(), "keeping the reservations we had");
+        }
+    }
+
+    /// Lock the file against changes by other instances, until the lock is dropped
+    fn lock_file(&self) -> std::io::Result<Option<File>> {
+        let Some(ref path) = self.path else {
+            return Ok(None);
+        };
+
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.with_extension("lock"))?;
+        lock.lock()?;
+        Ok(Some(lock))
+    }
+
+    /// Read the file again if it changed since we last did, or anyway if `force`d:
+    /// another instance may have changed it within the resolution of its mtime.
+    /// Fails rather than keep what we had, so a change doesn't overwrite a file we can't read
+    fn reload(&self, state: &mut State, force: bool) -> Result<(), ReservationError> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+
+        let modified = match std::fs::metadata(path).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(ReservationError::Unreadable(error.to_string())),
+        };
+        if !force && state.modified == Some(modified) {
+            return Ok(());
+        }
+
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| ReservationError::Unreadable(e.to_string()))?;
+        state.domains =
+            serde_json::from_str(&data).map_err(|e| ReservationError::Unreadable(e.to_string()))?;
+        state.modified = Some(modified);
+        Ok(())
+    }
+
+    fn save(
+        &self,
+        state: &mut State,
+        domains: BTreeMap<String, Reservation>,
+    ) -> std::io::Result<()> {
+        if let Some(ref path) = self.path {
+            // replace the file at once, so nobody reads half of it
+            let tmp = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4().simple()));
+            std::fs::write(&tmp, serde_json::to_vec_pretty(&domains)?)?;
+            std::fs::rename(&tmp, path)?;
+            state.modified = std::fs::metadata(path)?.modified().ok();
+        }
+
+        state.domains = domains;
+        Ok(())
+    }
+}
+
+/// Run `f` on the reservations where blocking is fine:
+/// it may wait on the lock of another instance, and reads and writes the file
+pub async fn blocking<T, F>(f: F) -> T
+where
+    F: FnOnce(&'static Reservations) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(move || f(crate::get_reservations())).await {
+        Ok(result) => result,
+        Err(error) => std::panic::resume_unwind(error.into_panic()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(3600);
+
+    #[test]
+    fn test_reservations() {
+        let path = std::env::temp_dir().join(format!("reservations-{}.json", uuid::Uuid::new_v4()));
+        let reservations = Reservations::new(Some(path.clone()));
+        let (alice, bob) = (ClientId::generate(), ClientId::generate());
+
+        assert!(reservations.reserve("alice-api", &alice, 2, TTL).unwrap().1);
+        assert!(!reservations.reserve("alice-api", &alice, 2, TTL).unwrap().1);
+        assert!(matches!(
+            reservations.reserve("alice-api", &bob, 2, TTL),
+            Err(ReservationError::ReservedByOther)
+        ));
+        assert!(matches!(
+            reservations.check("alice-api", &alice),
+            AuthResult::ReservedByYou
+        ));
+        assert!(matches!(
+            reservations.check("alice-api", &bob),
+            AuthResult::ReservedByOther
+        ));
+        assert!(matches!(
+            reservations.check("other", &bob),
+            AuthResult::Available
+        ));
+
+        // it covers the hosts below it
+        assert!(matches!(
+            reservations.check("*.v2.alice-api", &bob),
+            AuthResult::ReservedByOther
+        ));
+        assert!(matches!(
+            reservations.check("v2.alice-api", &alice),
+            AuthResult::ReservedByYou
+        ));
+        assert!(matches!(
+            reservations.reserve("v2.alice-api", &bob, 2, TTL),
+            Err(ReservationError::ReservedByOther)
+        ));
+        reservations.reserve("bob.other", &bob, 2, TTL).unwrap();
+        assert!(matches!(
+            reservations.reserve("other", &alice, 2, TTL),
+            Err(ReservationError::ReservedByOther)
+        ));
+        reservations.release("bob.other", &bob).unwrap();
+
+        // up to the limit of the account
+        reservations.reserve("alice-web", &alice, 2, TTL).unwrap();
+        assert!(matches!(
+            reservations.reserve("alice-db", &alice, 2, TTL),
+            Err(ReservationError::LimitReached(2))
+        ));
+        assert!(!reservations.reserve("alice-web", &alice, 2, TTL).unwrap().1);
+        reservations.release("alice-web", &alice).unwrap();
+
+        // another instance sharing the file
+        let shared = Reservations::new(Some(path.clone()));
+        assert_eq!(shared.list(&alice).len(), 1);
+        assert!(shared.list(&bob).is_empty());
+
+        assert!(matches!(
+            reservations.release("alice-api", &bob),
+            Err(ReservationError::NotReserved)
+        ));
+        reservations.release("alice-api", &alice).unwrap();
+        assert!(reservations.list(&alice).is_empty());
+
+        // unless renewed in time, a reservation lapses
+        let (reservation, _) = reservations
+            .reserve("alice-tmp", &alice, 2, Duration::ZERO)
+            .unwrap();
+        assert!(reservation.expires.is_some());
+        assert!(reservations.list(&alice).is_empty());
+        assert!(matches!(
+            reservations.check("alice-tmp", &bob),
+            AuthResult::Available
+        ));
+        assert!(reservations.reserve("alice-tmp", &bob, 2, TTL).unwrap().1);
+        let (renewed, created) = reservations.reserve("alice-tmp", &bob, 2, TTL).unwrap();
+        assert!(!created);
+        assert!(renewed.expires > reservation.expires);
+        reservations.release("alice-tmp", &bob).unwrap();
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(path.with_extension("lock"));
+    }
+
+    #[test]
+    fn test_instances_sharing_a_file() {
+        let path = std::env::temp_dir().join(format!("reservations-{}.json", uuid::Uuid::new_v4()));
+        let instances = [
+            Reservations::new(Some(path.clone())),
+            Reservations::new(Some(path.clone())),
+        ];
+        let owner = ClientId::generate();
+
+        // none of the changes made at the same time get lost
+        std::thread::scope(|scope| {
+            for (i, instance) in instances.iter().enumerate() {
+                let owner = &owner;
+                scope.spawn(move || {
+                    for n in 0..20 {
+                        instance
+                            .reserve(&format!("app-{}-{}", i, n), owner, 100, TTL)
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(Reservations::new(Some(path.clone())).list(&owner).len(), 40);
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(path.with_extension("lock"));
+    }
+
+    #[test]
+    fn test_unreadable_file() {
+        let path = std::env::temp_dir().join(format!("reservations-{}.json", uuid::Uuid::new_v4()));
+        let reservations = Reservations::new(Some(path.clone()));
+        let owner = ClientId::generate();
+        reservations.reserve("app", &owner, 2, TTL).unwrap();
+
+        // a change fails rather than overwrite what we couldn't read
+        std::fs::write(&path, "{ not json").unwrap();
+        assert!(matches!(
+            reservations.reserve("other", &owner, 2, TTL),
+            Err(ReservationError::Unreadable(_))
+        ));
+        assert!(matches!(
+            reservations.release("app", &owner),
+            Err(ReservationError::Unreadable(_))
+        ));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{ not json");
+        // checks keep going by what we read last
+        assert!(matches!(
+            reservations.check("app", &owner),
+            AuthResult::ReservedByYou
+        ));
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(path.with_extension("lock"));
+    }
+}
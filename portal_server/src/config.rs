@@ -1,13 +1,26 @@
-use crate::auth::SigKey;
+use crate::auth::{SigKey, SigKeyRing};
+use crate::network::DiscoveryConfig;
+use crate::rate_limit::RateLimit;
 
 use std::error::Error;
-use std::net::IpAddr;
+use std::fmt::Debug;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 use serde::Deserialize;
-use tracing::info;
+use tracing::{info, warn};
 use uuid::Uuid;
 
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_MAINTENANCE_PAGE_TTL_SECS: u64 = 3600;
+const DEFAULT_MAX_RESERVATIONS_PER_ACCOUNT: usize = 10;
+/// 90 days
+const DEFAULT_RESERVATION_TTL_SECS: u64 = 90 * 24 * 3600;
+const DEFAULT_CLUSTER_TLS_PORT: u16 = 6443;
+const DEFAULT_CLUSTER_SERVER_NAME: &str = "portal.cluster";
+
 #[derive(Deserialize, Debug)]
 struct InternalConfig {
     /// What hosts do we allow tunnels on:
@@ -28,12 +41,36 @@ struct InternalConfig {
     /// internal port for instance-to-instance gossip communications
     internal_network_port: Option<u16>,
 
-    /// our signature key path
+    /// our signature key, in hex
     master_sig_key: Option<String>,
 
-    /// Instance DNS discovery domain for gossip protocol
+    /// files with our signature keys in hex, the first one signs:
+    /// i.e:    ["keys/2024-06.key", "keys/2024-05.key"]
+    sig_key_files: Option<Vec<PathBuf>>,
+
+    /// Instance DNS discovery domain for gossip protocol,
+    /// same as `discovery = { type = "dns", host = "..." }`
     gossip_dns_host: Option<String>,
 
+    /// How we find other instances for the gossip protocol:
+    /// i.e:    { type = "static", peers = ["10.0.0.2:6000"] }
+    ///         { type = "srv", name = "_net._tcp.portal.svc.cluster.local" }
+    ///         { type = "file", path = "/etc/portal/peers" }
+    discovery: Option<DiscoveryConfig>,
+
+    /// Shared key in hex, signing requests between instances
+    cluster_key: Option<String>,
+
+    /// Mutual TLS for streams proxied between instances
+    cluster_tls: Option<ClusterTlsConfig>,
+
+    /// TLS on the control port, authenticating clients by certificate
+    control_tls: Option<ControlTlsConfig>,
+
+    /// Authenticate clients by bearer JWTs:
+    /// i.e:    { jwks = "https://id.example.com/.well-known/jwks.json", issuer = "https://id.example.com" }
+    jwt: Option<JwtConfig>,
+
     /// Observability API key
     honeycomb_api_key: Option<String>,
 
@@ -45,10 +82,240 @@ struct InternalConfig {
 
     /// The host on which we create tunnels on
     portal_host: Option<String>,
+
+    /// New control connections allowed per client ip
+    control_connection_limit: Option<RateLimit>,
+
+    /// Maximum concurrent tunnels per client id (api key)
+    max_tunnels_per_client: Option<usize>,
+
+    /// Visitor connections allowed per tunnel on the remote listener,
+    /// keep-alive requests on a connection count once
+    tunnel_request_limit: Option<RateLimit>,
+
+    /// Bandwidth allowed per client, in bytes
+    client_bandwidth_limit: Option<RateLimit>,
+
+    /// How we treat clients without an API key
+    anonymous_policy: Option<AnonymousPolicy>,
+
+    /// Maximum lifetime of an anonymous tunnel, in seconds
+    max_anonymous_session: Option<u64>,
+
+    /// How long to wait for active streams on shutdown, in seconds
+    shutdown_timeout: Option<u64>,
+
+    /// How long to hold streams for a disconnected client to resume them, in seconds
+    stream_resume_grace: Option<u64>,
+
+    /// How new streams are spread across the clients of a pool tunnel
+    pool_strategy: Option<PoolStrategy>,
+
+    /// Bearer token for the admin endpoints, disabled if unset
+    admin_token: Option<String>,
+
+    /// Serve the admin endpoints here rather than on the control port
+    admin_addr: Option<SocketAddr>,
+
+    /// Where reserved sub-domains are kept, in memory only if unset
+    reservations_file: Option<PathBuf>,
+
+    /// What the error pages served to visitors look like
+    error_pages: Option<ErrorPagesConfig>,
+
+    /// How long to serve the maintenance page of a disconnected client, in seconds
+    maintenance_page_ttl: Option<u64>,
+
+    /// Maximum sub-domains an account may reserve, 0 turns reservations off
+    max_reservations_per_account: Option<usize>,
+
+    /// How long a reservation lasts unless renewed, in seconds
+    reservation_ttl: Option<u64>,
+}
+
+/// Mutual TLS between the instances of a cluster
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ClusterTlsConfig {
+    /// this instance's certificate chain (PEM)
+    pub cert: PathBuf,
+    /// this instance's private key (PEM)
+    pub key: PathBuf,
+    /// the CA that issued every instance's certificate (PEM)
+    pub ca: PathBuf,
+    /// the name on the instance certificates
+    #[serde(default = "default_cluster_server_name")]
+    pub server_name: String,
+    /// port for streams proxied from other instances
+    #[serde(default = "default_cluster_tls_port")]
+    pub port: u16,
+}
+
+/// TLS on the control port, authenticating clients by certificate
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ControlTlsConfig {
+    /// the control server's certificate chain (PEM)
+    pub cert: PathBuf,
+    /// the control server's private key (PEM)
+    pub key: PathBuf,
+    /// the CA that issues client certificates (PEM), none are asked for if unset
+    pub client_ca: Option<PathBuf>,
+    /// reject clients without a certificate, instead of falling back to API keys
+    #[serde(default)]
+    pub require_client_cert: bool,
+    /// the accounts of client certificates, by default their common name.
+    /// Certificates matching none of them are rejected
+    #[serde(default)]
+    pub identities: Vec<CertIdentity>,
+}
+
+/// An account authenticated by client certificate
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct CertIdentity {
+    /// the subject common name, or a DNS, email or URI subject alternative name
+    pub name: String,
+    /// the account its tunnels belong to
+    pub account: String,
+    /// the sub-domains it may use, any if empty
+    #[serde(default)]
+    pub sub_domains: Vec<String>,
+}
+
+impl CertIdentity {
+    pub fn allows(&self, sub_domain: &str) -> bool {
+        self.sub_domains.is_empty()
+            || self
+                .sub_domains
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(sub_domain))
+    }
+}
+
+/// Bearer JWT authentication, and what the claims of tokens mean
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct JwtConfig {
+    /// the keys tokens are signed with: a JWKS file, or an http(s) URL
+    pub jwks: String,
+    /// the issuer tokens must have, any if unset
+    pub issuer: Option<String>,
+    /// the audience tokens must have, any if unset
+    pub audience: Option<String>,
+    /// the claim naming the account
+    #[serde(default = "default_account_claim")]
+    pub account_claim: String,
+    /// the claim with the sub-domains a token may use, i.e. `["acme-*"]`, any if missing
+    #[serde(default = "default_sub_domains_claim")]
+    pub sub_domains_claim: String,
+    /// the claim with the account's limits, i.e. `{ "max_tunnels": 2 }`
+    #[serde(default = "default_limits_claim")]
+    pub limits_claim: String,
+}
+
+/// The error pages served to visitors, and where the allowed hosts themselves redirect
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ErrorPagesConfig {
+    /// the name shown on error pages
+    #[serde(default = "default_brand")]
+    pub brand: String,
+    /// where visitors of an allowed host itself are redirected
+    #[serde(default = "default_homepage")]
+    pub homepage: String,
+    /// an HTML template with `{{status}}`, `{{reason}}`, `{{message}}`, `{{brand}}`
+    /// and `{{request_id}}` placeholders, a built-in one if unset
+    pub template: Option<PathBuf>,
+    /// show visitors a request id to quote to support, also sent as `X-Request-Id`
+    #[serde(default)]
+    pub request_id: bool,
+}
+
+impl Default for ErrorPagesConfig {
+    fn default() -> Self {
+        ErrorPagesConfig {
+            brand: default_brand(),
+            homepage: default_homepage(),
+            template: None,
+            request_id: false,
+        }
+    }
+}
+
+fn default_brand() -> String {
+    "Portal".to_string()
+}
+
+fn default_homepage() -> String {
+    "https://tunnelto.dev".to_string()
+}
+
+fn default_account_claim() -> String {
+    "sub".to_string()
+}
+
+fn default_sub_domains_claim() -> String {
+    "sub_domains".to_string()
+}
+
+fn default_limits_claim() -> String {
+    "limits".to_string()
+}
+
+fn default_cluster_server_name() -> String {
+    DEFAULT_CLUSTER_SERVER_NAME.to_string()
+}
+
+fn default_cluster_tls_port() -> u16 {
+    DEFAULT_CLUSTER_TLS_PORT
+}
+
+/// How we treat clients without an API key
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnonymousPolicy {
+    /// Reject anonymous clients
+    Disallow,
+    /// Anonymous clients always get a random sub-domain
+    Random,
+    /// Anonymous clients get a `<prefix>-<random>` sub-domain
+    Prefixed,
+}
+
+impl FromStr for AnonymousPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disallow" => Ok(AnonymousPolicy::Disallow),
+            "random" => Ok(AnonymousPolicy::Random),
+            "prefixed" => Ok(AnonymousPolicy::Prefixed),
+            _ => Err(format!("unknown anonymous policy: {}", s)),
+        }
+    }
+}
+
+/// How new streams are spread across the clients of a pool tunnel
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolStrategy {
+    /// Take turns
+    RoundRobin,
+    /// The client with the fewest active streams
+    #[default]
+    LeastStreams,
+}
+
+impl FromStr for PoolStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round_robin" => Ok(PoolStrategy::RoundRobin),
+            "least_streams" => Ok(PoolStrategy::LeastStreams),
+            _ => Err(format!("unknown pool strategy: {}", s)),
+        }
+    }
 }
 
 /// Global service configuration
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Config {
     /// What hosts do we allow tunnels on:
     /// i.e:    baz.com => *.baz.com
@@ -68,11 +335,23 @@ pub struct Config {
     /// internal port for instance-to-instance gossip coms
     pub internal_network_port: u16,
 
-    /// our signature key
-    pub master_sig_key: SigKey,
+    /// our signature keys
+    pub sig_keys: SigKeyRing,
+
+    /// How we find other instances for the gossip protocol, disabled if unset
+    pub discovery: Option<DiscoveryConfig>,
+
+    /// Shared key signing requests between instances, unsigned if unset
+    pub cluster_key: Option<SigKey>,
 
-    /// Instance DNS discovery domain for gossip protocol
-    pub gossip_dns_host: Option<String>,
+    /// Mutual TLS for streams proxied between instances, plain TCP if unset
+    pub cluster_tls: Option<ClusterTlsConfig>,
+
+    /// TLS on the control port, plain websockets if unset
+    pub control_tls: Option<ControlTlsConfig>,
+
+    /// Bearer JWT authentication, disabled if unset
+    pub jwt: Option<JwtConfig>,
 
     /// Observability API key
     pub honeycomb_api_key: Option<String>,
@@ -85,22 +364,88 @@ pub struct Config {
 
     /// The host on which we create tunnels on
     pub portal_host: String,
+
+    /// New control connections allowed per client ip
+    pub control_connection_limit: Option<RateLimit>,
+
+    /// Maximum concurrent tunnels per client id (api key)
+    pub max_tunnels_per_client: Option<usize>,
+
+    /// Visitor connections allowed per tunnel on the remote listener,
+    /// keep-alive requests on a connection count once
+    pub tunnel_request_limit: Option<RateLimit>,
+
+    /// Bandwidth allowed per client, in bytes
+    pub client_bandwidth_limit: Option<RateLimit>,
+
+    /// How we treat clients without an API key
+    pub anonymous_policy: AnonymousPolicy,
+
+    /// Maximum lifetime of an anonymous tunnel
+    pub max_anonymous_session: Option<Duration>,
+
+    /// How long to wait for active streams on shutdown
+    pub shutdown_timeout: Duration,
+
+    /// How long to hold streams for a disconnected client to resume them
+    pub stream_resume_grace: Duration,
+
+    /// How new streams are spread across the clients of a pool tunnel
+    pub pool_strategy: PoolStrategy,
+
+    /// Bearer token for the admin endpoints, disabled if unset
+    pub admin_token: Option<String>,
+
+    /// Serve the admin endpoints here rather than on the control port
+    pub admin_addr: Option<SocketAddr>,
+
+    /// Where reserved sub-domains are kept, in memory only if unset
+    pub reservations_file: Option<PathBuf>,
+
+    /// What the error pages served to visitors look like
+    pub error_pages: ErrorPagesConfig,
+
+    /// How long to serve the maintenance page of a disconnected client
+    pub maintenance_page_ttl: Duration,
+
+    /// Maximum sub-domains an account may reserve, 0 turns reservations off
+    pub max_reservations_per_account: usize,
+
+    /// How long a reservation lasts unless renewed
+    pub reservation_ttl: Duration,
 }
 
-impl From<InternalConfig> for Config {
-    fn from(config: InternalConfig) -> Self {
+impl TryFrom<InternalConfig> for Config {
+    type Error = Box<dyn Error>;
+
+    fn try_from(config: InternalConfig) -> Result<Self, Self::Error> {
         let allowed_hosts = config.allowed_hosts.unwrap_or_default();
         let blocked_sub_domains = config.blocked_sub_domains.unwrap_or_default();
         let remote_port = config.remote_port.unwrap_or(8080);
         let control_port = config.control_port.unwrap_or(5000);
         let internal_network_port = config.internal_network_port.unwrap_or(6000);
-        let master_sig_key = config
-            .master_sig_key
-            .map(|key| {
-                SigKey::from_hex(&key).expect("invalid master key: not hex or length incorrect")
-            })
-            .unwrap_or_else(SigKey::generate);
-        let gossip_dns_host = config.gossip_dns_host;
+        let sig_keys = match (config.sig_key_files, config.master_sig_key) {
+            (Some(files), _) => SigKeyRing::from_files(&files)?,
+            (None, Some(key)) => SigKeyRing::new(
+                SigKey::from_hex(&key)
+                    .map_err(|_| "invalid master key: not hex or length incorrect")?,
+                vec![],
+            ),
+            (None, None) => SigKeyRing::generate(),
+        };
+        let discovery = config.discovery.or(config
+            .gossip_dns_host
+            .map(|host| DiscoveryConfig::Dns { host }));
+        let cluster_key = match config.cluster_key {
+            Some(key) => Some(
+                SigKey::from_hex(&key)
+                    .map_err(|_| "invalid cluster key: not hex or length incorrect")?,
+            ),
+            None => None,
+        };
+        let cluster_tls = config.cluster_tls;
+        let control_tls = config.control_tls;
+        let jwt = config.jwt;
         let honeycomb_api_key = config.honeycomb_api_key;
         let instance_id = config
             .instance_id
@@ -109,20 +454,74 @@ impl From<InternalConfig> for Config {
         let portal_host = config
             .portal_host
             .unwrap_or_else(|| "tunnelto.dev".to_string());
-
-        Config {
+        let control_connection_limit = config.control_connection_limit;
+        let max_tunnels_per_client = config.max_tunnels_per_client;
+        let tunnel_request_limit = config.tunnel_request_limit;
+        let client_bandwidth_limit = config.client_bandwidth_limit;
+        let anonymous_policy = config.anonymous_policy.unwrap_or(AnonymousPolicy::Prefixed);
+        let max_anonymous_session = config.max_anonymous_session.map(Duration::from_secs);
+        let shutdown_timeout = Duration::from_secs(
+            config
+                .shutdown_timeout
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+        );
+        let admin_token = config.admin_token;
+        let admin_addr = config.admin_addr;
+        let reservations_file = config.reservations_file;
+        let error_pages = config.error_pages.unwrap_or_default();
+        let maintenance_page_ttl = Duration::from_secs(
+            config
+                .maintenance_page_ttl
+                .unwrap_or(DEFAULT_MAINTENANCE_PAGE_TTL_SECS),
+        );
+        let stream_resume_grace = Duration::from_secs(
+            config
+                .stream_resume_grace
+                .unwrap_or(portal_lib::STREAM_RESUME_GRACE),
+        );
+        let pool_strategy = config.pool_strategy.unwrap_or_default();
+        let max_reservations_per_account = config
+            .max_reservations_per_account
+            .unwrap_or(DEFAULT_MAX_RESERVATIONS_PER_ACCOUNT);
+        let reservation_ttl = Duration::from_secs(
+            config
+                .reservation_ttl
+                .unwrap_or(DEFAULT_RESERVATION_TTL_SECS),
+        );
+
+        Ok(Config {
             allowed_hosts,
             blocked_sub_domains,
             remote_port,
             control_port,
             internal_network_port,
-            master_sig_key,
-            gossip_dns_host,
+            sig_keys,
+            discovery,
+            cluster_key,
+            cluster_tls,
+            control_tls,
+            jwt,
             honeycomb_api_key,
             instance_id,
             blocked_ips,
             portal_host,
-        }
+            control_connection_limit,
+            max_tunnels_per_client,
+            tunnel_request_limit,
+            client_bandwidth_limit,
+            anonymous_policy,
+            max_anonymous_session,
+            shutdown_timeout,
+            admin_token,
+            admin_addr,
+            reservations_file,
+            error_pages,
+            maintenance_page_ttl,
+            stream_resume_grace,
+            pool_strategy,
+            max_reservations_per_account,
+            reservation_ttl,
+        })
     }
 }
 
@@ -131,8 +530,172 @@ impl Config {
         info!("loading config from file: {}", path);
         let config = std::fs::read_to_string(path)?;
         let config: InternalConfig = toml::from_str(&config)?;
+        let config = Config::try_from(config)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Check the settings that deserialize fine but make no sense
+    fn validate(&self) -> Result<(), String> {
+        let rate_limits = [
+            ("control_connection_limit", self.control_connection_limit),
+            ("tunnel_request_limit", self.tunnel_request_limit),
+            ("client_bandwidth_limit", self.client_bandwidth_limit),
+        ];
+
+        for (name, limit) in rate_limits {
+            if let Some(limit) = limit {
+                if limit.per_second <= 0.0 || limit.burst <= 0.0 {
+                    return Err(format!("{} must be positive", name));
+                }
+            }
+        }
+
+        if self.max_tunnels_per_client == Some(0) {
+            return Err("max_tunnels_per_client must be positive".to_string());
+        }
+        if self.reservation_ttl.is_zero() {
+            return Err("reservation_ttl must be positive".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Apply the reloadable settings of `new` on top of this config.
+    /// Returns the updated config and a description of what changed.
+    pub fn reload(&self, new: Config) -> (Config, Vec<String>) {
+        let mut config = self.clone();
+        let mut changes = vec![];
+
+        // the rest only take effect on restart
+        restart_field("remote_port", &self.remote_port, &new.remote_port);
+        restart_field("control_port", &self.control_port, &new.control_port);
+        restart_field(
+            "internal_network_port",
+            &self.internal_network_port,
+            &new.internal_network_port,
+        );
+        restart_field("discovery", &self.discovery, &new.discovery);
+        restart_field(
+            "cluster_key",
+            &self.cluster_key.as_ref().map(SigKey::id),
+            &new.cluster_key.as_ref().map(SigKey::id),
+        );
+        restart_field("cluster_tls", &self.cluster_tls, &new.cluster_tls);
+        restart_field("control_tls", &self.control_tls, &new.control_tls);
+        restart_field("jwt", &self.jwt, &new.jwt);
+        restart_field(
+            "honeycomb_api_key",
+            &self.honeycomb_api_key,
+            &new.honeycomb_api_key,
+        );
+        restart_field("portal_host", &self.portal_host, &new.portal_host);
+        restart_field("admin_token", &self.admin_token, &new.admin_token);
+        restart_field("admin_addr", &self.admin_addr, &new.admin_addr);
+        restart_field(
+            "reservations_file",
+            &self.reservations_file,
+            &new.reservations_file,
+        );
+        restart_field("error_pages", &self.error_pages, &new.error_pages);
+
+        reload_field(
+            "allowed_hosts",
+            &mut config.allowed_hosts,
+            new.allowed_hosts,
+            &mut changes,
+        );
+        reload_field(
+            "blocked_sub_domains",
+            &mut config.blocked_sub_domains,
+            new.blocked_sub_domains,
+            &mut changes,
+        );
+        reload_field(
+            "blocked_ips",
+            &mut config.blocked_ips,
+            new.blocked_ips,
+            &mut changes,
+        );
+        reload_field(
+            "control_connection_limit",
+            &mut config.control_connection_limit,
+            new.control_connection_limit,
+            &mut changes,
+        );
+        reload_field(
+            "max_tunnels_per_client",
+            &mut config.max_tunnels_per_client,
+            new.max_tunnels_per_client,
+            &mut changes,
+        );
+        reload_field(
+            "tunnel_request_limit",
+            &mut config.tunnel_request_limit,
+            new.tunnel_request_limit,
+            &mut changes,
+        );
+        reload_field(
+            "client_bandwidth_limit",
+            &mut config.client_bandwidth_limit,
+            new.client_bandwidth_limit,
+            &mut changes,
+        );
+        reload_field(
+            "anonymous_policy",
+            &mut config.anonymous_policy,
+            new.anonymous_policy,
+            &mut changes,
+        );
+        reload_field(
+            "max_anonymous_session",
+            &mut config.max_anonymous_session,
+            new.max_anonymous_session,
+            &mut changes,
+        );
+        reload_field(
+            "shutdown_timeout",
+            &mut config.shutdown_timeout,
+            new.shutdown_timeout,
+            &mut changes,
+        );
+        reload_field(
+            "stream_resume_grace",
+            &mut config.stream_resume_grace,
+            new.stream_resume_grace,
+            &mut changes,
+        );
+        reload_field(
+            "pool_strategy",
+            &mut config.pool_strategy,
+            new.pool_strategy,
+            &mut changes,
+        );
+        reload_field(
+            "maintenance_page_ttl",
+            &mut config.maintenance_page_ttl,
+            new.maintenance_page_ttl,
+            &mut changes,
+        );
+        reload_field(
+            "max_reservations_per_account",
+            &mut config.max_reservations_per_account,
+            new.max_reservations_per_account,
+            &mut changes,
+        );
+        reload_field(
+            "reservation_ttl",
+            &mut config.reservation_ttl,
+            new.reservation_ttl,
+            &mut changes,
+        );
+        // a random key ring would invalidate every token, keep ours instead
+        if !new.sig_keys.is_ephemeral() {
+            reload_field("sig_keys", &mut config.sig_keys, new.sig_keys, &mut changes);
+        }
 
-        Ok(Config::from(config))
+        (config, changes)
     }
 
     pub fn load_from_env() -> Config {
@@ -145,16 +708,83 @@ impl Config {
             .map(|s| s.split(',').map(String::from).collect())
             .unwrap_or_default();
 
-        let master_sig_key = if let Ok(key) = std::env::var("MASTER_SIG_KEY") {
-            SigKey::from_hex(&key).expect("invalid master key: not hex or length incorrect")
+        let sig_keys = if let Ok(files) = std::env::var("SIG_KEY_FILES") {
+            let files: Vec<&str> = files.split(',').collect();
+            SigKeyRing::from_files(&files).unwrap_or_else(|e| panic!("invalid ENV: {}", e))
+        } else if let Ok(key) = std::env::var("MASTER_SIG_KEY") {
+            let key =
+                SigKey::from_hex(&key).expect("invalid master key: not hex or length incorrect");
+            SigKeyRing::new(key, vec![])
         } else {
             tracing::warn!("WARNING! generating ephemeral signature key!");
-            SigKey::generate()
+            SigKeyRing::generate()
         };
 
-        let gossip_dns_host = std::env::var("FLY_APP_NAME")
-            .map(|app_name| format!("global.{}.internal", app_name))
-            .ok();
+        let discovery = match std::env::var("DISCOVERY") {
+            Ok(discovery) => Some(
+                discovery
+                    .parse()
+                    .unwrap_or_else(|e| panic!("invalid ENV: {}", e)),
+            ),
+            Err(_) => std::env::var("FLY_APP_NAME")
+                .map(|app_name| DiscoveryConfig::Dns {
+                    host: format!("global.{}.internal", app_name),
+                })
+                .ok(),
+        };
+
+        let cluster_key = std::env::var("CLUSTER_KEY").ok().map(|key| {
+            SigKey::from_hex(&key).expect("invalid cluster key: not hex or length incorrect")
+        });
+
+        let cluster_tls = match (
+            std::env::var("CLUSTER_TLS_CERT"),
+            std::env::var("CLUSTER_TLS_KEY"),
+            std::env::var("CLUSTER_TLS_CA"),
+        ) {
+            (Ok(cert), Ok(key), Ok(ca)) => Some(ClusterTlsConfig {
+                cert: cert.into(),
+                key: key.into(),
+                ca: ca.into(),
+                server_name: std::env::var("CLUSTER_TLS_SERVER_NAME")
+                    .unwrap_or_else(|_| default_cluster_server_name()),
+                port: get_port("CLUSTER_TLS_PORT", DEFAULT_CLUSTER_TLS_PORT),
+            }),
+            _ => None,
+        };
+
+        let control_tls = match (
+            std::env::var("CONTROL_TLS_CERT"),
+            std::env::var("CONTROL_TLS_KEY"),
+        ) {
+            (Ok(cert), Ok(key)) => Some(ControlTlsConfig {
+                cert: cert.into(),
+                key: key.into(),
+                client_ca: std::env::var("CONTROL_TLS_CLIENT_CA").ok().map(Into::into),
+                require_client_cert: std::env::var("CONTROL_TLS_REQUIRE_CLIENT_CERT").is_ok(),
+                identities: vec![],
+            }),
+            _ => None,
+        };
+
+        let jwt = std::env::var("JWT_JWKS").ok().map(|jwks| JwtConfig {
+            jwks,
+            issuer: std::env::var("JWT_ISSUER").ok(),
+            audience: std::env::var("JWT_AUDIENCE").ok(),
+            account_claim: std::env::var("JWT_ACCOUNT_CLAIM")
+                .unwrap_or_else(|_| default_account_claim()),
+            sub_domains_claim: std::env::var("JWT_SUB_DOMAINS_CLAIM")
+                .unwrap_or_else(|_| default_sub_domains_claim()),
+            limits_claim: std::env::var("JWT_LIMITS_CLAIM")
+                .unwrap_or_else(|_| default_limits_claim()),
+        });
+
+        let error_pages = ErrorPagesConfig {
+            brand: std::env::var("ERROR_PAGE_BRAND").unwrap_or_else(|_| default_brand()),
+            homepage: std::env::var("HOMEPAGE_URL").unwrap_or_else(|_| default_homepage()),
+            template: std::env::var("ERROR_PAGE_TEMPLATE").ok().map(Into::into),
+            request_id: std::env::var("ERROR_PAGE_REQUEST_ID").is_ok(),
+        };
 
         let honeycomb_api_key = std::env::var("HONEYCOMB_API_KEY").ok();
         let instance_id = std::env::var("FLY_ALLOC_ID").unwrap_or(Uuid::new_v4().to_string());
@@ -167,7 +797,75 @@ impl Config {
             })
             .unwrap_or_default();
 
-        let portal_host = std::env::var("PORTAL_HOST").unwrap_or("portal.illusiontech.cn".to_string());
+        let portal_host =
+            std::env::var("PORTAL_HOST").unwrap_or("portal.illusiontech.cn".to_string());
+
+        let max_tunnels_per_client = std::env::var("MAX_TUNNELS_PER_CLIENT").ok().map(|max| {
+            max.parse().unwrap_or_else(|_| {
+                panic!("invalid ENV MAX_TUNNELS_PER_CLIENT={}", max);
+            })
+        });
+
+        let anonymous_policy = std::env::var("ANONYMOUS_POLICY")
+            .map(|policy| {
+                policy
+                    .parse()
+                    .unwrap_or_else(|e| panic!("invalid ENV: {}", e))
+            })
+            .unwrap_or(AnonymousPolicy::Prefixed);
+
+        let max_anonymous_session = std::env::var("MAX_ANONYMOUS_SESSION").ok().map(|secs| {
+            Duration::from_secs(secs.parse().unwrap_or_else(|_| {
+                panic!("invalid ENV MAX_ANONYMOUS_SESSION={}", secs);
+            }))
+        });
+
+        let shutdown_timeout = std::env::var("SHUTDOWN_TIMEOUT")
+            .map(|secs| {
+                secs.parse().unwrap_or_else(|_| {
+                    panic!("invalid ENV SHUTDOWN_TIMEOUT={}", secs);
+                })
+            })
+            .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS);
+
+        let stream_resume_grace = std::env::var("STREAM_RESUME_GRACE")
+            .map(|secs| {
+                secs.parse().unwrap_or_else(|_| {
+                    panic!("invalid ENV STREAM_RESUME_GRACE={}", secs);
+                })
+            })
+            .unwrap_or(portal_lib::STREAM_RESUME_GRACE);
+
+        let maintenance_page_ttl = std::env::var("MAINTENANCE_PAGE_TTL")
+            .map(|secs| {
+                secs.parse().unwrap_or_else(|_| {
+                    panic!("invalid ENV MAINTENANCE_PAGE_TTL={}", secs);
+                })
+            })
+            .unwrap_or(DEFAULT_MAINTENANCE_PAGE_TTL_SECS);
+
+        let max_reservations_per_account = std::env::var("MAX_RESERVATIONS_PER_ACCOUNT")
+            .map(|max| {
+                max.parse().unwrap_or_else(|_| {
+                    panic!("invalid ENV MAX_RESERVATIONS_PER_ACCOUNT={}", max);
+                })
+            })
+            .unwrap_or(DEFAULT_MAX_RESERVATIONS_PER_ACCOUNT);
+
+        let reservation_ttl = std::env::var("RESERVATION_TTL")
+            .map(|secs| match secs.parse() {
+                Ok(secs) if secs > 0 => secs,
+                _ => panic!("invalid ENV RESERVATION_TTL={}", secs),
+            })
+            .unwrap_or(DEFAULT_RESERVATION_TTL_SECS);
+
+        let pool_strategy = std::env::var("POOL_STRATEGY")
+            .map(|strategy| {
+                strategy
+                    .parse()
+                    .unwrap_or_else(|e| panic!("invalid ENV: {}", e))
+            })
+            .unwrap_or_default();
 
         Config {
             allowed_hosts,
@@ -175,16 +873,57 @@ impl Config {
             control_port: get_port("CTRL_PORT", 5000),
             remote_port: get_port("PORT", 8080),
             internal_network_port: get_port("NET_PORT", 6000),
-            master_sig_key,
-            gossip_dns_host,
+            sig_keys,
+            discovery,
+            cluster_key,
+            cluster_tls,
+            control_tls,
+            jwt,
             honeycomb_api_key,
             instance_id,
             blocked_ips,
             portal_host,
+            control_connection_limit: get_rate_limit("CONTROL_CONNECTION_LIMIT"),
+            max_tunnels_per_client,
+            tunnel_request_limit: get_rate_limit("TUNNEL_REQUEST_LIMIT"),
+            client_bandwidth_limit: get_rate_limit("CLIENT_BANDWIDTH_LIMIT"),
+            anonymous_policy,
+            max_anonymous_session,
+            shutdown_timeout: Duration::from_secs(shutdown_timeout),
+            admin_token: std::env::var("ADMIN_TOKEN").ok(),
+            admin_addr: std::env::var("ADMIN_ADDR").ok().map(|addr| {
+                addr.parse()
+                    .unwrap_or_else(|_| panic!("invalid ENV ADMIN_ADDR={}", addr))
+            }),
+            reservations_file: std::env::var("RESERVATIONS_FILE").ok().map(PathBuf::from),
+            error_pages,
+            maintenance_page_ttl: Duration::from_secs(maintenance_page_ttl),
+            stream_resume_grace: Duration::from_secs(stream_resume_grace),
+            pool_strategy,
+            max_reservations_per_account,
+            reservation_ttl: Duration::from_secs(reservation_ttl),
         }
     }
 }
 
+fn reload_field<T: PartialEq + Debug>(
+    name: &str,
+    current: &mut T,
+    new: T,
+    changes: &mut Vec<String>,
+) {
+    if *current != new {
+        changes.push(format!("{}: {:?} => {:?}", name, current, new));
+        *current = new;
+    }
+}
+
+fn restart_field<T: PartialEq>(name: &str, current: &T, new: &T) {
+    if current != new {
+        warn!(field = name, "config change requires restart, ignoring it");
+    }
+}
+
 fn get_port(var: &'static str, default: u16) -> u16 {
     match std::env::var(var) {
         Ok(port) => port.parse().unwrap_or_else(|_| {
@@ -194,6 +933,15 @@ fn get_port(var: &'static str, default: u16) -> u16 {
     }
 }
 
+/// parse a rate limit ENV of the form `<per_second>:<burst>`
+fn get_rate_limit(var: &'static str) -> Option<RateLimit> {
+    std::env::var(var).ok().map(|limit| {
+        limit.parse().unwrap_or_else(|e| {
+            panic!("invalid rate limit ENV {}={}: {}", var, limit, e);
+        })
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -205,4 +953,28 @@ mod tests {
         let config = Config::load_from_file("tests/config.toml").unwrap();
         println!("config from file: {:?}", config);
     }
+
+    #[test]
+    fn test_reload_config() {
+        let current = Config::load_from_file("tests/config.toml").unwrap();
+        let mut new = current.clone();
+        new.blocked_sub_domains = vec!["dashboard".to_string()];
+        new.remote_port = 9999;
+
+        let (reloaded, changes) = current.reload(new);
+        assert_eq!(reloaded.blocked_sub_domains, vec!["dashboard".to_string()]);
+        assert_eq!(reloaded.remote_port, current.remote_port);
+        assert_eq!(changes.len(), 1);
+
+        // rotate the signature keys, but never to a random one
+        let mut new = reloaded.clone();
+        new.sig_keys = SigKeyRing::generate();
+        let (reloaded, changes) = reloaded.reload(new);
+        assert!(changes.is_empty());
+
+        let mut new = reloaded.clone();
+        new.sig_keys = SigKeyRing::new(SigKey::generate(), vec![]);
+        let (_, changes) = reloaded.reload(new);
+        assert_eq!(changes.len(), 1);
+    }
 }
@@ -1,12 +1,29 @@
 use super::*;
+use crate::auth::AccountLimits;
+use crate::config::PoolStrategy;
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
 use std::fmt::Formatter;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use uuid::Uuid;
 
 #[derive(Clone)]
 pub struct ConnectedClient {
     pub id: ClientId,
     pub host: String,
     pub is_anonymous: bool,
+    pub limits: AccountLimits,
+    pub session_expires: Option<DateTime<Utc>>,
+    /// share the host with other connections of this client
+    pub pool: bool,
+    /// understands `ControlPacket::Reset`, older clients only know `End`
+    pub supports_reset: bool,
+    /// the same across reconnects of this connection, so they resume its streams
+    pub session_id: Uuid,
+    /// when the client last authenticated, reconnects keep it
+    pub authenticated: DateTime<Utc>,
+    /// how many streams opened on this connection are still open
+    pub open_streams: Arc<AtomicUsize>,
     pub tx: UnboundedSender<ControlPacket>,
 }
 
@@ -16,19 +33,64 @@ impl std::fmt::Debug for ConnectedClient {
             .field("id", &self.id)
             .field("sub", &self.host)
             .field("anon", &self.is_anonymous)
+            .field("pool", &self.pool)
             .finish()
     }
 }
 
+impl ConnectedClient {
+    /// how long until the session of this client is over, if it ever is
+    pub fn session_remaining(&self, now: DateTime<Utc>) -> Option<std::time::Duration> {
+        self.session_expires
+            .map(|expires| (expires - now).to_std().unwrap_or_default())
+    }
+}
+
+/// The clients serving a host: a single one, or the members of a pool
+#[derive(Default)]
+struct HostClients {
+    members: Vec<ConnectedClient>,
+    /// where round robin picks next
+    next: usize,
+}
+
+impl HostClients {
+    /// pick the member to serve a new stream
+    fn pick(&mut self, strategy: PoolStrategy) -> Option<ConnectedClient> {
+        if self.members.len() < 2 {
+            return self.members.first().cloned();
+        }
+
+        match strategy {
+            PoolStrategy::RoundRobin => {
+                self.next = (self.next + 1) % self.members.len();
+                self.members.get(self.next).cloned()
+            }
+            PoolStrategy::LeastStreams => self
+                .members
+                .iter()
+                .min_by_key(|member| member.open_streams.load(Ordering::Relaxed))
+                .cloned(),
+        }
+    }
+
+    /// a pool only takes pool members of its account, a single tunnel no pool,
+    /// except for reconnects of a member
+    fn conflicts(&self, client_id: &ClientId, pool: bool, session_id: Option<Uuid>) -> bool {
+        self.members
+            .iter()
+            .filter(|c| Some(c.session_id) != session_id)
+            .any(|c| c.pool != pool || (pool && &c.id != client_id))
+    }
+}
+
 pub struct Connections {
-    clients: Arc<DashMap<ClientId, ConnectedClient>>,
-    hosts: Arc<DashMap<String, ConnectedClient>>,
+    hosts: Arc<DashMap<String, HostClients>>,
 }
 
 impl Default for Connections {
     fn default() -> Self {
         Self {
-            clients: Arc::new(DashMap::new()),
             hosts: Arc::new(DashMap::new()),
         }
     }
@@ -39,59 +101,217 @@ impl Connections {
         Self::default()
     }
 
-    pub fn update_host(client: &ConnectedClient) {
-        get_connections()
-            .hosts
-            .insert(client.host.clone(), client.clone());
-    }
-
     pub fn remove(client: &ConnectedClient) {
         client.tx.close_channel();
 
         let connections = get_connections();
-        // ensure another client isn't using this host
-        if connections
-            .hosts
-            .get(&client.host)
-            .map_or(false, |c| c.id == client.id)
-        {
-            tracing::debug!("dropping sub-domain: {}", &client.host);
-            connections.hosts.remove(&client.host);
+        // other clients (or newer connections of this one) may still serve this host
+        let (is_empty, is_replaced) = match connections.hosts.get_mut(&client.host) {
+            Some(mut host) => {
+                host.members.retain(|c| !c.tx.same_receiver(&client.tx));
+                let is_replaced = host
+                    .members
+                    .iter()
+                    .any(|c| c.session_id == client.session_id);
+                (host.members.is_empty(), is_replaced)
+            }
+            None => (false, false),
         };
 
-        connections.clients.remove(&client.id);
+        if is_empty
+            && connections
+                .hosts
+                .remove_if(&client.host, |_, host| host.members.is_empty())
+                .is_some()
+        {
+            tracing::debug!("dropping sub-domain: {}", &client.host);
+            crate::network::spawn_release(client.host.clone(), client.id.clone());
+        }
+        // a reconnect of this connection took its page over already
+        if !is_replaced {
+            get_maintenance_pages().expire(
+                &client.host,
+                client.session_id,
+                get_config().maintenance_page_ttl,
+            );
+        }
         tracing::debug!("rm client: {}", &client.id);
 
-        // // drop all the streams
-        // // if there are no more tunnel clients
-        // if CONNECTIONS.clients.is_empty() {
-        //     let mut streams = ACTIVE_STREAMS.;
-        //     for (_, stream) in streams.drain() {
-        //         stream.tx.close_channel();
-        //     }
-        // }
+        ActiveStream::hold_for_resume(client);
     }
 
     pub fn client_for_host(host: &String) -> Option<ClientId> {
-        get_connections().hosts.get(host).map(|c| c.id.clone())
+        get_connections()
+            .hosts
+            .get(host)
+            .and_then(|host| host.members.first().map(|c| c.id.clone()))
     }
 
-    pub fn get(client_id: &ClientId) -> Option<ConnectedClient> {
+    /// count the tunnels this client holds, other than `host`
+    pub fn tunnel_count(client_id: &ClientId, host: &str) -> usize {
         get_connections()
-            .clients
-            .get(client_id)
-            .map(|c| c.value().clone())
+            .hosts
+            .iter()
+            .filter(|h| h.key() != host && h.members.iter().any(|c| &c.id == client_id))
+            .count()
     }
 
+    /// find the client to serve a new stream for this host
     pub fn find_by_host(host: &String) -> Option<ConnectedClient> {
-        get_connections().hosts.get(host).map(|c| c.value().clone())
+        get_connections()
+            .hosts
+            .get_mut(host)?
+            .pick(get_config().pool_strategy)
+    }
+
+    /// every host we serve, and the client serving it
+    pub fn hosts() -> Vec<(String, ClientId)> {
+        get_connections()
+            .hosts
+            .iter()
+            .filter_map(|h| h.members.first().map(|c| (h.key().clone(), c.id.clone())))
+            .collect()
     }
 
-    pub fn add(client: ConnectedClient) {
+    pub fn all() -> Vec<ConnectedClient> {
+        get_connections()
+            .hosts
+            .iter()
+            .flat_map(|h| h.members.clone())
+            .collect()
+    }
+
+    /// whether a new connection would conflict with the ones serving its host
+    pub fn conflicts(
+        client_id: &ClientId,
+        host: &str,
+        pool: bool,
+        session_id: Option<Uuid>,
+    ) -> bool {
+        get_connections()
+            .hosts
+            .get(host)
+            .is_some_and(|h| h.conflicts(client_id, pool, session_id))
+    }
+
+    /// serve the host of this client, false if it conflicts with the ones serving it
+    pub fn add(client: ConnectedClient) -> bool {
         let connections = get_connections();
-        connections
-            .clients
-            .insert(client.id.clone(), client.clone());
-        connections.hosts.insert(client.host.clone(), client);
+        let mut host = connections.hosts.entry(client.host.clone()).or_default();
+        if host.members.iter().any(|c| c.tx.same_receiver(&client.tx)) {
+            return true;
+        }
+        if host.conflicts(&client.id, client.pool, Some(client.session_id)) {
+            return false;
+        }
+
+        // join the pool, otherwise take over the host
+        if client.pool {
+            host.members.retain(|c| c.session_id != client.session_id);
+        } else {
+            host.members.clear();
+        }
+        host.members.push(client);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(pool: bool) -> ConnectedClient {
+        let (tx, _) = futures::channel::mpsc::unbounded();
+        ConnectedClient {
+            id: ClientId::generate(),
+            host: "foo".into(),
+            is_anonymous: false,
+            limits: AccountLimits::default(),
+            session_expires: None,
+            pool,
+            supports_reset: true,
+            session_id: Uuid::new_v4(),
+            authenticated: Utc::now(),
+            open_streams: Arc::default(),
+            tx,
+        }
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let members = vec![client(true), client(true), client(true)];
+        let mut host = HostClients {
+            members: members.clone(),
+            next: 0,
+        };
+
+        let picked: Vec<_> = (0..6)
+            .map(|_| host.pick(PoolStrategy::RoundRobin).unwrap())
+            .collect();
+        for (i, client) in picked.iter().enumerate() {
+            assert!(client.tx.same_receiver(&members[(i + 1) % 3].tx));
+        }
+    }
+
+    #[test]
+    fn test_least_streams() {
+        let members = vec![client(true), client(true)];
+        let mut host = HostClients {
+            members: members.clone(),
+            next: 0,
+        };
+
+        let (first, _) = ActiveStream::new(host.pick(PoolStrategy::LeastStreams).unwrap());
+        let (second, _) = ActiveStream::new(host.pick(PoolStrategy::LeastStreams).unwrap());
+        assert!(!first.client.tx.same_receiver(&second.client.tx));
+
+        // the member whose stream closed is picked next
+        let closed = first.client.clone();
+        drop(first);
+        let picked = host.pick(PoolStrategy::LeastStreams).unwrap();
+        assert!(picked.tx.same_receiver(&closed.tx));
+    }
+
+    #[test]
+    fn test_pool_conflicts() {
+        let single = client(false);
+        let host = HostClients {
+            members: vec![single.clone()],
+            next: 0,
+        };
+        // a pool can't join a single tunnel, another single one takes over
+        assert!(host.conflicts(&single.id, true, None));
+        assert!(!host.conflicts(&single.id, false, None));
+
+        let member = client(true);
+        let host = HostClients {
+            members: vec![member.clone()],
+            next: 0,
+        };
+        assert!(host.conflicts(&member.id, false, None));
+        assert!(host.conflicts(&ClientId::generate(), true, None));
+        assert!(!host.conflicts(&member.id, true, None));
+        // a reconnect replaces its own connection
+        assert!(!host.conflicts(&member.id, false, Some(member.session_id)));
+    }
+
+    #[test]
+    fn test_session_remaining() {
+        let now = Utc::now();
+        let mut client = client(false);
+        assert_eq!(client.session_remaining(now), None);
+
+        client.session_expires = Some(now + chrono::Duration::minutes(5));
+        assert_eq!(
+            client.session_remaining(now),
+            Some(std::time::Duration::from_secs(300))
+        );
+
+        // an expired session closes right away
+        client.session_expires = Some(now - chrono::Duration::minutes(5));
+        assert_eq!(
+            client.session_remaining(now),
+            Some(std::time::Duration::ZERO)
+        );
     }
 }
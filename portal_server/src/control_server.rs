@@ -1,41 +1,208 @@
 pub use super::*;
 use crate::auth::reconnect_token::ReconnectTokenPayload;
 use crate::client_auth::ClientHandshake;
+use crate::config::CertIdentity;
 use chrono::Utc;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::time::Duration;
+use subtle::ConstantTimeEq;
 use tracing::{error, info, warn, Instrument};
-use warp::Rejection;
+use warp::http::StatusCode;
+use warp::hyper::server::conn::Http;
+use warp::{Rejection, Reply};
 
 pub fn spawn<A: Into<SocketAddr>>(addr: A) {
+    let addr = addr.into();
+
+    // spawn our websocket control server
+    match get_control_tls() {
+        Some(tls) => {
+            tokio::spawn(serve_tls(tls, addr));
+        }
+        None => {
+            tokio::spawn(warp::serve(routes(None)).run(addr));
+        }
+    }
+}
+
+/// How long a client may take to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The other end of a control connection we terminate TLS for
+#[derive(Debug, Clone)]
+struct TlsPeer {
+    addr: SocketAddr,
+    /// who the client certificate says it is
+    identity: Option<CertIdentity>,
+}
+
+async fn serve_tls(tls: &'static crate::control_tls::ControlTls, addr: SocketAddr) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(error) => {
+            error!(?error, %addr, "failed to bind control port");
+            return;
+        }
+    };
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(s) => s,
+            Err(error) => {
+                error!(?error, "failed to accept control connection");
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            let (stream, identity) =
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+                    Ok(Ok(accepted)) => accepted,
+                    Ok(Err(error)) => {
+                        warn!(%peer, %error, "rejected control connection");
+                        return;
+                    }
+                    Err(_) => {
+                        warn!(%peer, "control connection timed out during the tls handshake");
+                        return;
+                    }
+                };
+
+            let service = warp::service(routes(Some(TlsPeer {
+                addr: peer,
+                identity,
+            })));
+            if let Err(error) = Http::new()
+                .serve_connection(stream, service)
+                .with_upgrades()
+                .await
+            {
+                tracing::debug!(?error, "control connection closed");
+            }
+        });
+    }
+}
+
+fn routes(
+    tls_peer: Option<TlsPeer>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static {
     let health_check = warp::get().and(warp::path("health_check")).map(|| {
         tracing::debug!("Health Check #2 triggered");
-        "ok"
+        if shutdown::is_shutting_down() {
+            warp::reply::with_status("shutting down", StatusCode::SERVICE_UNAVAILABLE)
+        } else {
+            warp::reply::with_status("ok", StatusCode::OK)
+        }
     });
 
-    let client_conn = warp::path("wormhole").and(client_ip()).and(warp::ws()).map(
-        move |client_ip: IpAddr, ws: Ws| {
+    let peer_addr = tls_peer.as_ref().map(|peer| peer.addr);
+    let identity = tls_peer.and_then(|peer| peer.identity);
+    let domains = crate::domains::routes(identity.clone());
+    let client_conn = warp::path("wormhole")
+        .and(client_ip(peer_addr))
+        .and(warp::ws())
+        .map(move |client_ip: IpAddr, ws: Ws| {
+            if shutdown::is_shutting_down() {
+                return warp::reply::with_status(
+                    "Service Unavailable",
+                    StatusCode::SERVICE_UNAVAILABLE,
+                )
+                .into_response();
+            }
+
+            if !get_rate_limiters().allow_control_connection(&client_ip) {
+                warn!(
+                    ?client_ip,
+                    "too many control connections, denying connection"
+                );
+                return warp::reply::with_status(
+                    "Too Many Requests",
+                    StatusCode::TOO_MANY_REQUESTS,
+                )
+                .into_response();
+            }
+
+            let identity = identity.clone();
             ws.on_upgrade(move |w| {
-                async move { handle_new_connection(client_ip, w).await }
+                async move { handle_new_connection(client_ip, identity, w).await }
                     .instrument(observability::remote_trace("handle_websocket"))
             })
-        },
-    );
+            .into_response()
+        });
+
+    // unless they have a listener of their own
+    let admin = warp::any()
+        .and_then(|| async {
+            match get_config().admin_addr {
+                None => Ok(()),
+                Some(_) => Err(warp::reject::not_found()),
+            }
+        })
+        .untuple_one()
+        .and(admin_routes());
 
-    let routes = client_conn.or(health_check);
+    client_conn.or(health_check).or(admin).or(domains)
+}
 
-    // spawn our websocket control server
-    tokio::spawn(warp::serve(routes).run(addr.into()));
+/// Serve the admin endpoints on their own listener, i.e. an internal address
+pub fn spawn_admin(addr: SocketAddr) {
+    tokio::spawn(warp::serve(admin_routes()).run(addr));
 }
 
-fn client_ip() -> impl Filter<Extract = (IpAddr,), Error = Rejection> + Copy {
+fn admin_routes(
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static {
+    warp::post()
+        .and(warp::path!("admin" / "reload"))
+        .and(admin_auth())
+        .map(|| match crate::reload::reload() {
+            Ok(changes) => warp::reply::with_status(warp::reply::json(&changes), StatusCode::OK),
+            Err(error) => {
+                error!(%error, "failed to reload config");
+                warp::reply::with_status(warp::reply::json(&error), StatusCode::BAD_REQUEST)
+            }
+        })
+}
+
+/// Only allow requests bearing the configured admin token
+fn admin_auth() -> impl Filter<Extract = (), Error = Rejection> + Copy {
+    warp::header::optional::<String>("authorization")
+        .and_then(|auth: Option<String>| async move {
+            let expected = get_config()
+                .admin_token
+                .as_ref()
+                .map(|token| format!("Bearer {}", token));
+
+            match (auth, expected) {
+                (Some(auth), Some(expected))
+                    if bool::from(auth.as_bytes().ct_eq(expected.as_bytes())) =>
+                {
+                    Ok(())
+                }
+                _ => {
+                    warn!("unauthorized admin request");
+                    Err(warp::reject::not_found())
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// The ip of the client. On connections we terminate TLS for it's `peer_addr`:
+/// nothing sits in front of us, so the forwarding headers are the client's own
+fn client_ip(
+    peer_addr: Option<SocketAddr>,
+) -> impl Filter<Extract = (IpAddr,), Error = Rejection> + Copy {
     warp::any()
         .and(warp::header::optional("Fly-Client-IP"))
         .and(warp::header::optional("X-Forwarded-For"))
         .and(warp::addr::remote())
         .map(
-            |client_ip: Option<String>, fwd: Option<String>, remote: Option<SocketAddr>| {
+            move |client_ip: Option<String>, fwd: Option<String>, remote: Option<SocketAddr>| {
+                if let Some(peer_addr) = peer_addr {
+                    return peer_addr.ip();
+                }
+
                 let client_ip = client_ip.and_then(|s| IpAddr::from_str(&s).ok());
                 let fwd = fwd.and_then(|s| {
                     s.split(',')
@@ -53,7 +220,11 @@ fn client_ip() -> impl Filter<Extract = (IpAddr,), Error = Rejection> + Copy {
 }
 
 #[tracing::instrument(skip(websocket))]
-async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
+async fn handle_new_connection(
+    client_ip: IpAddr,
+    identity: Option<CertIdentity>,
+    websocket: WebSocket,
+) {
     let config = get_config();
     // check if this client is blocked
     if config.blocked_ips.contains(&client_ip) {
@@ -62,7 +233,7 @@ async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
         return;
     }
 
-    let (websocket, handshake) = match try_client_handshake(websocket).await {
+    let (websocket, handshake) = match try_client_handshake(websocket, identity).await {
         Some(ws) => ws,
         None => return,
     };
@@ -70,13 +241,48 @@ async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
     info!(client_ip=%client_ip, subdomain=%handshake.sub_domain, "open tunnel");
 
     let (tx, rx) = unbounded::<ControlPacket>();
+    let session_id = handshake.session_id.unwrap_or_else(uuid::Uuid::new_v4);
+    // streams resumed from the replaced connection still count towards this one
+    let open_streams = get_active_streams()
+        .iter()
+        .find(|s| s.client.session_id == session_id)
+        .map(|s| s.client.open_streams.clone())
+        .unwrap_or_default();
     let mut client = ConnectedClient {
         id: handshake.id,
         host: handshake.sub_domain,
         is_anonymous: handshake.is_anonymous,
+        limits: handshake.limits,
+        session_expires: handshake.session_expires,
+        pool: handshake.pool,
+        supports_reset: handshake.supports_reset,
+        session_id,
+        authenticated: handshake.authenticated,
+        open_streams,
         tx,
     };
-    Connections::add(client.clone());
+    if !Connections::add(client.clone()) {
+        warn!(client_id=%client.id, host=%client.host, "host taken by a conflicting tunnel");
+        return;
+    }
+    get_maintenance_pages().set(
+        &client.host,
+        &client.id,
+        client.session_id,
+        handshake.maintenance_page,
+    );
+
+    // ask the client to resume the streams of the connection it replaces,
+    // a new connection of the same account has none
+    for stream in get_active_streams()
+        .iter()
+        .filter(|s| s.client.session_id == client.session_id)
+    {
+        let received = stream.detach();
+        let _ = client
+            .tx
+            .unbounded_send(ControlPacket::Resume(stream.id.clone(), received));
+    }
 
     let (sink, stream) = websocket.split();
 
@@ -98,25 +304,22 @@ async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
         .instrument(observability::remote_trace("process_client")),
     );
 
-    // play ping pong
+    // play ping pong, until the client disconnects or its session is over
+    let session_remaining = client.session_remaining(Utc::now());
     tokio::spawn(
         async move {
+            let session_over = async move {
+                match session_remaining {
+                    Some(remaining) => tokio::time::sleep(remaining).await,
+                    None => futures::future::pending().await,
+                }
+            };
+            tokio::pin!(session_over);
+
             loop {
                 tracing::trace!("sending ping");
 
-                // create a new reconnect token for anonymous clients
-                let reconnect_token = if client.is_anonymous {
-                    ReconnectTokenPayload {
-                        sub_domain: client.host.clone(),
-                        client_id: client.id.clone(),
-                        expires: Utc::now() + chrono::Duration::minutes(2),
-                    }
-                    .to_token(&config.master_sig_key)
-                    .map_err(|e| error!("unable to create reconnect token: {:?}", e))
-                    .ok()
-                } else {
-                    None
-                };
+                let reconnect_token = reconnect_token(&client);
 
                 match client.tx.send(ControlPacket::Ping(reconnect_token)).await {
                     Ok(_) => {}
@@ -127,17 +330,112 @@ async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
                     }
                 };
 
-                tokio::time::sleep(Duration::new(PING_INTERVAL, 0)).await;
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::new(PING_INTERVAL, 0)) => {}
+                    _ = &mut session_over => {
+                        info!(client_id=%client.id, "session expired, closing tunnel");
+                        Connections::remove(&client);
+                        return;
+                    }
+                }
             }
         }
         .instrument(observability::remote_trace("control_ping")),
     );
 }
 
+/// create a new reconnect token for this client
+pub fn reconnect_token(client: &ConnectedClient) -> Option<ReconnectToken> {
+    ReconnectTokenPayload {
+        sub_domain: client.host.clone(),
+        client_id: client.id.clone(),
+        expires: Utc::now() + chrono::Duration::minutes(2),
+        session_expires: client.session_expires,
+        is_anonymous: client.is_anonymous,
+        limits: client.limits.clone(),
+        session_id: Some(client.session_id),
+        authenticated: Some(client.authenticated),
+    }
+    .to_token(&get_config().sig_keys)
+    .map_err(|e| error!("unable to create reconnect token: {:?}", e))
+    .ok()
+}
+
 #[tracing::instrument(skip(websocket))]
-async fn try_client_handshake(websocket: WebSocket) -> Option<(WebSocket, ClientHandshake)> {
+async fn try_client_handshake(
+    websocket: WebSocket,
+    identity: Option<CertIdentity>,
+) -> Option<(WebSocket, ClientHandshake)> {
     // Authenticate client handshake
-    let (mut websocket, client_handshake) = client_auth::auth_client_handshake(websocket).await?;
+    let (mut websocket, client_handshake) =
+        client_auth::auth_client_handshake(websocket, identity).await?;
+
+    // enforce the concurrent tunnel limit for this client
+    let max_tunnels = client_handshake
+        .limits
+        .max_tunnels
+        .or(get_config().max_tunnels_per_client);
+    if let Some(max_tunnels) = max_tunnels {
+        if Connections::tunnel_count(&client_handshake.id, &client_handshake.sub_domain)
+            >= max_tunnels
+        {
+            warn!(client_id=%client_handshake.id, "too many tunnels for client");
+            let data = serde_json::to_vec(&ServerHello::Error(format!(
+                "Too many tunnels: at most {} can be open at once.",
+                max_tunnels
+            )))
+            .unwrap_or_default();
+            let _ = websocket.send(Message::binary(data)).await;
+            return None;
+        }
+    }
+
+    // pool and single tunnels don't share a host
+    if Connections::conflicts(
+        &client_handshake.id,
+        &client_handshake.sub_domain,
+        client_handshake.pool,
+        client_handshake.session_id,
+    ) {
+        warn!(client_id=%client_handshake.id, "pool mode conflicts with the tunnels on this host");
+        let message = if client_handshake.pool {
+            format!(
+                "{} is served by a single tunnel, close it to open a pool there.",
+                client_handshake.sub_domain
+            )
+        } else {
+            format!(
+                "{} is served by a pool of tunnels, connect with --pool to join it.",
+                client_handshake.sub_domain
+            )
+        };
+        let data = serde_json::to_vec(&ServerHello::Error(message)).unwrap_or_default();
+        let _ = websocket.send(Message::binary(data)).await;
+        return None;
+    }
+
+    // lease the sub-domain across the cluster, so no other instance hands it out
+    let claim = get_routes()
+        .claim(&client_handshake.sub_domain, &client_handshake.id)
+        .await;
+    let server_hello = match claim {
+        Ok(None) => None,
+        Ok(Some(holder)) => {
+            warn!(sub_domain=%client_handshake.sub_domain, holder=%holder.client_id, "sub-domain already claimed");
+            Some(ServerHello::SubDomainInUse)
+        }
+        Err(error) => {
+            error!(?error, "failed to claim sub-domain");
+            Some(ServerHello::Error(
+                "Unable to reserve sub-domain, please try again.".into(),
+            ))
+        }
+    };
+    if let Some(server_hello) = server_hello {
+        let data = serde_json::to_vec(&server_hello).unwrap_or_default();
+        let _ = websocket.send(Message::binary(data)).await;
+        return None;
+    }
 
     // Send server hello success
     let data = serde_json::to_vec(&ServerHello::Success {
@@ -148,6 +446,7 @@ async fn try_client_handshake(websocket: WebSocket) -> Option<(WebSocket, Client
             get_config().portal_host
         ),
         client_id: client_handshake.id.clone(),
+        stream_resume_grace: Some(get_config().stream_resume_grace.as_secs()),
     })
     .unwrap_or_default();
 
@@ -170,20 +469,12 @@ async fn try_client_handshake(websocket: WebSocket) -> Option<(WebSocket, Client
 }
 
 /// Send the client a "stream init" message
-pub async fn send_client_stream_init(mut stream: ActiveStream) {
-    match stream
-        .client
-        .tx
-        .send(ControlPacket::Init(stream.id.clone()))
-        .await
-    {
-        Ok(_) => {
-            tracing::debug!("sent control to client: {}", &stream.client.id);
-        }
-        Err(_) => {
-            tracing::debug!("removing disconnected client: {}", &stream.client.id);
-            Connections::remove(&stream.client);
-        }
+pub async fn send_client_stream_init(stream: ActiveStream) {
+    if stream.send(ControlPacket::Init(stream.id.clone())) {
+        tracing::debug!("sent control to client: {}", &stream.client.id);
+    } else {
+        tracing::debug!("removing disconnected client: {}", &stream.client.id);
+        Connections::remove(&stream.client);
     }
 }
 
@@ -222,19 +513,51 @@ async fn process_client_messages(client: ConnectedClient, mut client_conn: Split
         let (stream_id, message) = match packet {
             ControlPacket::Data(stream_id, data) => {
                 tracing::debug!(?stream_id, num_bytes=?data.len(),"forwarding to stream");
+                // drop data from a connection the stream was resumed away from
+                let is_current = get_active_streams()
+                    .get(&stream_id)
+                    .is_some_and(|stream| stream.receive(&client.tx, data.len()));
+                if !is_current {
+                    tracing::debug!(?stream_id, "dropping data from stale connection");
+                    continue;
+                }
+
                 (stream_id, StreamMessage::Data(data))
             }
-            ControlPacket::Refused(stream_id) => {
-                tracing::debug!("tunnel says: refused");
-                (stream_id, StreamMessage::TunnelRefused)
+            ControlPacket::Resume(stream_id, offset) => {
+                let stream = get_active_streams()
+                    .get(&stream_id)
+                    .map(|s| s.value().clone());
+
+                if let Some(stream) = stream.filter(|s| s.client.session_id == client.session_id) {
+                    if !stream.resume(client.tx.clone(), offset) {
+                        tracing::debug!(?stream_id, "unable to resume stream, closing");
+                        stream.tx.close_channel();
+                    }
+                }
+                continue;
+            }
+            ControlPacket::Reset(stream_id, reason) => {
+                tracing::debug!(?stream_id, ?reason, "tunnel says: reset");
+                (stream_id, StreamMessage::Reset(reason))
             }
-            ControlPacket::Init(_) | ControlPacket::End(_) => {
+            ControlPacket::End(stream_id) => {
+                tracing::debug!(?stream_id, "tunnel says: end");
+                // the client is done sending: flush what we have, then half-close
+                if let Some(stream) = get_active_streams().get(&stream_id) {
+                    stream.tx.close_channel();
+                }
+                continue;
+            }
+            ControlPacket::Init(_) | ControlPacket::Reconnect(_) => {
                 error!("invalid protocol control::init message");
                 continue;
             }
             ControlPacket::Ping(_) => {
                 tracing::trace!("pong");
-                Connections::add(client.clone());
+                if !Connections::add(client.clone()) {
+                    warn!(client_id=%client.id, host=%client.host, "host taken by a conflicting tunnel");
+                }
                 continue;
             }
         };
@@ -269,8 +592,32 @@ async fn tunnel_client(
             }
             None => {
                 tracing::debug!("ending client tunnel");
+                let _ = sink.close().await;
                 return;
             }
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_client_ip() {
+        let peer: SocketAddr = "203.0.113.7:4000".parse().unwrap();
+        let request = || {
+            warp::test::request()
+                .remote_addr("10.0.0.2:5000".parse().unwrap())
+                .header("Fly-Client-IP", "198.51.100.1")
+                .header("X-Forwarded-For", "198.51.100.2, 10.0.0.1")
+        };
+
+        let ip = request().filter(&client_ip(None)).await.unwrap();
+        assert_eq!(ip, IpAddr::from([198, 51, 100, 1]));
+
+        // terminating TLS ourselves, the headers come from the client
+        let ip = request().filter(&client_ip(Some(peer))).await.unwrap();
+        assert_eq!(ip, peer.ip());
+    }
+}
@@ -0,0 +1,163 @@
+use crate::config::{CertIdentity, ControlTlsConfig};
+use crate::network::{load_certs, load_key};
+use std::error::Error;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// TLS on the control port: clients with a certificate from the
+/// client CA are authenticated as the account it maps to
+pub struct ControlTls {
+    acceptor: TlsAcceptor,
+    identities: Vec<CertIdentity>,
+}
+
+impl ControlTls {
+    pub fn load(config: &ControlTlsConfig) -> Result<Self, Box<dyn Error>> {
+        let certs = load_certs(&config.cert)?;
+        let key = load_key(&config.key)?;
+
+        let builder = match config.client_ca {
+            Some(ref client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for ca in load_certs(client_ca)? {
+                    roots.add(ca)?;
+                }
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+                let verifier = if config.require_client_cert {
+                    verifier.build()?
+                } else {
+                    verifier.allow_unauthenticated().build()?
+                };
+                ServerConfig::builder().with_client_cert_verifier(verifier)
+            }
+            None if config.require_client_cert => {
+                return Err("requiring client certificates needs a client CA".into())
+            }
+            None => ServerConfig::builder().with_no_client_auth(),
+        };
+
+        Ok(ControlTls {
+            acceptor: TlsAcceptor::from(Arc::new(builder.with_single_cert(certs, key)?)),
+            identities: config.identities.clone(),
+        })
+    }
+
+    /// Complete the handshake, and identify the client by its certificate if it has one
+    pub async fn accept(
+        &self,
+        stream: TcpStream,
+    ) -> Result<(TlsStream<TcpStream>, Option<CertIdentity>), Box<dyn Error>> {
+        let stream = self.acceptor.accept(stream).await?;
+
+        let identity = match stream.get_ref().1.peer_certificates() {
+            Some([cert, ..]) => Some(
+                self.identify(cert)
+                    .ok_or("client certificate doesn't match any identity")?,
+            ),
+            _ => None,
+        };
+
+        Ok((stream, identity))
+    }
+
+    fn identify(&self, cert: &CertificateDer<'_>) -> Option<CertIdentity> {
+        let names = cert_names(cert);
+        if self.identities.is_empty() {
+            // the account is the common name
+            let name = names.into_iter().next()?;
+            return Some(CertIdentity {
+                account: name.clone(),
+                name,
+                sub_domains: vec![],
+            });
+        }
+
+        self.identities
+            .iter()
+            .find(|identity| names.contains(&identity.name))
+            .cloned()
+    }
+}
+
+/// The subject common names of a certificate, then its DNS, email and URI alternative names
+fn cert_names(cert: &CertificateDer<'_>) -> Vec<String> {
+    let cert = match X509Certificate::from_der(cert.as_ref()) {
+        Ok((_, cert)) => cert,
+        Err(error) => {
+            tracing::warn!(?error, "unable to parse client certificate");
+            return vec![];
+        }
+    };
+
+    let mut names: Vec<String> = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|cn| cn.as_str().ok())
+        .map(String::from)
+        .collect();
+
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        names.extend(
+            san.value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => Some(name.to_string()),
+                    _ => None,
+                }),
+        );
+    }
+
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn control_tls(identities: Vec<CertIdentity>) -> ControlTls {
+        ControlTls::load(&ControlTlsConfig {
+            cert: PathBuf::from("tests/cluster_tls/cert.pem"),
+            key: PathBuf::from("tests/cluster_tls/key.pem"),
+            client_ca: Some(PathBuf::from("tests/cluster_tls/ca.pem")),
+            require_client_cert: true,
+            identities,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_identify() {
+        let cert = load_certs("tests/cluster_tls/cert.pem".as_ref())
+            .unwrap()
+            .remove(0);
+
+        let identity = control_tls(vec![]).identify(&cert).unwrap();
+        assert_eq!(identity.account, "portal.cluster");
+        assert!(identity.sub_domains.is_empty());
+
+        let identity = CertIdentity {
+            name: "portal.cluster".into(),
+            account: "acme".into(),
+            sub_domains: vec!["acme".into()],
+        };
+        let other = CertIdentity {
+            name: "other.example.com".into(),
+            ..identity.clone()
+        };
+        assert_eq!(
+            control_tls(vec![other.clone(), identity.clone()]).identify(&cert),
+            Some(identity)
+        );
+        assert_eq!(control_tls(vec![other]).identify(&cert), None);
+    }
+}
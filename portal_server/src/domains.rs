@@ -0,0 +1,326 @@
+use crate::auth::jwt::TokenClaims;
+use crate::auth::reservations::{self, Reservation, ReservationError};
+use crate::auth::{AccountLimits, AuthResult, AuthService};
+use crate::config::CertIdentity;
+use crate::{get_auth_db_service, get_config, get_jwt_auth, get_routes, sub_domain};
+use portal_lib::{ClientId, DomainReservation, SecretKey};
+use std::convert::Infallible;
+use tracing::{error, info};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+/// An account managing its reserved sub-domains
+enum DomainOwner {
+    Key { key: String, limits: AccountLimits },
+    Token(TokenClaims),
+    Cert(CertIdentity),
+}
+
+impl DomainOwner {
+    /// The id its tunnels connect with
+    fn client_id(&self) -> ClientId {
+        match self {
+            DomainOwner::Key { key, .. } => SecretKey(key.clone()).client_id(),
+            DomainOwner::Token(claims) => ClientId::for_account(&claims.account),
+            DomainOwner::Cert(identity) => ClientId::for_account(&identity.account),
+        }
+    }
+
+    /// Whether it may use `sub_domain`, asking the auth service like tunnels do for keys
+    fn auth_sub_domain(&self, sub_domain: &str) -> AuthResult {
+        let allowed = match self {
+            DomainOwner::Key { key, .. } => {
+                return match get_auth_db_service().auth_sub_domain(key, sub_domain) {
+                    Ok(result) => result,
+                    Err(error) => {
+                        info!(?error, "key denied sub-domain");
+                        AuthResult::PaymentRequired
+                    }
+                };
+            }
+            DomainOwner::Token(claims) => claims.allows(sub_domain),
+            DomainOwner::Cert(identity) => identity.allows(sub_domain),
+        };
+        if allowed {
+            AuthResult::Available
+        } else {
+            AuthResult::PaymentRequired
+        }
+    }
+
+    /// How many sub-domains it may reserve
+    fn max_reservations(&self) -> usize {
+        let limit = match self {
+            DomainOwner::Key { limits, .. } => limits.max_reservations,
+            DomainOwner::Token(claims) => claims.limits.max_reservations,
+            DomainOwner::Cert(_) => None,
+        };
+        limit.unwrap_or(get_config().max_reservations_per_account)
+    }
+}
+
+/// `GET /domains`, `PUT /domains/<sub-domain>` and `DELETE /domains/<sub-domain>`,
+/// authenticated like tunnels: by key, token or client certificate
+pub fn routes(
+    identity: Option<CertIdentity>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static {
+    let auth = warp::header::optional::<String>("authorization")
+        .and(warp::any().map(move || identity.clone()));
+
+    let list = warp::get()
+        .and(warp::path!("domains"))
+        .and(auth.clone())
+        .and_then(list);
+    let reserve = warp::put()
+        .and(warp::path!("domains" / String))
+        .and(auth.clone())
+        .and_then(reserve);
+    let release = warp::delete()
+        .and(warp::path!("domains" / String))
+        .and(auth)
+        .and_then(release);
+
+    list.or(reserve).or(release)
+}
+
+async fn list(
+    auth: Option<String>,
+    identity: Option<CertIdentity>,
+) -> Result<Response, Infallible> {
+    let owner = match authenticate(auth, identity).await {
+        Ok(owner) => owner,
+        Err(response) => return Ok(response),
+    };
+
+    let client_id = owner.client_id();
+    let reservations = reservations::blocking(move |reservations| reservations.list(&client_id))
+        .await
+        .into_iter()
+        .map(|(sub_domain, reservation)| domain_reservation(sub_domain, reservation))
+        .collect::<Vec<_>>();
+    Ok(warp::reply::json(&reservations).into_response())
+}
+
+async fn reserve(
+    sub_domain: String,
+    auth: Option<String>,
+    identity: Option<CertIdentity>,
+) -> Result<Response, Infallible> {
+    let owner = match authenticate(auth, identity).await {
+        Ok(owner) => owner,
+        Err(response) => return Ok(response),
+    };
+    let client_id = owner.client_id();
+    let sub_domain = sub_domain.to_lowercase();
+
+    let max_reservations = owner.max_reservations();
+    if max_reservations == 0 {
+        return Ok(reply_error(
+            StatusCode::FORBIDDEN,
+            "Reserving sub-domains is turned off on this server.",
+        ));
+    }
+    if !sub_domain::is_valid(&sub_domain) {
+        return Ok(reply_error(
+            StatusCode::BAD_REQUEST,
+            "Invalid sub-domain: only alphanumeric and hyphen characters are allowed.",
+        ));
+    }
+    if sub_domain.starts_with("*.") {
+        return Ok(reply_error(
+            StatusCode::BAD_REQUEST,
+            "Reserve the name itself, which covers every host below it.",
+        ));
+    }
+    let config = get_config();
+    let is_blocked = sub_domain::parents(&sub_domain)
+        .any(|name| config.blocked_sub_domains.iter().any(|b| b == name));
+    if is_blocked {
+        return Ok(reply_error(
+            StatusCode::FORBIDDEN,
+            "You are not allowed to reserve this sub-domain.",
+        ));
+    }
+    match owner.auth_sub_domain(&sub_domain) {
+        AuthResult::Available | AuthResult::ReservedByYou => {}
+        AuthResult::ReservedByOther => {
+            return Ok(reply_error(
+                StatusCode::CONFLICT,
+                "This sub-domain is reserved by someone else.",
+            ))
+        }
+        AuthResult::ReservedByYouButDelinquent | AuthResult::PaymentRequired => {
+            return Ok(reply_error(
+                StatusCode::FORBIDDEN,
+                "You are not allowed to reserve this sub-domain.",
+            ))
+        }
+    }
+
+    // not from under someone else's open tunnel
+    match get_routes()
+        .lookup(std::slice::from_ref(&sub_domain))
+        .await
+        .map(|routes| routes.into_iter().flatten().next())
+    {
+        Ok(Some(route)) if route.client_id != client_id => {
+            return Ok(reply_error(
+                StatusCode::CONFLICT,
+                "This sub-domain is in use by someone else.",
+            ));
+        }
+        Ok(_) => {}
+        Err(error) => {
+            error!(?error, "failed to look up sub-domain");
+            return Ok(reply_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Unable to reserve sub-domain, please try again.",
+            ));
+        }
+    }
+
+    let reserved = {
+        let (sub_domain, client_id) = (sub_domain.clone(), client_id.clone());
+        let ttl = config.reservation_ttl;
+        reservations::blocking(move |reservations| {
+            reservations.reserve(&sub_domain, &client_id, max_reservations, ttl)
+        })
+        .await
+    };
+    match reserved {
+        Ok((reservation, created)) => {
+            info!(%sub_domain, %client_id, created, "reserved sub-domain");
+            let status = if created {
+                StatusCode::CREATED
+            } else {
+                StatusCode::OK
+            };
+            Ok(warp::reply::with_status(
+                warp::reply::json(&domain_reservation(sub_domain, reservation)),
+                status,
+            )
+            .into_response())
+        }
+        Err(ReservationError::ReservedByOther) => Ok(reply_error(
+            StatusCode::CONFLICT,
+            "This sub-domain is reserved by someone else.",
+        )),
+        Err(ReservationError::LimitReached(max)) => Ok(reply_error(
+            StatusCode::FORBIDDEN,
+            &format!(
+                "You already reserved {} sub-domains, release one to reserve another.",
+                max
+            ),
+        )),
+        Err(error) => {
+            error!(%error, "failed to reserve sub-domain");
+            Ok(reply_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to reserve sub-domain, please try again.",
+            ))
+        }
+    }
+}
+
+async fn release(
+    sub_domain: String,
+    auth: Option<String>,
+    identity: Option<CertIdentity>,
+) -> Result<Response, Infallible> {
+    let owner = match authenticate(auth, identity).await {
+        Ok(owner) => owner,
+        Err(response) => return Ok(response),
+    };
+    let client_id = owner.client_id();
+    let sub_domain = sub_domain.to_lowercase();
+
+    let released = {
+        let (sub_domain, client_id) = (sub_domain.clone(), client_id.clone());
+        reservations::blocking(move |reservations| reservations.release(&sub_domain, &client_id))
+            .await
+    };
+    match released {
+        Ok(()) => {
+            info!(%sub_domain, %client_id, "released sub-domain");
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        Err(ReservationError::NotReserved) | Err(ReservationError::ReservedByOther) => {
+            Ok(reply_error(
+                StatusCode::NOT_FOUND,
+                "You haven't reserved this sub-domain.",
+            ))
+        }
+        Err(error) => {
+            error!(%error, "failed to release sub-domain");
+            Ok(reply_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to release sub-domain, please try again.",
+            ))
+        }
+    }
+}
+
+/// The account of the bearer key or token, else of the client certificate
+async fn authenticate(
+    auth: Option<String>,
+    identity: Option<CertIdentity>,
+) -> Result<DomainOwner, Response> {
+    let bearer = auth
+        .as_deref()
+        .and_then(|auth| auth.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    match (bearer, identity) {
+        // keys are alphanumeric, tokens are three dot separated parts
+        (Some(token), _) if token.split('.').count() == 3 => {
+            let Some(jwt_auth) = get_jwt_auth() else {
+                return Err(reply_error(
+                    StatusCode::UNAUTHORIZED,
+                    "Token authentication is not enabled.",
+                ));
+            };
+            jwt_auth
+                .validate(token)
+                .await
+                .map(DomainOwner::Token)
+                .map_err(|error| {
+                    info!(%error, "invalid token for domains");
+                    reply_error(StatusCode::UNAUTHORIZED, "Invalid token.")
+                })
+        }
+        // any key passes without an account backend, and would let anyone hold names for good
+        (Some(_), _) if !get_auth_db_service().verifies_keys() => Err(reply_error(
+            StatusCode::UNAUTHORIZED,
+            "This server doesn't verify access keys, reserve with a token or client certificate.",
+        )),
+        (Some(key), _) => {
+            let key = key.to_string();
+            match get_auth_db_service().account_limits(&key) {
+                Ok(limits) => Ok(DomainOwner::Key { key, limits }),
+                Err(error) => {
+                    info!(?error, "invalid key for domains");
+                    Err(reply_error(StatusCode::UNAUTHORIZED, "Invalid access key."))
+                }
+            }
+        }
+        (None, Some(identity)) => Ok(DomainOwner::Cert(identity)),
+        (None, None) => Err(reply_error(
+            StatusCode::UNAUTHORIZED,
+            "An access key, token or client certificate is required.",
+        )),
+    }
+}
+
+fn domain_reservation(sub_domain: String, reservation: Reservation) -> DomainReservation {
+    DomainReservation {
+        hostname: format!("{}.{}", sub_domain, get_config().portal_host),
+        sub_domain,
+        created: reservation.created,
+        expires: reservation.expires,
+    }
+}
+
+fn reply_error(status: StatusCode, message: &str) -> Response {
+    warp::reply::with_status(message.to_string(), status).into_response()
+}
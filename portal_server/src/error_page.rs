@@ -0,0 +1,280 @@
+use crate::config::ErrorPagesConfig;
+use std::io;
+use uuid::Uuid;
+use warp::http::StatusCode;
+
+const DEFAULT_TEMPLATE: &str = include_str!("error_page.html");
+const MAX_REQUEST_ID_LEN: usize = 64;
+/// When visitors of a tunnel that's down should try again, in seconds
+const MAINTENANCE_RETRY_AFTER: u64 = 30;
+
+/// Renders the responses visitors get when we can't reach their tunnel
+#[derive(Debug)]
+pub struct ErrorPages {
+    brand: String,
+    homepage: String,
+    template: String,
+    request_id: bool,
+}
+
+/// What an error page needs to know about the request it answers
+#[derive(Debug, Clone)]
+pub struct PageRequest {
+    /// the `X-Request-Id` of the request, else one we made up
+    pub id: String,
+    /// the visitor asked for JSON rather than HTML
+    pub wants_json: bool,
+}
+
+impl PageRequest {
+    pub fn from_headers(headers: &[httparse::Header]) -> Self {
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case(name))
+                .and_then(|h| std::str::from_utf8(h.value).ok())
+        };
+
+        let id = header("x-request-id")
+            .filter(|id| {
+                !id.is_empty()
+                    && id.len() <= MAX_REQUEST_ID_LEN
+                    && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            })
+            .map(String::from)
+            .unwrap_or_else(|| Uuid::new_v4().simple().to_string());
+
+        PageRequest {
+            id,
+            wants_json: header("accept").map(wants_json).unwrap_or(false),
+        }
+    }
+}
+
+/// Browsers accept html, api clients that want json say so
+fn wants_json(accept: &str) -> bool {
+    let accept = accept.to_ascii_lowercase();
+    !accept.contains("text/html") && (accept.contains("/json") || accept.contains("+json"))
+}
+
+impl ErrorPages {
+    pub fn load(config: &ErrorPagesConfig) -> io::Result<Self> {
+        let template = match config.template {
+            Some(ref path) => std::fs::read_to_string(path)?,
+            None => DEFAULT_TEMPLATE.to_string(),
+        };
+
+        Ok(ErrorPages {
+            brand: config.brand.clone(),
+            homepage: config.homepage.clone(),
+            template,
+            request_id: config.request_id,
+        })
+    }
+
+    /// A complete http response for the error, as json or html
+    pub fn response(&self, status: StatusCode, message: &str, request: &PageRequest) -> Vec<u8> {
+        self.build(status, message, None, request)
+    }
+
+    /// The maintenance page a client uploaded, for visitors while its tunnel is down
+    pub fn maintenance(&self, page: &str, request: &PageRequest) -> Vec<u8> {
+        self.build(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "This tunnel is temporarily down, please try again later.",
+            Some(page),
+            request,
+        )
+    }
+
+    fn build(
+        &self,
+        status: StatusCode,
+        message: &str,
+        page: Option<&str>,
+        request: &PageRequest,
+    ) -> Vec<u8> {
+        let reason = status.canonical_reason().unwrap_or("Error");
+        let request_id = if self.request_id {
+            request.id.as_str()
+        } else {
+            ""
+        };
+
+        let (content_type, body) = if request.wants_json {
+            let mut body = serde_json::json!({
+                "status": status.as_u16(),
+                "error": reason,
+                "message": message,
+            });
+            if self.request_id {
+                body["request_id"] = request_id.into();
+            }
+            ("application/json", body.to_string())
+        } else if let Some(page) = page {
+            ("text/html; charset=utf-8", page.to_string())
+        } else {
+            let body = render(&self.template, |name| match name {
+                "status" => Some(status.as_str().to_string()),
+                "reason" => Some(escape(reason)),
+                "message" => Some(escape(message)),
+                "brand" => Some(escape(&self.brand)),
+                "request_id" => Some(escape(request_id)),
+                _ => None,
+            });
+            ("text/html; charset=utf-8", body)
+        };
+
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            status.as_str(),
+            reason,
+            content_type,
+            body.len()
+        );
+        if self.request_id {
+            head.push_str(&format!("X-Request-Id: {}\r\n", request_id));
+        }
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            head.push_str(&format!("Retry-After: {}\r\n", MAINTENANCE_RETRY_AFTER));
+        }
+        head.push_str("\r\n");
+
+        let mut response = head.into_bytes();
+        response.extend_from_slice(body.as_bytes());
+        response
+    }
+
+    /// Send visitors of an allowed host itself to our homepage
+    pub fn redirect(&self) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 301 Moved Permanently\r\nLocation: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.homepage,
+            self.homepage.len(),
+            self.homepage
+        )
+        .into_bytes()
+    }
+}
+
+/// Fill in the `{{name}}` placeholders of a template in one pass,
+/// so values can't be mistaken for placeholders. Unknown ones are left as is.
+fn render(template: &str, value: impl Fn(&str) -> Option<String>) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after
+            .find("}}")
+            .and_then(|end| Some((value(after[..end].trim())?, end)))
+        {
+            Some((value, end)) => {
+                rendered.push_str(&value);
+                rest = &after[end + 2..];
+            }
+            None => {
+                rendered.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pages(request_id: bool) -> ErrorPages {
+        ErrorPages {
+            brand: "Acme".to_string(),
+            homepage: "https://acme.dev".to_string(),
+            template: "<h1>{{status}} {{reason}}</h1><p>{{ message }}</p>{{brand}}:{{request_id}}{{other}}"
+                .to_string(),
+            request_id,
+        }
+    }
+
+    fn split(response: &[u8]) -> (String, String) {
+        let response = String::from_utf8(response.to_vec()).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.to_string(), body.to_string())
+    }
+
+    #[test]
+    fn test_error_pages() {
+        let request = PageRequest {
+            id: "abc-123".to_string(),
+            wants_json: false,
+        };
+
+        let (head, body) =
+            split(&pages(false).response(StatusCode::NOT_FOUND, "<b>{{brand}}</b>", &request));
+        assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(head.contains("Content-Type: text/html"));
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        assert!(!head.contains("X-Request-Id"));
+        assert_eq!(
+            body,
+            "<h1>404 Not Found</h1><p>&lt;b&gt;{{brand}}&lt;/b&gt;</p>Acme:{{other}}"
+        );
+
+        let (head, body) = split(&pages(true).response(
+            StatusCode::BAD_GATEWAY,
+            "refused",
+            &PageRequest {
+                wants_json: true,
+                ..request.clone()
+            },
+        ));
+        assert!(head.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        assert!(head.contains("X-Request-Id: abc-123"));
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["status"], 502);
+        assert_eq!(body["message"], "refused");
+        assert_eq!(body["request_id"], "abc-123");
+
+        let (head, body) = split(&pages(false).maintenance("<p>back soon</p>", &request));
+        assert!(head.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(head.contains("Retry-After: 30"));
+        assert_eq!(body, "<p>back soon</p>");
+    }
+
+    #[test]
+    fn test_page_request() {
+        let headers = [
+            httparse::Header {
+                name: "Accept",
+                value: b"application/json",
+            },
+            httparse::Header {
+                name: "X-Request-Id",
+                value: b"lb-42",
+            },
+        ];
+        let request = PageRequest::from_headers(&headers);
+        assert!(request.wants_json);
+        assert_eq!(request.id, "lb-42");
+
+        let headers = [httparse::Header {
+            name: "accept",
+            value: b"text/html,application/xhtml+xml,*/*;q=0.8",
+        }];
+        let request = PageRequest::from_headers(&headers);
+        assert!(!request.wants_json);
+        assert_eq!(request.id.len(), 32);
+    }
+}
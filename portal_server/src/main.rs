@@ -4,7 +4,7 @@ use warp::Filter;
 
 use dashmap::DashMap;
 pub use portal_lib::*;
-use std::sync::{Arc, OnceLock};
+use std::sync::{Arc, OnceLock, RwLock};
 
 use tokio::net::TcpListener;
 
@@ -22,6 +22,10 @@ pub use self::auth::client_auth;
 // pub use self::auth_db::AuthDbService;
 
 mod control_server;
+mod control_tls;
+mod domains;
+mod error_page;
+mod maintenance;
 mod remote;
 
 mod config;
@@ -30,6 +34,12 @@ mod network;
 
 mod observability;
 
+mod rate_limit;
+mod reload;
+mod shutdown;
+mod sub_domain;
+use rate_limit::RateLimiters;
+
 mod cli;
 use clap::Parser;
 use cli::Cli;
@@ -38,13 +48,23 @@ use tracing::level_filters::LevelFilter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::registry;
 
-use tracing::{error, info, Instrument};
+use tracing::{error, info, warn, Instrument};
 
 static CLI: OnceLock<Cli> = OnceLock::new();
 static CONNECTIONS: OnceLock<Connections> = OnceLock::new();
 static ACTIVE_STREAMS: OnceLock<ActiveStreams> = OnceLock::new();
-static CONFIG: OnceLock<Config> = OnceLock::new();
+static CONFIG: OnceLock<RwLock<Arc<Config>>> = OnceLock::new();
 static AUTH_DB_SERVICE: OnceLock<crate::auth::NoAuth> = OnceLock::new();
+static RATE_LIMITERS: OnceLock<RateLimiters> = OnceLock::new();
+static DISCOVERY: OnceLock<Box<dyn network::Discovery>> = OnceLock::new();
+static ROUTES: OnceLock<Box<dyn network::RouteStore>> = OnceLock::new();
+static ROUTE_TABLE: OnceLock<network::RouteTable> = OnceLock::new();
+static CLUSTER_TLS: OnceLock<Option<network::ClusterTls>> = OnceLock::new();
+static CONTROL_TLS: OnceLock<Option<control_tls::ControlTls>> = OnceLock::new();
+static JWT_AUTH: OnceLock<Option<auth::jwt::JwtAuth>> = OnceLock::new();
+static RESERVATIONS: OnceLock<auth::reservations::Reservations> = OnceLock::new();
+static ERROR_PAGES: OnceLock<error_page::ErrorPages> = OnceLock::new();
+static MAINTENANCE_PAGES: OnceLock<maintenance::MaintenancePages> = OnceLock::new();
 
 pub fn get_cli() -> &'static Cli {
     CLI.get_or_init(Cli::parse)
@@ -58,17 +78,108 @@ pub fn get_active_streams() -> &'static ActiveStreams {
     ACTIVE_STREAMS.get_or_init(|| Arc::new(DashMap::new()))
 }
 
-pub fn get_config() -> &'static Config {
-    CONFIG.get_or_init(|| match get_cli().config {
-        Some(ref config_path) => Config::load_from_file(config_path.to_str().unwrap()).unwrap(),
-        None => Config::load_from_env(),
-    })
+pub fn get_config() -> Arc<Config> {
+    CONFIG
+        .get_or_init(|| {
+            let config = match get_cli().config {
+                Some(ref config_path) => {
+                    Config::load_from_file(config_path.to_str().unwrap()).unwrap()
+                }
+                None => Config::load_from_env(),
+            };
+            RwLock::new(Arc::new(config))
+        })
+        .read()
+        .unwrap()
+        .clone()
+}
+
+pub fn set_config(config: Config) {
+    let _ = get_config();
+    *CONFIG.get().unwrap().write().unwrap() = Arc::new(config);
 }
 
 pub fn get_auth_db_service() -> &'static crate::auth::NoAuth {
     AUTH_DB_SERVICE.get_or_init(|| crate::auth::NoAuth)
 }
 
+pub fn get_rate_limiters() -> &'static RateLimiters {
+    RATE_LIMITERS.get_or_init(RateLimiters::default)
+}
+
+pub fn get_discovery() -> &'static dyn network::Discovery {
+    DISCOVERY
+        .get_or_init(|| {
+            let config = get_config();
+            match config.discovery {
+                Some(ref discovery) => discovery.build(config.internal_network_port),
+                None => {
+                    tracing::warn!("warning! gossip mode disabled!");
+                    Box::new(network::NoDiscovery)
+                }
+            }
+        })
+        .as_ref()
+}
+
+pub fn get_routes() -> &'static dyn network::RouteStore {
+    ROUTES
+        .get_or_init(|| match get_config().discovery {
+            Some(_) => Box::new(network::PeerRoutes),
+            None => Box::new(network::LocalRoutes),
+        })
+        .as_ref()
+}
+
+pub fn get_route_table() -> &'static network::RouteTable {
+    ROUTE_TABLE.get_or_init(network::RouteTable::default)
+}
+
+pub fn get_cluster_tls() -> Option<&'static network::ClusterTls> {
+    CLUSTER_TLS
+        .get_or_init(|| {
+            get_config().cluster_tls.as_ref().map(|config| {
+                network::ClusterTls::load(config)
+                    .unwrap_or_else(|e| panic!("invalid cluster tls: {}", e))
+            })
+        })
+        .as_ref()
+}
+
+pub fn get_jwt_auth() -> Option<&'static auth::jwt::JwtAuth> {
+    JWT_AUTH
+        .get_or_init(|| get_config().jwt.clone().map(auth::jwt::JwtAuth::new))
+        .as_ref()
+}
+
+pub fn get_reservations() -> &'static auth::reservations::Reservations {
+    RESERVATIONS.get_or_init(|| {
+        auth::reservations::Reservations::new(get_config().reservations_file.clone())
+    })
+}
+
+pub fn get_error_pages() -> &'static error_page::ErrorPages {
+    ERROR_PAGES.get_or_init(|| {
+        error_page::ErrorPages::load(&get_config().error_pages)
+            .unwrap_or_else(|e| panic!("invalid error page template: {}", e))
+    })
+}
+
+pub fn get_maintenance_pages() -> &'static maintenance::MaintenancePages {
+    MAINTENANCE_PAGES.get_or_init(maintenance::MaintenancePages::default)
+}
+
+pub fn get_control_tls() -> Option<&'static control_tls::ControlTls> {
+    CONTROL_TLS
+        .get_or_init(|| {
+            get_config().control_tls.as_ref().map(|config| {
+                control_tls::ControlTls::load(config)
+                    .unwrap_or_else(|e| panic!("invalid control tls: {}", e))
+            })
+        })
+        .as_ref()
+}
+
 #[tokio::main]
 async fn main() {
     // if let Some(config_path) = &CLI.config {
@@ -85,18 +196,55 @@ async fn main() {
 
     let config = get_config();
 
+    rate_limit::spawn_pruning();
+    // fail on a broken error page template now, rather than on the first visitor
+    get_error_pages();
+
+    // tokens are verified against these, they reload on unknown key ids
+    if let Some(jwt_auth) = get_jwt_auth() {
+        if let Err(error) = jwt_auth.load_keys().await {
+            error!(%error, "failed to load jwt keys");
+        }
+    }
+    network::spawn_lease_renewal();
+    reload::spawn_signal_listener();
+
     control_server::spawn(([0, 0, 0, 0], config.control_port));
     info!(
-        "started portal control server on 0.0.0.0:{}",
-        config.control_port
+        "started portal control server on 0.0.0.0:{}{}",
+        config.control_port,
+        if get_control_tls().is_some() {
+            " (tls)"
+        } else {
+            ""
+        }
     );
 
+    if let Some(admin_addr) = config.admin_addr {
+        control_server::spawn_admin(admin_addr);
+        info!("started admin server on {}", admin_addr);
+    }
+
     network::spawn(([0, 0, 0, 0, 0, 0, 0, 0], config.internal_network_port));
     info!(
         "start network service on [::]:{}",
         config.internal_network_port
     );
 
+    // a single instance has no peers to trust
+    if config.cluster_key.is_none() && (config.discovery.is_some() || config.cluster_tls.is_some())
+    {
+        warn!(
+            port = config.internal_network_port,
+            "cluster requests are NOT authenticated: anyone reaching this port can claim routes, set CLUSTER_KEY"
+        );
+    }
+
+    if let Some(cluster_tls) = get_cluster_tls() {
+        cluster_tls.spawn_listener();
+        info!("started cluster tls on 0.0.0.0:{}", cluster_tls.port);
+    }
+
     let listen_addr = format!("[::]:{}", config.remote_port);
     info!("listening on: {}", &listen_addr);
     info!("portal server with hostname: {}", config.portal_host);
@@ -106,13 +254,19 @@ async fn main() {
         .await
         .expect("failed to bind");
 
+    let shutdown_signal = shutdown::signal();
+    tokio::pin!(shutdown_signal);
+
     loop {
-        let socket = match listener.accept().await {
-            Ok((socket, _)) => socket,
-            Err(e) => {
-                error!("failed to accept socket: {:?}", e);
-                continue;
-            }
+        let socket = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    error!("failed to accept socket: {:?}", e);
+                    continue;
+                }
+            },
+            _ = &mut shutdown_signal => break,
         };
 
         info!("accepted connection from: {}", socket.peer_addr().unwrap());
@@ -124,4 +278,10 @@ async fn main() {
             .instrument(observability::remote_trace("remote_connect")),
         );
     }
+
+    // stop accepting remote connections and drain the existing ones
+    drop(listener);
+    info!("shutting down, draining connections");
+    shutdown::drain(config.shutdown_timeout).await;
+    info!("goodbye");
 }
@@ -0,0 +1,220 @@
+use crate::sub_domain;
+use dashmap::DashMap;
+use portal_lib::ClientId;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// The pages clients uploaded for their hosts, served while their tunnel is down.
+/// Each pool member of a host keeps its own page. Pages stay on the instance
+/// the client connected to, the others of a cluster serve their usual error pages.
+/// Pages kept for an account, and in total, each up to `MAX_MAINTENANCE_PAGE_SIZE`
+const MAX_PAGES_PER_ACCOUNT: usize = 16;
+const MAX_PAGES: usize = 256;
+
+#[derive(Default)]
+pub struct MaintenancePages {
+    pages: DashMap<String, Vec<MaintenancePage>>,
+}
+
+struct MaintenancePage {
+    client_id: ClientId,
+    /// the session of the connection that uploaded it
+    member: Uuid,
+    html: Arc<str>,
+    /// when we stop serving it, never while its client is connected
+    expires: Option<Instant>,
+}
+
+impl MaintenancePage {
+    fn is_live(&self, now: Instant) -> bool {
+        self.expires.is_none_or(|expires| expires > now)
+    }
+}
+
+impl MaintenancePages {
+    /// The page of a connection now serving `host`, replacing its previous one
+    /// and those of any other client that served it before
+    pub fn set(&self, host: &str, client_id: &ClientId, member: Uuid, html: Option<String>) {
+        if let Some(mut pages) = self.pages.get_mut(host) {
+            pages.retain(|page| &page.client_id == client_id && page.member != member);
+        }
+        self.pages.remove_if(host, |_, pages| pages.is_empty());
+
+        let Some(html) = html else {
+            return;
+        };
+        if !self.make_room(client_id) {
+            tracing::warn!(%client_id, %host, "too many maintenance pages, ignoring this one");
+            return;
+        }
+        self.pages
+            .entry(host.to_string())
+            .or_default()
+            .push(MaintenancePage {
+                client_id: client_id.clone(),
+                member,
+                html: html.into(),
+                expires: None,
+            });
+    }
+
+    /// Drop the pages of disconnected clients that expire first, until there's
+    /// room for another page of this account. False if connected ones fill it
+    fn make_room(&self, client_id: &ClientId) -> bool {
+        let now = Instant::now();
+        loop {
+            let mut total = 0;
+            let mut own = 0;
+            let mut expiring = vec![];
+            for pages in self.pages.iter() {
+                for page in pages.iter().filter(|page| page.is_live(now)) {
+                    total += 1;
+                    let is_own = &page.client_id == client_id;
+                    own += usize::from(is_own);
+                    if let Some(expires) = page.expires {
+                        expiring.push((expires, is_own, pages.key().clone(), page.member));
+                    }
+                }
+            }
+
+            let account_full = own >= MAX_PAGES_PER_ACCOUNT;
+            if !account_full && total < MAX_PAGES {
+                return true;
+            }
+            // make room in the account if that's what's full
+            let Some((_, _, host, member)) = expiring
+                .into_iter()
+                .filter(|(_, is_own, _, _)| *is_own || !account_full)
+                .min_by_key(|(expires, _, _, _)| *expires)
+            else {
+                return false;
+            };
+            if let Some(mut pages) = self.pages.get_mut(&host) {
+                pages.retain(|page| page.member != member);
+            }
+            self.pages.remove_if(&host, |_, pages| pages.is_empty());
+        }
+    }
+
+    /// A connection serving `host` closed, keep serving its page for `ttl`
+    pub fn expire(&self, host: &str, member: Uuid, ttl: Duration) {
+        let now = Instant::now();
+        if let Some(mut pages) = self.pages.get_mut(host) {
+            for page in pages.iter_mut().filter(|page| page.member == member) {
+                page.expires = Some(now + ttl);
+            }
+        }
+
+        self.pages.retain(|_, pages| {
+            pages.retain(|page| page.is_live(now));
+            !pages.is_empty()
+        });
+    }
+
+    /// The page for visitors of `host`, or of a wildcard tunnel above it.
+    /// That of a connected member first, else the one that expires last
+    pub fn find(&self, host: &str) -> Option<Arc<str>> {
+        let now = Instant::now();
+        sub_domain::route_candidates(host)
+            .iter()
+            .find_map(|candidate| {
+                self.pages.get(candidate).and_then(|pages| {
+                    pages
+                        .iter()
+                        .filter(|page| page.is_live(now))
+                        .max_by_key(|page| (page.expires.is_none(), page.expires))
+                        .map(|page| page.html.clone())
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_maintenance_pages() {
+        let pages = MaintenancePages::default();
+        let alice = ClientId::generate();
+        let bob = ClientId::generate();
+        let (alice_1, alice_2, bob_1) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        pages.set("*.alice", &alice, alice_1, Some("down".into()));
+        assert_eq!(pages.find("v2.alice").as_deref(), Some("down"));
+        assert!(pages.find("alice").is_none());
+
+        // another pool member without a page leaves it alone
+        pages.set("*.alice", &alice, alice_2, None);
+        assert_eq!(pages.find("v2.alice").as_deref(), Some("down"));
+        pages.set("*.alice", &alice, alice_2, Some("down 2".into()));
+        pages.expire("*.alice", alice_2, Duration::from_secs(60));
+        assert_eq!(pages.find("v2.alice").as_deref(), Some("down"));
+        pages.expire("*.alice", alice_1, Duration::ZERO);
+        assert_eq!(pages.find("v2.alice").as_deref(), Some("down 2"));
+
+        // bob took over the host before alice's connection was dropped
+        pages.set("api", &alice, alice_1, Some("alice".into()));
+        pages.set("api", &bob, bob_1, Some("bob".into()));
+        pages.expire("api", alice_1, Duration::ZERO);
+        assert_eq!(pages.find("api").as_deref(), Some("bob"));
+
+        pages.set("api", &bob, bob_1, None);
+        assert!(pages.find("api").is_none());
+    }
+
+    #[test]
+    fn test_page_limits() {
+        let pages = MaintenancePages::default();
+        let alice = ClientId::generate();
+
+        let members: Vec<_> = (0..MAX_PAGES_PER_ACCOUNT).map(|_| Uuid::new_v4()).collect();
+        for (i, member) in members.iter().enumerate() {
+            pages.set(
+                &format!("alice-{}", i),
+                &alice,
+                *member,
+                Some("down".into()),
+            );
+        }
+        // every page of alice is in use
+        pages.set("alice-new", &alice, Uuid::new_v4(), Some("down".into()));
+        assert!(pages.find("alice-new").is_none());
+
+        // a disconnected one makes room, the one expiring first goes
+        pages.expire("alice-1", members[1], Duration::from_secs(60));
+        pages.expire("alice-2", members[2], Duration::from_secs(30));
+        pages.set("alice-new", &alice, Uuid::new_v4(), Some("down".into()));
+        assert!(pages.find("alice-new").is_some());
+        assert!(pages.find("alice-1").is_some());
+        assert!(pages.find("alice-2").is_none());
+
+        // the pages of other accounts count towards the total
+        let others = MAX_PAGES - MAX_PAGES_PER_ACCOUNT;
+        for i in 0..others {
+            let other = ClientId::generate();
+            pages.set(
+                &format!("other-{}", i),
+                &other,
+                Uuid::new_v4(),
+                Some("down".into()),
+            );
+        }
+        pages.set(
+            "bob",
+            &ClientId::generate(),
+            Uuid::new_v4(),
+            Some("down".into()),
+        );
+        assert!(pages.find("bob").is_some());
+        assert!(pages.find("alice-1").is_none());
+        pages.set(
+            "carol",
+            &ClientId::generate(),
+            Uuid::new_v4(),
+            Some("down".into()),
+        );
+        assert!(pages.find("carol").is_none());
+    }
+}
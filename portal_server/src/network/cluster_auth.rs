@@ -0,0 +1,300 @@
+use crate::auth::{SigKey, Signature};
+use crate::get_config;
+use chrono::Utc;
+use reqwest::{Method, RequestBuilder, Url};
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::{Filter, Rejection};
+
+const TIMESTAMP_HEADER: &str = "x-portal-timestamp";
+const SIGNATURE_HEADER: &str = "x-portal-signature";
+const NONCE_HEADER: &str = "x-portal-nonce";
+/// How old a signed request may be, allowing for clock skew between instances
+const MAX_REQUEST_AGE_SECS: i64 = 60;
+
+static SEEN_NONCES: OnceLock<NonceCache> = OnceLock::new();
+
+/// The nonces of the signed requests we accepted, so none is accepted twice.
+/// Kept for as long as their requests are young enough to be accepted:
+/// a request may be signed up to its max age ahead of or behind our clock.
+#[derive(Default)]
+struct NonceCache {
+    seen: Mutex<SeenNonces>,
+}
+
+#[derive(Default)]
+struct SeenNonces {
+    nonces: HashSet<String>,
+    /// when we accepted each nonce, oldest first
+    accepted: VecDeque<(Instant, String)>,
+}
+
+impl NonceCache {
+    /// Returns false if we saw this nonce already
+    fn insert(&self, nonce: &str) -> bool {
+        let now = Instant::now();
+        let keep = Duration::from_secs(2 * MAX_REQUEST_AGE_SECS as u64);
+        let mut seen = self.seen.lock().unwrap();
+        while let Some((accepted, _)) = seen.accepted.front() {
+            if now.duration_since(*accepted) <= keep {
+                break;
+            }
+            if let Some((_, nonce)) = seen.accepted.pop_front() {
+                seen.nonces.remove(&nonce);
+            }
+        }
+
+        if !seen.nonces.insert(nonce.to_string()) {
+            return false;
+        }
+        seen.accepted.push_back((now, nonce.to_string()));
+        true
+    }
+}
+
+/// what we sign: the request, when it was made and a nonce so it's only accepted once
+fn signed_data(
+    timestamp: i64,
+    nonce: &str,
+    method: &str,
+    path_and_query: &str,
+    body: &[u8],
+) -> Vec<u8> {
+    let mut data =
+        format!("{}\n{}\n{}\n{}\n", timestamp, nonce, method, path_and_query).into_bytes();
+    data.extend_from_slice(body);
+    data
+}
+
+/// Build a request to another instance, signed with the cluster key if we have one
+pub fn request(method: Method, url: Url, body: Vec<u8>) -> RequestBuilder {
+    let mut path_and_query = url.path().to_string();
+    if let Some(query) = url.query() {
+        path_and_query = format!("{}?{}", path_and_query, query);
+    }
+
+    let mut request = reqwest::Client::new()
+        .request(method.clone(), url)
+        .header("content-type", "application/json");
+
+    if let Some(key) = get_config().cluster_key.as_ref() {
+        let timestamp = Utc::now().timestamp();
+        let nonce = uuid::Uuid::new_v4().simple().to_string();
+        let signature = key.sign(&signed_data(
+            timestamp,
+            &nonce,
+            method.as_str(),
+            &path_and_query,
+            &body,
+        ));
+        request = request
+            .header(TIMESTAMP_HEADER, timestamp)
+            .header(NONCE_HEADER, nonce)
+            .header(SIGNATURE_HEADER, signature.as_str());
+    }
+
+    request.body(body)
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+impl warp::reject::Reject for Unauthorized {}
+
+/// Only allow requests signed with the cluster key, if we have one.
+/// Extracts the request body.
+pub fn authenticated() -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::optional::<i64>(TIMESTAMP_HEADER))
+        .and(warp::header::optional::<String>(NONCE_HEADER))
+        .and(warp::header::optional::<String>(SIGNATURE_HEADER))
+        .and(warp::body::bytes())
+        .and_then(
+            |method: warp::http::Method,
+             path: warp::path::FullPath,
+             query: String,
+             timestamp: Option<i64>,
+             nonce: Option<String>,
+             signature: Option<String>,
+             body: Bytes| async move {
+                let key = match get_config().cluster_key.clone() {
+                    Some(key) => key,
+                    None => return Ok(body),
+                };
+
+                let mut path_and_query = path.as_str().to_string();
+                if !query.is_empty() {
+                    path_and_query = format!("{}?{}", path_and_query, query);
+                }
+
+                if verify(
+                    &key,
+                    SEEN_NONCES.get_or_init(NonceCache::default),
+                    method.as_str(),
+                    &path_and_query,
+                    &body,
+                    timestamp,
+                    nonce,
+                    signature,
+                ) {
+                    Ok(body)
+                } else {
+                    tracing::warn!(path=%path.as_str(), "rejected unsigned cluster request");
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            },
+        )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn verify(
+    key: &SigKey,
+    nonces: &NonceCache,
+    method: &str,
+    path_and_query: &str,
+    body: &[u8],
+    timestamp: Option<i64>,
+    nonce: Option<String>,
+    signature: Option<String>,
+) -> bool {
+    let (timestamp, nonce, signature) = match (timestamp, nonce, signature) {
+        (Some(timestamp), Some(nonce), Some(signature)) => {
+            (timestamp, nonce, Signature::new(signature))
+        }
+        _ => return false,
+    };
+
+    if (Utc::now().timestamp() - timestamp).abs() > MAX_REQUEST_AGE_SECS {
+        return false;
+    }
+
+    // only a validly signed request may take up its nonce
+    key.verify(
+        &signed_data(timestamp, &nonce, method, path_and_query, body),
+        &signature,
+    ) && nonces.insert(&nonce)
+}
+
+/// Reply 401 to unsigned requests
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl warp::Reply, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        Ok(warp::reply::with_status(
+            "Unauthorized",
+            StatusCode::UNAUTHORIZED,
+        ))
+    } else {
+        Err(rejection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_signed_request() {
+        let key = SigKey::generate();
+        let nonces = NonceCache::default();
+        let now = Utc::now().timestamp();
+        let sign = |timestamp: i64, nonce: &str, body: &[u8]| {
+            key.sign(&signed_data(
+                timestamp,
+                nonce,
+                "POST",
+                "/routes/claim",
+                body,
+            ))
+            .as_str()
+            .to_string()
+        };
+        let verify = |key: &SigKey, path: &str, body: &[u8], timestamp, nonce: &str, signature| {
+            verify(
+                key,
+                &nonces,
+                "POST",
+                path,
+                body,
+                Some(timestamp),
+                Some(nonce.to_string()),
+                Some(signature),
+            )
+        };
+
+        let signature = sign(now, "a", b"{}");
+        assert!(verify(
+            &key,
+            "/routes/claim",
+            b"{}",
+            now,
+            "a",
+            signature.clone()
+        ));
+
+        // replayed, tampered with, stale or signed with another key
+        assert!(!verify(
+            &key,
+            "/routes/claim",
+            b"{}",
+            now,
+            "a",
+            signature.clone()
+        ));
+        assert!(!verify(
+            &key,
+            "/routes/release",
+            b"{}",
+            now,
+            "b",
+            sign(now, "b", b"{}")
+        ));
+        assert!(!verify(
+            &key,
+            "/routes/claim",
+            b"[]",
+            now,
+            "c",
+            sign(now, "c", b"{}")
+        ));
+        assert!(!verify(&key, "/routes/claim", b"{}", now, "d", signature));
+        assert!(!verify(
+            &key,
+            "/routes/claim",
+            b"{}",
+            now - 600,
+            "e",
+            sign(now - 600, "e", b"{}")
+        ));
+        assert!(!verify(
+            &SigKey::generate(),
+            "/routes/claim",
+            b"{}",
+            now,
+            "f",
+            sign(now, "f", b"{}")
+        ));
+
+        // a forged request doesn't burn the nonce of a real one
+        assert!(verify(
+            &key,
+            "/routes/claim",
+            b"{}",
+            now,
+            "b",
+            sign(now, "b", b"{}")
+        ));
+        assert!(!super::verify(
+            &key,
+            &nonces,
+            "POST",
+            "/routes/claim",
+            b"{}",
+            None,
+            None,
+            None
+        ));
+    }
+}
@@ -0,0 +1,212 @@
+use super::{Error, Instance};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::SystemTime;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// How we find the other instances of our server
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DiscoveryConfig {
+    /// A fixed list of `host:port` network service addresses
+    Static { peers: Vec<String> },
+    /// Every A/AAAA record of a host, on our own network port
+    /// i.e:    global.<app>.internal on Fly.io
+    Dns { host: String },
+    /// SRV records, which carry each instance's network port
+    /// i.e:    _net._tcp.portal.default.svc.cluster.local on Kubernetes
+    Srv { name: String },
+    /// A file with one `host:port` per line, re-read when it changes
+    File { path: PathBuf },
+}
+
+impl FromStr for DiscoveryConfig {
+    type Err = String;
+
+    /// parse `static:<peer>,<peer>`, `dns:<host>`, `srv:<name>` or `file:<path>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("static", peers)) => Ok(DiscoveryConfig::Static {
+                peers: peers.split(',').map(String::from).collect(),
+            }),
+            Some(("dns", host)) => Ok(DiscoveryConfig::Dns { host: host.into() }),
+            Some(("srv", name)) => Ok(DiscoveryConfig::Srv { name: name.into() }),
+            Some(("file", path)) => Ok(DiscoveryConfig::File { path: path.into() }),
+            _ => Err(format!("unknown discovery: {}", s)),
+        }
+    }
+}
+
+impl DiscoveryConfig {
+    pub fn build(&self, network_port: u16) -> Box<dyn Discovery> {
+        match self.clone() {
+            DiscoveryConfig::Static { peers } => Box::new(StaticPeers { peers }),
+            DiscoveryConfig::Dns { host } => Box::new(DnsHost { host, network_port }),
+            DiscoveryConfig::Srv { name } => Box::new(DnsSrv { name }),
+            DiscoveryConfig::File { path } => Box::new(PeerFile {
+                path,
+                cached: RwLock::new(None),
+            }),
+        }
+    }
+}
+
+/// Find the instances where our app runs
+#[async_trait]
+pub trait Discovery: Send + Sync {
+    async fn instances(&self) -> Result<Vec<Instance>, Error>;
+}
+
+/// Gossip is disabled: we are the only instance
+pub struct NoDiscovery;
+
+#[async_trait]
+impl Discovery for NoDiscovery {
+    async fn instances(&self) -> Result<Vec<Instance>, Error> {
+        Ok(vec![])
+    }
+}
+
+pub struct StaticPeers {
+    peers: Vec<String>,
+}
+
+#[async_trait]
+impl Discovery for StaticPeers {
+    async fn instances(&self) -> Result<Vec<Instance>, Error> {
+        resolve_peers(&self.peers).await
+    }
+}
+
+pub struct DnsHost {
+    host: String,
+    network_port: u16,
+}
+
+#[async_trait]
+impl Discovery for DnsHost {
+    async fn instances(&self) -> Result<Vec<Instance>, Error> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+        let ips = resolver.lookup_ip(self.host.as_str()).await?;
+
+        Ok(ips
+            .iter()
+            .map(|ip| Instance::new(SocketAddr::new(ip, self.network_port)))
+            .collect())
+    }
+}
+
+pub struct DnsSrv {
+    name: String,
+}
+
+#[async_trait]
+impl Discovery for DnsSrv {
+    async fn instances(&self) -> Result<Vec<Instance>, Error> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+        let records = resolver.srv_lookup(self.name.as_str()).await?;
+
+        let mut instances = vec![];
+        for record in records.iter() {
+            let ips = resolver.lookup_ip(record.target().clone()).await?;
+            instances.extend(
+                ips.iter()
+                    .map(|ip| Instance::new(SocketAddr::new(ip, record.port()))),
+            );
+        }
+        Ok(instances)
+    }
+}
+
+pub struct PeerFile {
+    path: PathBuf,
+    /// the peers as of the file's last modified time
+    cached: RwLock<Option<(SystemTime, Vec<String>)>>,
+}
+
+#[async_trait]
+impl Discovery for PeerFile {
+    async fn instances(&self) -> Result<Vec<Instance>, Error> {
+        let modified = tokio::fs::metadata(&self.path).await?.modified()?;
+
+        let cached = match *self.cached.read().unwrap() {
+            Some((at, ref peers)) if at == modified => Some(peers.clone()),
+            _ => None,
+        };
+
+        let peers = match cached {
+            Some(peers) => peers,
+            None => {
+                let peers: Vec<String> = tokio::fs::read_to_string(&self.path)
+                    .await?
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(String::from)
+                    .collect();
+                tracing::info!(path=%self.path.display(), ?peers, "loaded peer file");
+                *self.cached.write().unwrap() = Some((modified, peers.clone()));
+                peers
+            }
+        };
+
+        resolve_peers(&peers).await
+    }
+}
+
+/// resolve `host:port` peers, skipping those that don't resolve
+async fn resolve_peers(peers: &[String]) -> Result<Vec<Instance>, Error> {
+    let mut instances = vec![];
+    for peer in peers {
+        match tokio::net::lookup_host(peer.as_str()).await {
+            Ok(addrs) => instances.extend(addrs.map(Instance::new)),
+            Err(error) => tracing::warn!(%peer, ?error, "failed to resolve peer"),
+        }
+    }
+    Ok(instances)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_discovery() {
+        assert_eq!(
+            DiscoveryConfig::from_str("static:10.0.0.1:6000,10.0.0.2:6000").unwrap(),
+            DiscoveryConfig::Static {
+                peers: vec!["10.0.0.1:6000".into(), "10.0.0.2:6000".into()]
+            }
+        );
+        assert_eq!(
+            DiscoveryConfig::from_str("srv:_net._tcp.portal").unwrap(),
+            DiscoveryConfig::Srv {
+                name: "_net._tcp.portal".into()
+            }
+        );
+        assert!(DiscoveryConfig::from_str("consul:portal").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_peer_file() {
+        let path = std::env::temp_dir().join(format!("portal-peers-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "# peers\n127.0.0.1:6001\n\n127.0.0.1:6002\n").unwrap();
+
+        let discovery = DiscoveryConfig::File { path: path.clone() }.build(6000);
+        let instances = discovery.instances().await.unwrap();
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[1].addr, "127.0.0.1:6002".parse().unwrap());
+
+        // make sure the modified time moves on
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        std::fs::write(&path, "127.0.0.1:6003\n").unwrap();
+        let instances = discovery.instances().await.unwrap();
+        assert_eq!(instances.len(), 1);
+
+        let _ = std::fs::remove_file(path);
+    }
+}
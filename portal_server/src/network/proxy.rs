@@ -1,29 +1,34 @@
-use crate::get_config;
-use crate::network::Instance;
-use std::net::SocketAddr;
+use crate::error_page::PageRequest;
+use crate::network::Route;
+use crate::{get_cluster_tls, get_error_pages};
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
+use warp::http::StatusCode;
 
-const HTTP_ERROR_PROXYING_TUNNEL_RESPONSE: &[u8] =
-    b"HTTP/1.1 500\r\nContent-Length: 28\r\n\r\nError: Error proxying tunnel";
-
-pub async fn proxy_stream(instance: Instance, mut stream: TcpStream) {
-    let addr = SocketAddr::new(instance.ip, get_config().remote_port);
-    let mut instance = match TcpStream::connect(addr).await {
-        Ok(stream) => stream,
-        Err(error) => {
-            tracing::error!(?error, "Error connecting to instance");
-            let _ = stream.write_all(HTTP_ERROR_PROXYING_TUNNEL_RESPONSE).await;
-            return;
-        }
+/// Proxy a remote stream to the instance serving its route
+pub async fn proxy_stream(route: Route, mut stream: TcpStream, request: &PageRequest) {
+    let result = match (route.tls, get_cluster_tls()) {
+        (true, Some(cluster_tls)) => match cluster_tls.connect(route.addr).await {
+            Ok(mut instance) => Ok(tokio::io::copy_bidirectional(&mut stream, &mut instance).await),
+            Err(error) => Err(error.to_string()),
+        },
+        (true, None) => Err("instance expects cluster tls, which we don't have".into()),
+        // never send visitor traffic in the clear if we have tls
+        (false, Some(_)) => Err("instance doesn't have cluster tls".into()),
+        (false, None) => match TcpStream::connect(route.addr).await {
+            // shuts down each side once the other is done, so neither end waits forever
+            Ok(mut instance) => Ok(tokio::io::copy_bidirectional(&mut stream, &mut instance).await),
+            Err(error) => Err(error.to_string()),
+        },
     };
 
-    let (mut i_read, mut i_write) = instance.split();
-    let (mut r_read, mut r_write) = stream.split();
-
-    let _ = futures::future::join(
-        tokio::io::copy(&mut r_read, &mut i_write),
-        tokio::io::copy(&mut i_read, &mut r_write),
-    )
-    .await;
+    if let Err(error) = result {
+        tracing::error!(%error, instance=%route.addr, "Error connecting to instance");
+        let response = get_error_pages().response(
+            StatusCode::BAD_GATEWAY,
+            "We couldn't reach the server running this tunnel, please try again.",
+            request,
+        );
+        let _ = stream.write_all(&response).await;
+    }
 }
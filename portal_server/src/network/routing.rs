@@ -0,0 +1,377 @@
+use super::cluster_auth;
+use super::{Error, Instance};
+use crate::{
+    get_cluster_tls, get_config, get_discovery, get_route_table, get_routes, shutdown, sub_domain,
+    ClientId, Connections,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use reqwest::{Method, Url};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// How long a route is held without being renewed
+const LEASE_DURATION: Duration = Duration::from_secs(30);
+/// How often we renew the routes of our connected clients
+const LEASE_RENEW_INTERVAL: Duration = Duration::from_secs(10);
+/// How often we check for instances joining or leaving, which moves hosts
+/// to new owners without a lease for them
+const MEMBERSHIP_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+/// How long we wait on the instance owning a host
+const ROUTE_REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+/// The most hosts looked up at once: a tunnel's candidates and the names above it
+pub const MAX_LOOKUP_HOSTS: usize = 2 * (sub_domain::MAX_LABELS + 1);
+
+/// Where the tunnel for a host is served
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Route {
+    pub client_id: ClientId,
+    pub instance_id: String,
+    /// the remote listener of that instance
+    pub addr: SocketAddr,
+    /// is `addr` the cluster tls port
+    #[serde(default)]
+    pub tls: bool,
+    pub expires: DateTime<Utc>,
+}
+
+impl Route {
+    fn is_expired(&self) -> bool {
+        Utc::now() > self.expires
+    }
+}
+
+/// A request to hold or give up a host, sent to the instance owning it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RouteClaim {
+    pub host: String,
+    pub client_id: ClientId,
+    pub instance_id: String,
+    pub remote_port: u16,
+    #[serde(default)]
+    pub tls: bool,
+}
+
+impl RouteClaim {
+    fn new(host: &str, client_id: &ClientId) -> Self {
+        let config = get_config();
+        // other instances proxy to us over tls if we have it
+        let (remote_port, tls) = match get_cluster_tls() {
+            Some(cluster_tls) => (cluster_tls.port, true),
+            None => (config.remote_port, false),
+        };
+
+        RouteClaim {
+            host: host.to_string(),
+            client_id: client_id.clone(),
+            instance_id: config.instance_id.clone(),
+            remote_port,
+            tls,
+        }
+    }
+
+    /// the leased route, as seen from `ip`
+    pub fn into_route(self, ip: IpAddr) -> Route {
+        Route {
+            client_id: self.client_id,
+            instance_id: self.instance_id,
+            addr: SocketAddr::new(ip, self.remote_port),
+            tls: self.tls,
+            expires: Utc::now() + chrono::Duration::from_std(LEASE_DURATION).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClaimResponse {
+    /// who holds the host, if not the claiming client
+    pub holder: Option<Route>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RouteQuery {
+    /// comma separated
+    pub hosts: String,
+}
+
+/// The leases for the hosts this instance is authoritative for
+#[derive(Default)]
+pub struct RouteTable {
+    routes: DashMap<String, Route>,
+}
+
+impl RouteTable {
+    /// Lease `host` to the route's client, unless another client holds it.
+    /// Returns that other route if so. The client's lease on another instance,
+    /// i.e. of another pool member, is kept until it's released or expires
+    pub fn claim(&self, host: &str, route: Route) -> Option<Route> {
+        match self.routes.entry(host.to_string()) {
+            Entry::Occupied(entry) if entry.get().is_expired() => {
+                entry.replace_entry(route);
+                None
+            }
+            Entry::Occupied(entry) if entry.get().client_id != route.client_id => {
+                Some(entry.get().clone())
+            }
+            Entry::Occupied(mut entry) => {
+                if entry.get().instance_id == route.instance_id {
+                    entry.insert(route);
+                }
+                None
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(route);
+                None
+            }
+        }
+    }
+
+    /// Drop the lease, if this instance still holds it for this client
+    pub fn release(&self, claim: &RouteClaim) {
+        self.routes.remove_if(&claim.host, |_, route| {
+            route.client_id == claim.client_id && route.instance_id == claim.instance_id
+        });
+    }
+
+    pub fn lookup(&self, host: &str) -> Option<Route> {
+        self.routes
+            .get(host)
+            .filter(|route| !route.is_expired())
+            .map(|route| route.clone())
+    }
+
+    fn prune(&self) {
+        self.routes.retain(|_, route| !route.is_expired());
+    }
+}
+
+/// Where we keep the routing table
+#[async_trait]
+pub trait RouteStore: Send + Sync {
+    /// Lease `host` to this client on this instance.
+    /// Returns the route of another client holding it.
+    async fn claim(&self, host: &str, client_id: &ClientId) -> Result<Option<Route>, Error>;
+
+    async fn release(&self, host: &str, client_id: &ClientId) -> Result<(), Error>;
+
+    /// The routes of `hosts`, which are all below the same name, in one go
+    async fn lookup(&self, hosts: &[String]) -> Result<Vec<Option<Route>>, Error>;
+}
+
+/// We're the only instance: keep the table in memory
+pub struct LocalRoutes;
+
+#[async_trait]
+impl RouteStore for LocalRoutes {
+    async fn claim(&self, host: &str, client_id: &ClientId) -> Result<Option<Route>, Error> {
+        let route = RouteClaim::new(host, client_id).into_route(IpAddr::from([127, 0, 0, 1]));
+        Ok(get_route_table().claim(host, route))
+    }
+
+    async fn release(&self, host: &str, client_id: &ClientId) -> Result<(), Error> {
+        get_route_table().release(&RouteClaim::new(host, client_id));
+        Ok(())
+    }
+
+    async fn lookup(&self, hosts: &[String]) -> Result<Vec<Option<Route>>, Error> {
+        Ok(hosts
+            .iter()
+            .map(|host| get_route_table().lookup(host))
+            .collect())
+    }
+}
+
+/// Each name is owned by one instance, picked by rendezvous hashing
+/// over the discovered instances, which keeps the leases of the hosts below it
+pub struct PeerRoutes;
+
+impl PeerRoutes {
+    async fn owner(host: &str) -> Result<Instance, Error> {
+        // so one instance answers for a host and the wildcards above it
+        let host = sub_domain::root(host);
+        get_discovery()
+            .instances()
+            .await?
+            .into_iter()
+            .max_by_key(|instance| {
+                sha2::Sha256::new()
+                    .chain_update(host.as_bytes())
+                    .chain_update(instance.addr.to_string().as_bytes())
+                    .finalize()
+            })
+            .ok_or(Error::NoInstances)
+    }
+
+    fn url(owner: &Instance, path: &str) -> Result<Url, Error> {
+        Url::parse(&format!("http://{}/routes{}", owner.addr, path))
+            .map_err(|_| Error::InvalidInstance(owner.addr))
+    }
+}
+
+#[async_trait]
+impl RouteStore for PeerRoutes {
+    async fn claim(&self, host: &str, client_id: &ClientId) -> Result<Option<Route>, Error> {
+        let owner = Self::owner(host).await?;
+        let body = serde_json::to_vec(&RouteClaim::new(host, client_id))?;
+        let response: ClaimResponse =
+            cluster_auth::request(Method::POST, Self::url(&owner, "/claim")?, body)
+                .timeout(ROUTE_REQUEST_TIMEOUT)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+        Ok(response.holder)
+    }
+
+    async fn release(&self, host: &str, client_id: &ClientId) -> Result<(), Error> {
+        let owner = Self::owner(host).await?;
+        let body = serde_json::to_vec(&RouteClaim::new(host, client_id))?;
+        cluster_auth::request(Method::POST, Self::url(&owner, "/release")?, body)
+            .timeout(ROUTE_REQUEST_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn lookup(&self, hosts: &[String]) -> Result<Vec<Option<Route>>, Error> {
+        let Some(host) = hosts.first() else {
+            return Ok(vec![]);
+        };
+        let owner = Self::owner(host).await?;
+        let mut url = Self::url(&owner, "")?;
+        url.query_pairs_mut().append_pair("hosts", &hosts.join(","));
+
+        let routes = cluster_auth::request(Method::GET, url, vec![])
+            .timeout(ROUTE_REQUEST_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(routes)
+    }
+}
+
+/// Give up the route of a disconnected client
+pub fn spawn_release(host: String, client_id: ClientId) {
+    tokio::spawn(async move {
+        if let Err(error) = get_routes().release(&host, &client_id).await {
+            tracing::warn!(%host, ?error, "failed to release route");
+        }
+    });
+}
+
+/// Give up the routes of all our clients, so other instances take them over
+pub async fn release_all() {
+    let releases = Connections::hosts()
+        .into_iter()
+        .map(|(host, client_id)| async move {
+            if let Err(error) = get_routes().release(&host, &client_id).await {
+                tracing::warn!(%host, ?error, "failed to release route");
+            }
+        });
+    futures::future::join_all(releases).await;
+}
+
+/// Keep renewing the leases of our connected clients,
+/// and drop the expired ones we're authoritative for
+pub fn spawn_lease_renewal() {
+    tokio::spawn(async move {
+        let mut members = vec![];
+        let mut renewed = Instant::now();
+        loop {
+            tokio::time::sleep(MEMBERSHIP_CHECK_INTERVAL).await;
+
+            // the new owners of our hosts get their leases right away
+            let mut current = match get_discovery().instances().await {
+                Ok(instances) => instances.into_iter().map(|i| i.addr).collect(),
+                Err(_) => members.clone(),
+            };
+            current.sort();
+            let changed = current != members;
+            members = current;
+            if !changed && renewed.elapsed() < LEASE_RENEW_INTERVAL {
+                continue;
+            }
+            renewed = Instant::now();
+            get_route_table().prune();
+
+            // our clients are moving to other instances
+            if shutdown::is_shutting_down() {
+                continue;
+            }
+
+            for (host, client_id) in Connections::hosts() {
+                match get_routes().claim(&host, &client_id).await {
+                    Ok(None) => {}
+                    Ok(Some(holder)) => {
+                        tracing::warn!(%host, holder=%holder.client_id, "route taken by another client")
+                    }
+                    Err(error) => tracing::warn!(%host, ?error, "failed to renew route"),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(client_id: &ClientId, instance_id: &str, expires_in: i64) -> Route {
+        Route {
+            client_id: client_id.clone(),
+            instance_id: instance_id.to_string(),
+            addr: "127.0.0.1:8080".parse().unwrap(),
+            tls: false,
+            expires: Utc::now() + chrono::Duration::seconds(expires_in),
+        }
+    }
+
+    #[test]
+    fn test_route_leases() {
+        let table = RouteTable::default();
+        let a = ClientId::generate();
+        let b = ClientId::generate();
+
+        assert!(table.claim("foo", route(&a, "one", 30)).is_none());
+        assert_eq!(
+            table.claim("foo", route(&b, "two", 30)).unwrap().client_id,
+            a
+        );
+
+        // another pool member of the client doesn't take the lease over
+        assert!(table.claim("foo", route(&a, "two", 30)).is_none());
+        assert_eq!(table.lookup("foo").unwrap().instance_id, "one");
+        let renewed = route(&a, "one", 60);
+        assert!(table.claim("foo", renewed.clone()).is_none());
+        assert_eq!(table.lookup("foo").unwrap().expires, renewed.expires);
+
+        // a release from another instance doesn't drop it
+        let release = |instance_id: &str| RouteClaim {
+            host: "foo".into(),
+            client_id: a.clone(),
+            instance_id: instance_id.into(),
+            remote_port: 8080,
+            tls: false,
+        };
+        table.release(&release("two"));
+        assert_eq!(table.lookup("foo").unwrap().instance_id, "one");
+
+        // once released, the client moves to another instance
+        table.release(&release("one"));
+        assert!(table.claim("foo", route(&a, "two", 30)).is_none());
+        assert_eq!(table.lookup("foo").unwrap().instance_id, "two");
+
+        // expired leases are up for grabs
+        assert!(table.claim("bar", route(&a, "one", -1)).is_none());
+        assert!(table.lookup("bar").is_none());
+        assert!(table.claim("bar", route(&b, "two", 30)).is_none());
+    }
+}
@@ -1,8 +1,10 @@
+use super::cluster_auth::{authenticated, handle_rejection};
+use super::routing::{ClaimResponse, RouteClaim, RouteQuery, MAX_LOOKUP_HOSTS};
 use super::*;
-use crate::connected_clients::Connections;
-use crate::ClientId;
-use serde::{Deserialize, Serialize};
-use warp::Filter;
+use crate::get_route_table;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::{Filter, Reply};
 
 pub fn spawn<A: Into<SocketAddr>>(addr: A) {
     let health_check = warp::get().and(warp::path("health_check")).map(|| {
@@ -10,30 +12,63 @@ pub fn spawn<A: Into<SocketAddr>>(addr: A) {
         "ok"
     });
 
-    let query_svc = warp::path::end()
+    let lookup = warp::path("routes")
+        .and(warp::path::end())
         .and(warp::get())
-        .and(warp::query::<HostQuery>())
-        .map(|query| warp::reply::json(&handle_query(query)));
+        .and(warp::query::<RouteQuery>())
+        .and(authenticated())
+        .map(|query: RouteQuery, _| {
+            tracing::debug!(hosts=%query.hosts, "got route lookup");
+            let routes = query
+                .hosts
+                .split(',')
+                .take(MAX_LOOKUP_HOSTS)
+                .map(|host| get_route_table().lookup(host))
+                .collect::<Vec<_>>();
+            warp::reply::json(&routes)
+        });
 
-    let routes = query_svc.or(health_check);
+    let claim = warp::path!("routes" / "claim")
+        .and(warp::post())
+        .and(authenticated())
+        .and(warp::addr::remote())
+        .map(|body: Bytes, remote: Option<SocketAddr>| {
+            let claim: RouteClaim = match serde_json::from_slice(&body) {
+                Ok(claim) => claim,
+                Err(_) => return bad_request(),
+            };
+            let ip = remote
+                .map(|r| r.ip())
+                .unwrap_or(std::net::IpAddr::from([127, 0, 0, 1]));
+            let host = claim.host.clone();
+            tracing::debug!(%host, client_id=%claim.client_id, "got route claim");
+            let holder = get_route_table().claim(&host, claim.into_route(ip));
+            warp::reply::json(&ClaimResponse { holder }).into_response()
+        });
 
-    // spawn our websocket control server
-    tokio::spawn(warp::serve(routes).run(addr.into()));
-}
+    let release = warp::path!("routes" / "release")
+        .and(warp::post())
+        .and(authenticated())
+        .map(|body: Bytes| {
+            let claim: RouteClaim = match serde_json::from_slice(&body) {
+                Ok(claim) => claim,
+                Err(_) => return bad_request(),
+            };
+            tracing::debug!(host=%claim.host, client_id=%claim.client_id, "got route release");
+            get_route_table().release(&claim);
+            warp::reply().into_response()
+        });
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct HostQuery {
-    pub host: String,
-}
+    let routes = lookup
+        .or(claim)
+        .or(release)
+        .or(health_check)
+        .recover(handle_rejection);
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct HostQueryResponse {
-    pub client_id: Option<ClientId>,
+    // spawn our websocket control server
+    tokio::spawn(warp::serve(routes).run(addr.into()));
 }
 
-fn handle_query(query: HostQuery) -> HostQueryResponse {
-    tracing::debug!(host=%query.host, "got query");
-    HostQueryResponse {
-        client_id: Connections::client_for_host(&query.host),
-    }
+fn bad_request() -> warp::reply::Response {
+    warp::reply::with_status("Bad Request", StatusCode::BAD_REQUEST).into_response()
 }
@@ -0,0 +1,116 @@
+use crate::config::ClusterTlsConfig;
+use crate::get_config;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{client, TlsAcceptor, TlsConnector};
+
+/// TLS for streams between instances: each side checks the other's
+/// certificate against the cluster CA
+pub struct ClusterTls {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+    pub port: u16,
+}
+
+impl ClusterTls {
+    pub fn load(config: &ClusterTlsConfig) -> Result<Self, Box<dyn Error>> {
+        let certs = load_certs(&config.cert)?;
+        let key = load_key(&config.key)?;
+
+        let mut roots = RootCertStore::empty();
+        for ca in load_certs(&config.ca)? {
+            roots.add(ca)?;
+        }
+        let roots = Arc::new(roots);
+
+        let verifier = WebPkiClientVerifier::builder(roots.clone()).build()?;
+        let server = ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs.clone(), key.clone_key())?;
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(certs, key)?;
+
+        Ok(ClusterTls {
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+            connector: TlsConnector::from(Arc::new(client)),
+            server_name: ServerName::try_from(config.server_name.clone())?,
+            port: config.port,
+        })
+    }
+
+    /// Open a TLS stream to another instance
+    pub async fn connect(&self, addr: SocketAddr) -> std::io::Result<client::TlsStream<TcpStream>> {
+        let stream = TcpStream::connect(addr).await?;
+        self.connector
+            .connect(self.server_name.clone(), stream)
+            .await
+    }
+
+    /// Accept streams proxied from other instances,
+    /// handing them to our remote listener
+    pub fn spawn_listener(&'static self) {
+        tokio::spawn(async move {
+            let listener = match TcpListener::bind(("0.0.0.0", self.port)).await {
+                Ok(listener) => listener,
+                Err(error) => {
+                    tracing::error!(?error, port=%self.port, "failed to bind cluster tls port");
+                    return;
+                }
+            };
+
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(s) => s,
+                    Err(error) => {
+                        tracing::error!(?error, "failed to accept cluster stream");
+                        continue;
+                    }
+                };
+
+                tokio::spawn(async move {
+                    let mut stream = match self.acceptor.accept(stream).await {
+                        Ok(stream) => stream,
+                        Err(error) => {
+                            tracing::warn!(%peer, ?error, "rejected cluster stream");
+                            return;
+                        }
+                    };
+
+                    let remote = SocketAddr::from(([127, 0, 0, 1], get_config().remote_port));
+                    let mut remote = match TcpStream::connect(remote).await {
+                        Ok(remote) => remote,
+                        Err(error) => {
+                            tracing::error!(?error, "failed to connect to remote listener");
+                            return;
+                        }
+                    };
+
+                    let _ = tokio::io::copy_bidirectional(&mut stream, &mut remote).await;
+                });
+            }
+        });
+    }
+}
+
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Box<dyn Error>> {
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in {}", path.display()).into());
+    }
+    Ok(certs)
+}
+
+pub fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, Box<dyn Error>> {
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| format!("no private key in {}", path.display()).into())
+}
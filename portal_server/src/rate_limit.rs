@@ -0,0 +1,291 @@
+use crate::auth::AccountLimits;
+use crate::{get_config, ClientId};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// How often we drop buckets that have fully refilled
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+/// The longest we ask a caller to pause at once
+const MAX_THROTTLE_WAIT: Duration = Duration::from_secs(60);
+
+/// A token bucket limit:
+/// i.e:    { per_second = 2, burst = 10 } => bursts of 10, refilled at 2 per second
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "RateLimitConfig")]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+/// A rate limit as written in the config file or account limits, before validation
+#[derive(Deserialize)]
+struct RateLimitConfig {
+    per_second: f64,
+    burst: f64,
+}
+
+impl TryFrom<RateLimitConfig> for RateLimit {
+    type Error = String;
+
+    fn try_from(config: RateLimitConfig) -> Result<Self, Self::Error> {
+        RateLimit::new(config.per_second, config.burst)
+    }
+}
+
+impl RateLimit {
+    pub fn new(per_second: f64, burst: f64) -> Result<Self, String> {
+        let valid = |n: f64| n.is_finite() && n > 0.0;
+        if !valid(per_second) || !valid(burst) {
+            return Err(format!(
+                "rate limit must be positive: {}:{}",
+                per_second, burst
+            ));
+        }
+        Ok(RateLimit { per_second, burst })
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    /// parse `<per_second>` or `<per_second>:<burst>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (per_second, burst) = match s.split_once(':') {
+            Some((per_second, burst)) => (per_second, Some(burst)),
+            None => (s, None),
+        };
+
+        let per_second: f64 = per_second
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid rate: {}", s))?;
+        let burst: f64 = match burst {
+            Some(burst) => burst
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid burst: {}", s))?,
+            None => per_second,
+        };
+
+        RateLimit::new(per_second, burst)
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, limit: RateLimit) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.limit = limit;
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst);
+        self.last_refill = now;
+    }
+
+    fn is_full(&self) -> bool {
+        let elapsed = self.last_refill.elapsed().as_secs_f64();
+        self.tokens + elapsed * self.limit.per_second >= self.limit.burst
+    }
+}
+
+/// A set of token buckets, one per key
+pub struct KeyedLimiter<K: Eq + Hash> {
+    buckets: DashMap<K, TokenBucket>,
+}
+
+impl<K: Eq + Hash> Default for KeyedLimiter<K> {
+    fn default() -> Self {
+        Self {
+            buckets: DashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> KeyedLimiter<K> {
+    /// Take a single token for this key, returns false if the limit is exceeded
+    pub fn check(&self, key: &K, limit: RateLimit) -> bool {
+        let mut bucket = self
+            .buckets
+            .entry(key.clone())
+            .or_insert_with(|| TokenBucket::new(limit));
+        bucket.refill(limit);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Account `amount` tokens against this key, going into debt if needed.
+    /// Returns how long the caller should pause to stay within the limit, at most a minute.
+    pub fn throttle(&self, key: &K, limit: RateLimit, amount: usize) -> Duration {
+        let mut bucket = self
+            .buckets
+            .entry(key.clone())
+            .or_insert_with(|| TokenBucket::new(limit));
+        bucket.refill(limit);
+        bucket.tokens -= amount as f64;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::try_from_secs_f64(-bucket.tokens / limit.per_second)
+                .map_or(MAX_THROTTLE_WAIT, |wait| wait.min(MAX_THROTTLE_WAIT))
+        }
+    }
+
+    /// Drop buckets that have fully refilled: they behave the same as a new one
+    pub fn prune(&self) {
+        self.buckets.retain(|_, bucket| !bucket.is_full());
+    }
+}
+
+/// All the limiters enforced by this server instance
+#[derive(Default)]
+pub struct RateLimiters {
+    control_connections: KeyedLimiter<IpAddr>,
+    tunnel_requests: KeyedLimiter<String>,
+    client_bandwidth: KeyedLimiter<ClientId>,
+}
+
+impl RateLimiters {
+    /// Check if a new control connection from this ip is allowed
+    pub fn allow_control_connection(&self, client_ip: &IpAddr) -> bool {
+        match get_config().control_connection_limit {
+            Some(limit) => self.control_connections.check(client_ip, limit),
+            None => true,
+        }
+    }
+
+    /// Check if a new remote connection to this tunnel is allowed.
+    /// Each connection counts once, however many keep-alive requests it carries.
+    pub fn allow_tunnel_request(&self, limits: &AccountLimits, host: &String) -> bool {
+        match limits
+            .tunnel_request_limit
+            .or_else(|| get_config().tunnel_request_limit)
+        {
+            Some(limit) => self.tunnel_requests.check(host, limit),
+            None => true,
+        }
+    }
+
+    /// Pause long enough to keep the client within its bandwidth limit
+    pub async fn throttle_bandwidth(
+        &self,
+        limits: &AccountLimits,
+        client_id: &ClientId,
+        bytes: usize,
+    ) {
+        let limit = match limits
+            .bandwidth_limit
+            .or_else(|| get_config().client_bandwidth_limit)
+        {
+            Some(limit) => limit,
+            None => return,
+        };
+
+        let wait = self.client_bandwidth.throttle(client_id, limit, bytes);
+        if !wait.is_zero() {
+            tracing::trace!(%client_id, ?wait, "throttling client bandwidth");
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn prune(&self) {
+        self.control_connections.prune();
+        self.tunnel_requests.prune();
+        self.client_bandwidth.prune();
+    }
+}
+
+/// Periodically drop idle buckets so the limiters don't grow unbounded
+pub fn spawn_pruning() {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(PRUNE_INTERVAL).await;
+            crate::get_rate_limiters().prune();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate_limit() {
+        let limit = RateLimit::from_str("2:10").unwrap();
+        assert_eq!(
+            limit,
+            RateLimit {
+                per_second: 2.0,
+                burst: 10.0
+            }
+        );
+        let limit = RateLimit::from_str("5").unwrap();
+        assert_eq!(
+            limit,
+            RateLimit {
+                per_second: 5.0,
+                burst: 5.0
+            }
+        );
+        assert!(RateLimit::from_str("0:10").is_err());
+        assert!(RateLimit::from_str("fast").is_err());
+        assert!(RateLimit::from_str("NaN").is_err());
+        assert!(RateLimit::from_str("inf:10").is_err());
+
+        // limits from account limits or tokens are checked the same way
+        let limit: RateLimit = serde_json::from_str(r#"{"per_second": 2, "burst": 10}"#).unwrap();
+        assert_eq!(limit, RateLimit::new(2.0, 10.0).unwrap());
+        assert!(serde_json::from_str::<RateLimit>(r#"{"per_second": 0, "burst": 10}"#).is_err());
+        assert!(serde_json::from_str::<RateLimit>(r#"{"per_second": -1, "burst": 10}"#).is_err());
+    }
+
+    #[test]
+    fn test_keyed_limiter() {
+        let limit = RateLimit {
+            per_second: 0.001,
+            burst: 2.0,
+        };
+        let limiter = KeyedLimiter::default();
+
+        assert!(limiter.check(&"a", limit));
+        assert!(limiter.check(&"a", limit));
+        assert!(!limiter.check(&"a", limit));
+        assert!(limiter.check(&"b", limit));
+
+        let limit = RateLimit {
+            per_second: 100.0,
+            burst: 100.0,
+        };
+        assert_eq!(limiter.throttle(&"c", limit, 100), Duration::ZERO);
+        assert!(limiter.throttle(&"c", limit, 100) > Duration::from_millis(900));
+
+        // a tiny rate doesn't make us wait forever
+        let limit = RateLimit {
+            per_second: f64::MIN_POSITIVE,
+            burst: 1.0,
+        };
+        assert_eq!(limiter.throttle(&"d", limit, 100), MAX_THROTTLE_WAIT);
+    }
+}
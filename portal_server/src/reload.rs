@@ -0,0 +1,46 @@
+use crate::{get_cli, get_config, set_config, Config};
+use tracing::info;
+
+/// Re-read the config file and swap in its reloadable settings.
+/// Returns what changed, an invalid config leaves the running one untouched.
+pub fn reload() -> Result<Vec<String>, String> {
+    let path = match get_cli().config {
+        Some(ref path) => path.to_string_lossy().to_string(),
+        None => return Err("no config file to reload: started from ENV".to_string()),
+    };
+
+    let new = Config::load_from_file(&path).map_err(|e| format!("invalid config: {}", e))?;
+    let (config, changes) = get_config().reload(new);
+    set_config(config);
+
+    if changes.is_empty() {
+        info!("reloaded config: no changes");
+    }
+    for change in &changes {
+        info!(%change, "reloaded config");
+    }
+
+    Ok(changes)
+}
+
+/// Reload the config whenever we get a SIGHUP
+pub fn spawn_signal_listener() {
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sighup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(error) => {
+                tracing::error!(?error, "failed to listen for SIGHUP");
+                return;
+            }
+        };
+
+        while sighup.recv().await.is_some() {
+            info!("got SIGHUP, reloading config");
+            if let Err(error) = reload() {
+                tracing::error!(%error, "failed to reload config");
+            }
+        }
+    });
+}
@@ -1,20 +1,14 @@
 use super::*;
+use crate::error_page::PageRequest;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
+use tokio::time::Duration;
 use tracing::debug;
 use tracing::{error, Instrument};
+use warp::http::StatusCode;
 
 /// Response Constants
-const HTTP_REDIRECT_RESPONSE:&[u8] = b"HTTP/1.1 301 Moved Permanently\r\nLocation: https://tunnelto.dev/\r\nContent-Length: 20\r\n\r\nhttps://tunnelto.dev";
-const HTTP_INVALID_HOST_RESPONSE: &[u8] =
-    b"HTTP/1.1 400\r\nContent-Length: 23\r\n\r\nError: Invalid Hostname";
-const HTTP_NOT_FOUND_RESPONSE: &[u8] =
-    b"HTTP/1.1 404\r\nContent-Length: 23\r\n\r\nError: Tunnel Not Found";
-const HTTP_ERROR_LOCATING_HOST_RESPONSE: &[u8] =
-    b"HTTP/1.1 500\r\nContent-Length: 27\r\n\r\nError: Error finding tunnel";
-const HTTP_TUNNEL_REFUSED_RESPONSE: &[u8] =
-    b"HTTP/1.1 500\r\nContent-Length: 32\r\n\r\nTunnel says: connection refused.";
 const HTTP_OK_RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
 const HEALTH_CHECK_PATH: &[u8] = b"/0xDEADBEEF_HEALTH_CHECK";
 
@@ -50,6 +44,7 @@ pub async fn accept_connection(socket: TcpStream) {
         mut socket,
         host,
         forwarded_for,
+        request,
     } = match peek_http_request_host(socket).await {
         Some(s) => s,
         None => return,
@@ -57,20 +52,25 @@ pub async fn accept_connection(socket: TcpStream) {
 
     let config = get_config();
 
-    tracing::info!(%host, %forwarded_for, "new remote connection");
+    tracing::info!(%host, %forwarded_for, request_id = %request.id, "new remote connection");
     tracing::debug!("Allowed hosts: {}", config.allowed_hosts.join(", "));
 
     // parse the host string and find our client
     if config.allowed_hosts.contains(&host) {
         error!("redirect to homepage");
-        let _ = socket.write_all(HTTP_REDIRECT_RESPONSE).await;
+        let _ = socket.write_all(&get_error_pages().redirect()).await;
         return;
     }
     let host = match validate_host_prefix(&host) {
         Some(sub_domain) => sub_domain,
         None => {
             error!("invalid host specified");
-            let _ = socket.write_all(HTTP_INVALID_HOST_RESPONSE).await;
+            let response = get_error_pages().response(
+                StatusCode::BAD_REQUEST,
+                "The hostname of this request is invalid.",
+                &request,
+            );
+            let _ = socket.write_all(&response).await;
             return;
         }
     };
@@ -81,30 +81,51 @@ pub async fn accept_connection(socket: TcpStream) {
         return;
     }
 
-    // find the client listening for this host
-    let client = match Connections::find_by_host(&host) {
-        Some(client) => client.clone(),
-        None => {
-            // check other instances that may be serving this host
-            match network::instance_for_host(&host).await {
-                Ok((instance, _)) => {
-                    network::proxy_stream(instance, socket).await;
-                    return;
-                }
-                Err(network::Error::DoesNotServeHost) => {
-                    error!(%host, "no tunnel found");
-                    let _ = socket.write_all(HTTP_NOT_FOUND_RESPONSE).await;
-                    return;
-                }
-                Err(error) => {
-                    error!(%host, ?error, "failed to find instance");
-                    let _ = socket.write_all(HTTP_ERROR_LOCATING_HOST_RESPONSE).await;
+    // find the client listening for this host, else for a wildcard above it
+    let candidates = sub_domain::route_candidates(&host);
+    let local = candidates
+        .iter()
+        .enumerate()
+        .find_map(|(i, candidate)| Some((i, Connections::find_by_host(candidate)?)));
+    // other instances may serve a more specific one, ask them all at once
+    let more_specific = match local {
+        Some((i, _)) => &candidates[..i],
+        None => &candidates[..],
+    };
+    if !more_specific.is_empty() {
+        match get_routes().lookup(more_specific).await {
+            Ok(routes) => {
+                let remote = routes.into_iter().flatten().next();
+                if let Some(route) = remote.filter(|r| r.instance_id != config.instance_id) {
+                    tracing::info!(%host, instance_addr=%route.addr, client_id=%route.client_id, "found instance for host");
+                    network::proxy_stream(route, socket, &request).await;
                     return;
                 }
             }
+            Err(error) => {
+                error!(%host, ?error, "failed to find instance");
+                let response = get_error_pages().response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "We couldn't look up the tunnel for this host, please try again.",
+                    &request,
+                );
+                let _ = socket.write_all(&response).await;
+                return;
+            }
         }
+    }
+    let Some((_, client)) = local else {
+        error!(%host, "no tunnel found");
+        let _ = socket.write_all(&unavailable(&host, &request)).await;
+        return;
     };
 
+    if let Some(response) = rate_limited(&client, &request) {
+        tracing::warn!(%host, "too many requests for tunnel");
+        let _ = socket.write_all(&response).await;
+        return;
+    }
+
     // allocate a new stream for this request
     let (active_stream, queue_rx) = ActiveStream::new(client.clone());
     let stream_id = active_stream.id.clone();
@@ -113,16 +134,17 @@ pub async fn accept_connection(socket: TcpStream) {
         stream_id = %active_stream.id.to_string(),
         "new stream connected"
     );
-    let (stream, sink) = tokio::io::split(socket);
+    let (stream, sink) = socket.into_split();
 
     // add our stream
     get_active_streams().insert(stream_id.clone(), active_stream.clone());
 
     // read from socket, write to client
     let span = observability::remote_trace("process_tcp_stream");
+    let tunnel_stream = active_stream.clone();
     tokio::spawn(
         async move {
-            process_tcp_stream(active_stream, stream).await;
+            process_tcp_stream(tunnel_stream, stream).await;
         }
         .instrument(span),
     );
@@ -131,12 +153,26 @@ pub async fn accept_connection(socket: TcpStream) {
     let span = observability::remote_trace("tunnel_to_stream");
     tokio::spawn(
         async move {
-            tunnel_to_stream(host, stream_id, sink, queue_rx).await;
+            tunnel_to_stream(host, active_stream, request, sink, queue_rx).await;
         }
         .instrument(span),
     );
 }
 
+/// The response for a connection over the request limit of its tunnel, if it is
+fn rate_limited(client: &ConnectedClient, request: &PageRequest) -> Option<Vec<u8>> {
+    if get_rate_limiters().allow_tunnel_request(&client.limits, &client.host) {
+        return None;
+    }
+    Some(get_error_pages().response(
+        StatusCode::TOO_MANY_REQUESTS,
+        "This tunnel is receiving too many requests, please slow down.",
+        request,
+    ))
+}
+
+/// The sub-domain of a host below one of the allowed hosts, i.e. `v2.api` of `v2.api.tunnel.host`.
+/// None if no tunnel could serve it, so we don't look it up
 fn validate_host_prefix(host: &str) -> Option<String> {
     let url = format!("http://{}", host);
     debug!(%url, "parsing host");
@@ -152,20 +188,35 @@ fn validate_host_prefix(host: &str) -> Option<String> {
         }
     };
 
-    let domain_segments = host.split('.').collect::<Vec<&str>>();
-    let prefix = &domain_segments[0];
-    let remaining = &domain_segments[1..].join(".");
-
     let config = get_config();
+    debug!(%host, ?config.allowed_hosts, "parsed host");
 
-    debug!(%host, %prefix, "parsed host");
-    debug!(%prefix, %remaining, "parsed host");
-    debug!(?config.allowed_hosts, "allowed hosts");
+    // everything below the allowed host, the longest one if they nest
+    let prefix = config
+        .allowed_hosts
+        .iter()
+        .filter_map(|allowed| host.strip_suffix(allowed.as_str())?.strip_suffix('.'))
+        .filter(|prefix| !prefix.is_empty())
+        .min_by_key(|prefix| prefix.len())?;
 
-    if config.allowed_hosts.contains(remaining) {
-        Some(prefix.to_string())
-    } else {
-        None
+    if host.len() > sub_domain::MAX_HOST_LEN || !sub_domain::is_valid_host(prefix) {
+        debug!(%prefix, "host too long or deep for a tunnel");
+        return None;
+    }
+
+    debug!(%prefix, "parsed host");
+    Some(prefix.to_string())
+}
+
+/// The maintenance page of the client that served this host, else not found
+fn unavailable(host: &str, request: &PageRequest) -> Vec<u8> {
+    match get_maintenance_pages().find(host) {
+        Some(page) => get_error_pages().maintenance(&page, request),
+        None => get_error_pages().response(
+            StatusCode::NOT_FOUND,
+            "There is no tunnel running for this host.",
+            request,
+        ),
     }
 }
 
@@ -173,6 +224,7 @@ struct StreamWithPeekedHost {
     socket: TcpStream,
     host: String,
     forwarded_for: String,
+    request: PageRequest,
 }
 /// Filter incoming remote streams
 #[tracing::instrument(skip(socket))]
@@ -240,10 +292,12 @@ async fn peek_http_request_host(mut socket: TcpStream) -> Option<StreamWithPeeke
     {
         tracing::info!(host=%host, path=%req.path.unwrap_or_default(), "peek request");
 
+        let request = PageRequest::from_headers(req.headers);
         return Some(StreamWithPeekedHost {
             socket,
             host: host.to_string(),
             forwarded_for,
+            request,
         });
     }
 
@@ -253,7 +307,7 @@ async fn peek_http_request_host(mut socket: TcpStream) -> Option<StreamWithPeeke
 
 /// Process Messages from the control path in & out of the remote stream
 #[tracing::instrument(skip(tunnel_stream, tcp_stream))]
-async fn process_tcp_stream(mut tunnel_stream: ActiveStream, mut tcp_stream: ReadHalf<TcpStream>) {
+async fn process_tcp_stream(mut tunnel_stream: ActiveStream, mut tcp_stream: OwnedReadHalf) {
     // send initial control stream init to client
     control_server::send_client_stream_init(tunnel_stream.clone()).await;
 
@@ -261,97 +315,280 @@ async fn process_tcp_stream(mut tunnel_stream: ActiveStream, mut tcp_stream: Rea
     let mut buf = [0; 1024];
 
     loop {
-        // client is no longer connected
-        if Connections::get(&tunnel_stream.client.id).is_none() {
+        // client is no longer connected, give it a chance to resume
+        if !tunnel_stream.wait_for_resume().await {
             debug!("client disconnected, closing stream");
             let _ = tunnel_stream.tx.send(StreamMessage::NoClientTunnel).await;
             tunnel_stream.tx.close_channel();
             return;
         }
 
-        // read from stream
-        let n = match tcp_stream.read(&mut buf).await {
-            Ok(n) => n,
-            Err(e) => {
-                error!("failed to read from tcp socket: {:?}", e);
+        // read from stream, until the client aborts it
+        let n = tokio::select! {
+            read = tcp_stream.read(&mut buf) => match read {
+                Ok(n) => n,
+                Err(error) => {
+                    error!(?error, "failed to read from tcp socket");
+                    // the visitor is gone, tell the client and close our end
+                    tunnel_stream.send(ControlPacket::Reset(
+                        tunnel_stream.id.clone(),
+                        CloseReason::from(&error),
+                    ));
+                    tunnel_stream.tx.close_channel();
+                    return;
+                }
+            },
+            _ = tunnel_stream.aborted() => {
+                debug!("stream aborted");
                 return;
             }
         };
 
         if n == 0 {
             debug!("stream ended");
-            let _ = tunnel_stream
-                .client
-                .tx
-                .send(ControlPacket::End(tunnel_stream.id.clone()))
-                .await
-                .map_err(|e| {
-                    error!("failed to send end signal: {:?}", e);
-                });
+            if !tunnel_stream.wait_for_resume().await
+                || !tunnel_stream.send(ControlPacket::End(tunnel_stream.id.clone()))
+            {
+                error!("failed to send end signal: client disconnected");
+            }
             return;
         }
 
         debug!("read {} bytes", n);
 
-        let data = &buf[..n];
-        let packet = ControlPacket::Data(tunnel_stream.id.clone(), data.to_vec());
+        get_rate_limiters()
+            .throttle_bandwidth(&tunnel_stream.client.limits, &tunnel_stream.client.id, n)
+            .await;
 
-        match tunnel_stream.client.tx.send(packet.clone()).await {
-            Ok(_) => debug!(client_id = %tunnel_stream.client.id, "sent data packet to client"),
-            Err(_) => {
-                error!("failed to forward tcp packets to disconnected client. dropping client.");
-                Connections::remove(&tunnel_stream.client);
-            }
+        if tunnel_stream.send_data(buf[..n].to_vec()) {
+            debug!(client_id = %tunnel_stream.client.id, "sent data packet to client");
+        } else {
+            debug!(client_id = %tunnel_stream.client.id, "client disconnected, holding data for resume");
         }
     }
 }
 
-#[tracing::instrument(skip(sink, stream_id, queue))]
+#[tracing::instrument(skip(tunnel_stream, request, sink, queue))]
 async fn tunnel_to_stream(
     subdomain: String,
-    stream_id: StreamId,
-    mut sink: WriteHalf<TcpStream>,
+    tunnel_stream: ActiveStream,
+    request: PageRequest,
+    mut sink: OwnedWriteHalf,
     mut queue: UnboundedReceiver<StreamMessage>,
 ) {
+    let stream_id = tunnel_stream.id.clone();
+    // once the response has started, only resetting the connection tells the visitor it failed
+    let mut responded = false;
+
     loop {
-        let result = queue.next().await;
-
-        let result = if let Some(message) = result {
-            match message {
-                StreamMessage::Data(data) => Some(data),
-                StreamMessage::TunnelRefused => {
-                    tracing::debug!(?stream_id, "tunnel refused");
-                    let _ = sink.write_all(HTTP_TUNNEL_REFUSED_RESPONSE).await;
-                    None
-                }
-                StreamMessage::NoClientTunnel => {
-                    tracing::info!(%subdomain, ?stream_id, "client tunnel not found");
-                    let _ = sink.write_all(HTTP_NOT_FOUND_RESPONSE).await;
-                    None
+        let reset_reason = match queue.next().await {
+            Some(StreamMessage::Data(data)) => {
+                // throttled here rather than where the client's packets are read,
+                // so a busy stream doesn't hold up the others of its client
+                get_rate_limiters()
+                    .throttle_bandwidth(
+                        &tunnel_stream.client.limits,
+                        &tunnel_stream.client.id,
+                        data.len(),
+                    )
+                    .await;
+                if let Err(error) = sink.write_all(&data).await {
+                    tracing::warn!(?error, "stream closed, disconnecting");
+                    tunnel_stream.send(ControlPacket::Reset(
+                        stream_id.clone(),
+                        CloseReason::from(&error),
+                    ));
+                    tunnel_stream.abort();
+                    get_active_streams().remove(&stream_id);
+                    return;
                 }
+                responded = true;
+                continue;
             }
-        } else {
-            None
+            Some(StreamMessage::Reset(reason)) => {
+                tracing::debug!(?stream_id, ?reason, "tunnel reset");
+                Some(reason)
+            }
+            Some(StreamMessage::NoClientTunnel) => {
+                tracing::info!(%subdomain, ?stream_id, "client tunnel not found");
+                None
+            }
+            // the client is done sending
+            None => break,
         };
 
-        let data = match result {
-            Some(data) => data,
-            None => {
-                tracing::debug!("done tunneling to sink");
-                let _ = sink.shutdown().await.map_err(|_e| {
-                    error!("error shutting down tcp stream");
-                });
+        if responded {
+            reset_visitor(sink, &tunnel_stream);
+            return;
+        }
 
-                get_active_streams().remove(&stream_id);
-                return;
-            }
+        let error_response = match reset_reason {
+            Some(reason) => reset_response(&subdomain, reason, &request),
+            None => unavailable(&subdomain, &request),
         };
+        let _ = sink.write_all(&error_response).await;
+        tunnel_stream.abort();
+        break;
+    }
 
-        let result = sink.write_all(&data).await;
+    tracing::debug!("done tunneling to sink");
+    let _ = sink.shutdown().await.map_err(|_e| {
+        error!("error shutting down tcp stream");
+    });
 
-        if let Some(error) = result.err() {
-            tracing::warn!(?error, "stream closed, disconnecting");
-            return;
+    get_active_streams().remove(&stream_id);
+}
+
+/// What visitors get when the client couldn't serve their request
+fn reset_response(host: &str, reason: CloseReason, request: &PageRequest) -> Vec<u8> {
+    let (status, message) = match reason {
+        CloseReason::ConnectRefused => match get_maintenance_pages().find(host) {
+            Some(page) => return get_error_pages().maintenance(&page, request),
+            None => (
+                StatusCode::BAD_GATEWAY,
+                "The tunnel client couldn't connect to its local server.",
+            ),
+        },
+        CloseReason::TlsFailure => (
+            StatusCode::BAD_GATEWAY,
+            "The tunnel client couldn't establish TLS with its local server.",
+        ),
+        CloseReason::Timeout => (
+            StatusCode::GATEWAY_TIMEOUT,
+            "The tunnel client's local server timed out.",
+        ),
+        CloseReason::Error => (
+            StatusCode::BAD_GATEWAY,
+            "The tunnel client lost the connection to its local server.",
+        ),
+    };
+    get_error_pages().response(status, message, request)
+}
+
+/// Close the visitor's connection with a RST rather than a FIN,
+/// so a response cut short isn't mistaken for a complete one
+fn reset_visitor(sink: OwnedWriteHalf, tunnel_stream: &ActiveStream) {
+    let _ = sink.as_ref().set_linger(Some(Duration::ZERO));
+    // the socket is closed once the reader stops too
+    sink.forget();
+    tunnel_stream.abort();
+    get_active_streams().remove(&tunnel_stream.id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AccountLimits;
+    use crate::rate_limit::RateLimit;
+    use tokio::net::TcpListener;
+
+    fn error_pages() {
+        let _ = ERROR_PAGES.set(error_page::ErrorPages::load(&Default::default()).unwrap());
+    }
+
+    fn client() -> ConnectedClient {
+        let (tx, _) = futures::channel::mpsc::unbounded();
+        // limits of its own, so the server config isn't needed
+        let limits = AccountLimits {
+            tunnel_request_limit: Some(RateLimit::new(0.001, 1.0).unwrap()),
+            bandwidth_limit: Some(RateLimit::new(1e9, 1e9).unwrap()),
+            ..Default::default()
+        };
+        ConnectedClient {
+            id: ClientId::generate(),
+            host: ClientId::generate().to_string(),
+            is_anonymous: false,
+            limits,
+            session_expires: None,
+            pool: false,
+            supports_reset: true,
+            session_id: uuid::Uuid::new_v4(),
+            authenticated: chrono::Utc::now(),
+            open_streams: Arc::default(),
+            tx,
         }
     }
+
+    /// a stream tunneling to a visitor, and the visitor's end of it
+    async fn visitor_stream() -> (ActiveStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let visitor = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        // only the sink is used once the request is read
+        let (_, sink) = socket.into_split();
+
+        let (stream, queue) = ActiveStream::new(client());
+        let request = PageRequest {
+            id: "test".into(),
+            wants_json: false,
+        };
+        tokio::spawn(tunnel_to_stream(
+            "foo".into(),
+            stream.clone(),
+            request,
+            sink,
+            queue,
+        ));
+        (stream, visitor)
+    }
+
+    #[tokio::test]
+    async fn test_reset_before_response() {
+        error_pages();
+        let (stream, mut visitor) = visitor_stream().await;
+
+        // the visitor gets an error page, and the stream is aborted
+        stream
+            .tx
+            .unbounded_send(StreamMessage::Reset(CloseReason::Error))
+            .unwrap();
+        let mut response = String::new();
+        visitor.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 502"));
+        tokio::time::timeout(Duration::from_secs(1), stream.aborted())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reset_after_response() {
+        let (stream, mut visitor) = visitor_stream().await;
+
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n";
+        stream
+            .tx
+            .unbounded_send(StreamMessage::Data(head.to_vec()))
+            .unwrap();
+        let mut received = vec![0; head.len()];
+        visitor.read_exact(&mut received).await.unwrap();
+
+        // too late for an error page, the visitor's connection is reset instead
+        stream
+            .tx
+            .unbounded_send(StreamMessage::Reset(CloseReason::Error))
+            .unwrap();
+        let error = visitor.read_to_end(&mut vec![]).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::ConnectionReset);
+        tokio::time::timeout(Duration::from_secs(1), stream.aborted())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_rate_limited() {
+        error_pages();
+        let tunnel = client();
+        let request = PageRequest {
+            id: "test".into(),
+            wants_json: false,
+        };
+
+        assert!(rate_limited(&tunnel, &request).is_none());
+        let response = rate_limited(&tunnel, &request).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 429"));
+        // other tunnels have their own budget
+        assert!(rate_limited(&client(), &request).is_none());
+    }
 }
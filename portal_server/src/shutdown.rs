@@ -0,0 +1,142 @@
+use crate::control_server::reconnect_token;
+use crate::{get_active_streams, ConnectedClient, Connections, ControlPacket, ReconnectToken};
+use futures::SinkExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// How often we check if the active streams have finished
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Are we draining connections before exiting?
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
+/// Wait for SIGTERM or ctrl-c
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+
+        tokio::select! {
+            _ = sigterm.recv() => info!("got SIGTERM"),
+            _ = tokio::signal::ctrl_c() => info!("got ctrl-c"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("got ctrl-c");
+    }
+}
+
+/// Tell every client to reconnect elsewhere, then wait for active streams to finish
+pub async fn drain(timeout: Duration) {
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+
+    // stop routing visitors to us, the clients claim their hosts where they reconnect
+    crate::network::release_all().await;
+
+    let clients = Connections::all();
+    info!(clients = clients.len(), "asking clients to reconnect");
+    ask_to_reconnect(clients, reconnect_token).await;
+
+    if wait_until_drained(|| get_active_streams().is_empty(), timeout).await {
+        info!("all streams drained");
+    } else {
+        warn!(
+            streams = get_active_streams().len(),
+            "shutdown timeout reached, dropping active streams"
+        );
+    }
+
+    // close the control connections, giving them a moment to flush
+    for client in Connections::all() {
+        Connections::remove(&client);
+    }
+    tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+}
+
+async fn ask_to_reconnect(
+    clients: Vec<ConnectedClient>,
+    reconnect_token: impl Fn(&ConnectedClient) -> Option<ReconnectToken>,
+) {
+    for mut client in clients {
+        let packet = ControlPacket::Reconnect(reconnect_token(&client));
+        if let Err(error) = client.tx.send(packet).await {
+            warn!(client_id=%client.id, ?error, "failed to send reconnect");
+        }
+    }
+}
+
+/// Wait for `is_drained`, returns false if it wasn't within `timeout`
+async fn wait_until_drained(is_drained: impl Fn() -> bool, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !is_drained() && Instant::now() < deadline {
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+    is_drained()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AccountLimits;
+    use futures::StreamExt;
+    use portal_lib::ClientId;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_ask_to_reconnect() {
+        let (tx, mut rx) = futures::channel::mpsc::unbounded();
+        let client = ConnectedClient {
+            id: ClientId::generate(),
+            host: "foo".into(),
+            is_anonymous: false,
+            limits: AccountLimits::default(),
+            session_expires: None,
+            pool: false,
+            supports_reset: true,
+            session_id: uuid::Uuid::new_v4(),
+            authenticated: chrono::Utc::now(),
+            open_streams: Default::default(),
+            tx,
+        };
+
+        let token = ReconnectToken("token".into());
+        ask_to_reconnect(vec![client], |_| Some(token.clone())).await;
+        assert!(matches!(
+            rx.next().await,
+            Some(ControlPacket::Reconnect(Some(sent))) if sent.0 == token.0
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_wait_until_drained() {
+        // streams that never finish are dropped after the timeout
+        let start = Instant::now();
+        assert!(!wait_until_drained(|| false, Duration::from_millis(500)).await);
+        assert!(start.elapsed() >= Duration::from_millis(500));
+
+        // otherwise we stop waiting as soon as the last one finishes
+        let drained = Arc::new(AtomicBool::new(false));
+        let finish = drained.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            finish.store(true, Ordering::Relaxed);
+        });
+
+        let start = Instant::now();
+        assert!(
+            wait_until_drained(|| drained.load(Ordering::Relaxed), Duration::from_secs(10)).await
+        );
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
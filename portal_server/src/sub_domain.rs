@@ -0,0 +1,89 @@
+/// The most labels the sub-domain of a tunnel may have, i.e. `v2.api.alice`
+pub const MAX_LABELS: usize = 4;
+/// The longest a label of a host may be
+const MAX_LABEL_LEN: usize = 63;
+/// The longest a host may be
+pub const MAX_HOST_LEN: usize = 253;
+
+/// Labels of a sub-domain are alphanumeric with hyphens, i.e. `v2.api`.
+/// The first may be `*` for a wildcard tunnel serving every host below the rest, i.e. `*.alice`
+pub fn is_valid(sub_domain: &str) -> bool {
+    let labels = sub_domain.strip_prefix("*.").unwrap_or(sub_domain);
+    labels.split('.').count() <= MAX_LABELS
+        && labels.split('.').all(|label| {
+            is_valid_label(label) && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+        })
+}
+
+/// Whether a tunnel could serve this sub-domain of a visitor's host:
+/// no deeper than a tunnel's, plus the level a wildcard covers
+pub fn is_valid_host(sub_domain: &str) -> bool {
+    sub_domain.split('.').count() <= MAX_LABELS + 1 && sub_domain.split('.').all(is_valid_label)
+}
+
+fn is_valid_label(label: &str) -> bool {
+    !label.is_empty() && label.len() <= MAX_LABEL_LEN
+}
+
+/// The sub-domain without its wildcard, then each of its parents: `*.v2.alice`, `v2.alice`, `alice`
+pub fn parents(sub_domain: &str) -> impl Iterator<Item = &str> {
+    let name = sub_domain.strip_prefix("*.").unwrap_or(sub_domain);
+    std::iter::successors(Some(name), |name| {
+        name.split_once('.').map(|(_, parent)| parent)
+    })
+}
+
+/// The top name of a sub-domain, i.e. `alice` of `*.v2.alice`.
+/// Everything below it is routed by the same instance
+pub fn root(sub_domain: &str) -> &str {
+    parents(sub_domain).last().unwrap_or(sub_domain)
+}
+
+/// The tunnels that may serve a host, most specific first:
+/// `v2.alice` itself, then `*.alice`
+pub fn route_candidates(host: &str) -> Vec<String> {
+    std::iter::once(host.to_string())
+        .chain(parents(host).skip(1).map(|parent| format!("*.{}", parent)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid() {
+        assert!(is_valid("alice"));
+        assert!(is_valid("v2.api"));
+        assert!(is_valid("*.alice"));
+        assert!(!is_valid("*"));
+        assert!(!is_valid("a.*.b"));
+        assert!(!is_valid("a..b"));
+        assert!(!is_valid(".alice"));
+        assert!(!is_valid("a_b"));
+        assert!(!is_valid(""));
+        assert!(is_valid("a.b.c.d"));
+        assert!(!is_valid("a.b.c.d.e"));
+        assert!(!is_valid(&"a".repeat(64)));
+
+        // a visitor's host may be a level below a wildcard tunnel
+        assert!(is_valid_host("x.a.b.c.d"));
+        assert!(!is_valid_host("y.x.a.b.c.d"));
+        assert!(!is_valid_host(&format!("{}.alice", "a".repeat(64))));
+        assert!(!is_valid_host("a..b"));
+    }
+
+    #[test]
+    fn test_route_candidates() {
+        assert_eq!(
+            parents("*.v2.alice").collect::<Vec<_>>(),
+            vec!["v2.alice", "alice"]
+        );
+        assert_eq!(root("*.v2.alice"), "alice");
+        assert_eq!(route_candidates("alice"), vec!["alice"]);
+        assert_eq!(
+            route_candidates("a.v2.alice"),
+            vec!["a.v2.alice", "*.v2.alice", "*.alice"]
+        );
+    }
+}
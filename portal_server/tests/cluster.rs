@@ -0,0 +1,243 @@
+//! Run a couple of server instances on localhost ports,
+//! finding each other through a static peer list
+use futures::{SinkExt, StreamExt};
+use portal_lib::{ClientHello, ClientType, ControlPacket, SecretKey, ServerHello};
+use std::net::TcpListener;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::process::{Child, Command};
+use tokio_tungstenite::tungstenite::Message;
+
+const CLUSTER_KEY: &str = "6b3a55e0261b0304143f805a24924d0c1c44524821305f31d9277843b8a10f4e";
+
+struct TestInstance {
+    control_port: u16,
+    remote_port: u16,
+    network_port: u16,
+    /// run with a cluster key and mutual tls on this port
+    cluster_tls_port: Option<u16>,
+    _process: Option<Child>,
+}
+
+impl TestInstance {
+    fn new() -> Self {
+        TestInstance {
+            control_port: free_port(),
+            remote_port: free_port(),
+            network_port: free_port(),
+            cluster_tls_port: None,
+            _process: None,
+        }
+    }
+
+    fn secure() -> Self {
+        TestInstance {
+            cluster_tls_port: Some(free_port()),
+            ..TestInstance::new()
+        }
+    }
+
+    async fn start(&mut self, peers: &[u16]) {
+        let peers: Vec<String> = peers.iter().map(|p| format!("127.0.0.1:{}", p)).collect();
+
+        let mut command = Command::new(env!("CARGO_BIN_EXE_portal_server"));
+        command.env_clear();
+        if let Some(port) = self.cluster_tls_port {
+            let tls = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/cluster_tls");
+            command
+                .env("CLUSTER_KEY", CLUSTER_KEY)
+                .env("CLUSTER_TLS_CERT", format!("{}/cert.pem", tls))
+                .env("CLUSTER_TLS_KEY", format!("{}/key.pem", tls))
+                .env("CLUSTER_TLS_CA", format!("{}/ca.pem", tls))
+                .env("CLUSTER_TLS_PORT", port.to_string());
+        }
+
+        let process = command
+            .env("ALLOWED_HOSTS", "localhost")
+            .env("PORTAL_HOST", "localhost")
+            .env("CTRL_PORT", self.control_port.to_string())
+            .env("PORT", self.remote_port.to_string())
+            .env("NET_PORT", self.network_port.to_string())
+            .env("DISCOVERY", format!("static:{}", peers.join(",")))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .expect("failed to start portal_server");
+        self._process = Some(process);
+
+        let ports = [self.control_port, self.remote_port, self.network_port];
+        for port in ports.into_iter().chain(self.cluster_tls_port) {
+            wait_for_port(port).await;
+        }
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+async fn wait_for_port(port: u16) {
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("instance never listened on port {}", port);
+}
+
+type WebSocket = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>;
+
+async fn hello(instance: &TestInstance, hello: ClientHello) -> (WebSocket, ServerHello) {
+    let url = format!("ws://127.0.0.1:{}/wormhole", instance.control_port);
+    let (mut websocket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+    websocket
+        .send(Message::binary(serde_json::to_vec(&hello).unwrap()))
+        .await
+        .unwrap();
+
+    let server_hello = websocket.next().await.unwrap().unwrap().into_data();
+    (websocket, serde_json::from_slice(&server_hello).unwrap())
+}
+
+/// Open a tunnel on this instance, answering every stream with `body`
+async fn open_tunnel(instance: &TestInstance, body: &'static str) -> String {
+    let client_hello = ClientHello::generate(Some("cluster".into()), ClientType::Anonymous);
+    let (mut websocket, server_hello) = hello(instance, client_hello).await;
+    let sub_domain = match server_hello {
+        ServerHello::Success { sub_domain, .. } => sub_domain,
+        other => panic!("tunnel refused: {:?}", other),
+    };
+
+    tokio::spawn(async move {
+        while let Some(Ok(message)) = websocket.next().await {
+            let packet = match ControlPacket::deserialize(&message.into_data()) {
+                Ok(packet) => packet,
+                Err(_) => continue,
+            };
+
+            if let ControlPacket::Data(stream_id, _) = packet {
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let data = ControlPacket::Data(stream_id.clone(), response.into_bytes());
+                let _ = websocket.send(Message::binary(data.serialize())).await;
+                let end = ControlPacket::End(stream_id);
+                let _ = websocket.send(Message::binary(end.serialize())).await;
+            }
+        }
+    });
+
+    sub_domain
+}
+
+async fn get(remote_port: u16, host: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", remote_port))
+        .await
+        .unwrap();
+    let request = format!(
+        "GET / HTTP/1.1\r\nHost: {}.localhost\r\nConnection: close\r\n\r\n",
+        host
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = vec![];
+    let _ = tokio::time::timeout(Duration::from_secs(10), stream.read_to_end(&mut response)).await;
+    String::from_utf8_lossy(&response).to_string()
+}
+
+#[tokio::test]
+async fn test_proxy_to_peer_instance() {
+    let mut a = TestInstance::new();
+    let mut b = TestInstance::new();
+    let peers = [a.network_port, b.network_port];
+    a.start(&peers).await;
+    b.start(&peers).await;
+
+    let sub_domain = open_tunnel(&a, "hello from a").await;
+
+    // served directly by the instance holding the tunnel
+    let response = get(a.remote_port, &sub_domain).await;
+    assert!(response.ends_with("hello from a"), "{}", response);
+
+    // found through discovery and proxied by the other one
+    let response = get(b.remote_port, &sub_domain).await;
+    assert!(response.ends_with("hello from a"), "{}", response);
+
+    // nobody serves this one
+    let response = get(b.remote_port, "nobody").await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+}
+
+#[tokio::test]
+async fn test_sub_domain_claimed_once_across_instances() {
+    let mut a = TestInstance::new();
+    let mut b = TestInstance::new();
+    let peers = [a.network_port, b.network_port];
+    a.start(&peers).await;
+    b.start(&peers).await;
+
+    let account = |key: &str| {
+        ClientHello::generate(
+            Some("shared".into()),
+            ClientType::Auth {
+                key: SecretKey(key.into()),
+            },
+        )
+    };
+
+    let (_first, server_hello) = hello(&a, account("first")).await;
+    assert!(matches!(server_hello, ServerHello::Success { .. }));
+
+    let (_, server_hello) = hello(&b, account("second")).await;
+    assert!(matches!(server_hello, ServerHello::SubDomainInUse));
+
+    // the same account may hold it from any instance
+    let (_, server_hello) = hello(&b, account("first")).await;
+    assert!(matches!(server_hello, ServerHello::Success { .. }));
+}
+
+#[tokio::test]
+async fn test_secure_cluster() {
+    let mut a = TestInstance::secure();
+    let mut b = TestInstance::secure();
+    let peers = [a.network_port, b.network_port];
+    a.start(&peers).await;
+    b.start(&peers).await;
+
+    let sub_domain = open_tunnel(&a, "hello over tls").await;
+
+    let response = get(b.remote_port, &sub_domain).await;
+    assert!(response.ends_with("hello over tls"), "{}", response);
+
+    // the routing api only answers signed requests
+    let url = format!(
+        "http://127.0.0.1:{}/routes?hosts={}",
+        a.network_port, sub_domain
+    );
+    let status = reqwest::get(url).await.unwrap().status();
+    assert_eq!(status, reqwest::StatusCode::UNAUTHORIZED);
+
+    // and the tls port only talks to instances with a cluster certificate
+    let mut stream = TcpStream::connect(("127.0.0.1", a.cluster_tls_port.unwrap()))
+        .await
+        .unwrap();
+    let request = format!(
+        "GET / HTTP/1.1\r\nHost: {}.localhost\r\nConnection: close\r\n\r\n",
+        sub_domain
+    );
+    let _ = stream.write_all(request.as_bytes()).await;
+    let mut response = vec![];
+    let _ = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response)).await;
+    assert!(!String::from_utf8_lossy(&response).contains("hello over tls"));
+}